@@ -0,0 +1,314 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::token::{Span,Token};
+
+#[derive(Debug,Clone,Serialize)]
+pub enum Expr {
+    BinaryExpr {
+        op: Token,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    UnaryExpr {
+        op: Token,
+        right: Box<Expr>,
+    },
+    Logical {
+        op: Token,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Ternary {
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        els: Box<Expr>,
+    },
+    Grouping {
+        expr: Box<Expr>,
+    },
+    Literal {
+        value: String,
+    },
+    StringLiteral {
+        value: String,
+    },
+    Assign {
+        name: Token,
+        value: Box<Expr>,
+        span: Span,
+    },
+    Variable {
+        name: Token,
+        span: Span,
+    },
+    Call {
+        callee: Box<Expr>,
+        paren: Token,
+        args: Vec<Box<Expr>>,
+    },
+    /// Element of an array made by the `array` builtin, `a[i]`.
+    Index {
+        array: Box<Expr>,
+        index: Box<Expr>,
+        span: Span,
+    },
+    /// Store into an array element, `a[i] = value`.
+    SetIndex {
+        array: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+        span: Span,
+    },
+    /// Member access, `object.name`. Parsed ahead of records, nothing can
+    /// evaluate it yet.
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
+}
+
+/// Type that can be written in annotations.
+#[derive(Debug,Clone,Copy,PartialEq,Serialize)]
+pub enum Type {
+    Int,
+    Float,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+        }
+    }
+}
+
+/// Function parameter, `float` unless annotated otherwise.
+#[derive(Debug,Clone,PartialEq,Serialize)]
+pub struct Param {
+    pub name: Token,
+    pub ty: Option<Type>,
+}
+
+impl Param {
+    pub fn ty(&self) -> Type {
+        self.ty.unwrap_or(Type::Float)
+    }
+}
+
+#[derive(Debug,Clone,Serialize)]
+pub enum Stmt {
+    Block(Vec<Box<Stmt>>),
+    Expr(Box<Expr>),
+    Print(Box<Expr>),
+    Return {
+        keyword: Token,
+        value: Option<Box<Expr>>,
+        span: Span,
+    },
+    /// `entry` is set by `wagmi`, marking the function run by default.
+    /// Without a `return_type` the function returns a float, or nothing.
+    Function {
+        name: Token,
+        params: Vec<Param>,
+        return_type: Option<Type>,
+        body: Vec<Box<Stmt>>,
+        span: Span,
+        entry: bool,
+    },
+    If {
+        cond: Box<Expr>,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    /// `increment` runs after the body and on `continue`, it is only set
+    /// by for loops.
+    While {
+        condition: Box<Expr>,
+        body: Box<Stmt>,
+        increment: Option<Box<Expr>>,
+    },
+    Break {
+        span: Span,
+    },
+    Continue {
+        span: Span,
+    },
+    Var {
+        name: Token,
+        initializer: Box<Expr>,
+        span: Span,
+    },
+    /// Comment kept for the formatter, either on a line of its own or
+    /// `trailing` the statement before it. Only produced by a parser that
+    /// keeps comments.
+    Comment {
+        text: String,
+        trailing: bool,
+    },
+}
+
+impl Expr {
+    /// Position of the first name in the expression, literals have none.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Expr::Assign { span, .. }
+            | Expr::Variable { span, .. }
+            | Expr::Index { span, .. }
+            | Expr::SetIndex { span, .. } => Some(*span),
+            Expr::BinaryExpr { left, right, .. } | Expr::Logical { left, right, .. } => left.span().or_else(|| right.span()),
+            Expr::UnaryExpr { right, .. } => right.span(),
+            Expr::Ternary { cond, then, els } => cond.span().or_else(|| then.span()).or_else(|| els.span()),
+            Expr::Grouping { expr } => expr.span(),
+            Expr::Call { callee, .. } => callee.span(),
+            Expr::Get { object, .. } => object.span(),
+            Expr::Literal { .. } | Expr::StringLiteral { .. } => None,
+        }
+    }
+}
+
+impl Stmt {
+    /// Where the statement starts, as far as the parser kept track of it.
+    /// Blocks have no position of their own.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Stmt::Return { span, .. }
+            | Stmt::Function { span, .. }
+            | Stmt::Break { span }
+            | Stmt::Continue { span }
+            | Stmt::Var { span, .. } => Some(*span),
+            Stmt::Expr(expr) | Stmt::Print(expr) => expr.span(),
+            Stmt::If { cond, .. } => cond.span(),
+            Stmt::While { condition, .. } => condition.span(),
+            Stmt::Block(_) | Stmt::Comment { .. } => None,
+        }
+    }
+}
+
+/// Walks the AST. Every method defaults to visiting the children of the
+/// node, so a pass only overrides the nodes it cares about and calls
+/// `walk_stmt` or `walk_expr` to keep descending.
+pub trait Visitor {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+/// Visits the statements and expressions directly inside the statement.
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Stmt) {
+    match stmt {
+        Stmt::Block(statements) => {
+            for stmt in statements.iter() {
+                visitor.visit_stmt(stmt);
+            }
+        },
+        Stmt::Expr(expr) | Stmt::Print(expr) => visitor.visit_expr(expr),
+        Stmt::Return { value, .. } => {
+            if let Some(value) = value {
+                visitor.visit_expr(value);
+            }
+        },
+        Stmt::Function { body, .. } => {
+            for stmt in body.iter() {
+                visitor.visit_stmt(stmt);
+            }
+        },
+        Stmt::If { cond, then_branch, else_branch } => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(then_branch);
+            if let Some(else_branch) = else_branch {
+                visitor.visit_stmt(else_branch);
+            }
+        },
+        Stmt::While { condition, body, increment } => {
+            visitor.visit_expr(condition);
+            visitor.visit_stmt(body);
+            if let Some(increment) = increment {
+                visitor.visit_expr(increment);
+            }
+        },
+        Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Comment { .. } => {},
+        Stmt::Var { initializer, .. } => visitor.visit_expr(initializer),
+    }
+}
+
+/// Visits the expressions directly inside the expression.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::BinaryExpr { left, right, .. } | Expr::Logical { left, right, .. } => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        },
+        Expr::UnaryExpr { right, .. } => visitor.visit_expr(right),
+        Expr::Ternary { cond, then, els } => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(then);
+            visitor.visit_expr(els);
+        },
+        Expr::Grouping { expr } => visitor.visit_expr(expr),
+        Expr::Assign { value, .. } => visitor.visit_expr(value),
+        Expr::Call { callee, args, .. } => {
+            visitor.visit_expr(callee);
+            for arg in args.iter() {
+                visitor.visit_expr(arg);
+            }
+        },
+        Expr::Index { array, index, .. } => {
+            visitor.visit_expr(array);
+            visitor.visit_expr(index);
+        },
+        Expr::SetIndex { array, index, value, .. } => {
+            visitor.visit_expr(array);
+            visitor.visit_expr(index);
+            visitor.visit_expr(value);
+        },
+        Expr::Get { object, .. } => visitor.visit_expr(object),
+        Expr::Literal { .. } | Expr::StringLiteral { .. } | Expr::Variable { .. } => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_str;
+
+    struct BinaryCounter {
+        count: usize,
+    }
+
+    impl Visitor for BinaryCounter {
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let Expr::BinaryExpr { .. } = expr {
+                self.count += 1;
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    #[test]
+    fn counts_binary_expressions() {
+        let statements = parse_str("
+            fun f(a, b) {
+                var c = a + b * 2;
+                if (c > 1 and !(a == b)) {
+                    print g(a - 1, b[0]);
+                }
+                while (c < 10) { c += 1; }
+                return c ? -a : b ** 2;
+            }
+            print f(1, 2) / 3;
+        ").unwrap();
+        let mut counter = BinaryCounter { count: 0 };
+        for stmt in statements.iter() {
+            counter.visit_stmt(stmt);
+        }
+        // `c += 1` counts as `c = c + 1`, `and` is a logical expression.
+        assert_eq!(counter.count, 9);
+    }
+}
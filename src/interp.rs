@@ -0,0 +1,470 @@
+use std::cell::RefCell;
+use std::collections::{HashMap,HashSet};
+
+use thiserror::Error;
+
+use crate::llvm_translator::{ends_in_value,returns_value,BUILTINS,HEAP_SLOTS};
+use crate::optimize::{constant,fold_binary,fold_expr,int_variables,Constant};
+use crate::parser::{Expr,Param,Stmt,Type};
+use crate::pretty;
+use crate::token::Token;
+
+#[derive(Debug,Error)]
+pub enum RuntimeError {
+    #[error("unknown variable `{0}`")]
+    UnknownVariable(String),
+    #[error("unknown function `{0}`")]
+    UnknownFunction(String),
+    #[error("`{name}` expects {expected} arguments, found {found}")]
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("only named functions can be called")]
+    InvalidCallee,
+    #[error("call to `{0}` produced no value, it can only be used as a statement")]
+    VoidCall(String),
+    #[error("assertion failed")]
+    AssertionFailed,
+    #[error("cannot allocate an array of {0} elements")]
+    OutOfMemory(f64),
+    #[error("{0} is not an array")]
+    InvalidArray(f64),
+    #[error("index {index} is out of bounds for an array of length {len}")]
+    IndexOutOfBounds {
+        index: f64,
+        len: usize,
+    },
+    #[error("invalid operation `{0}`")]
+    InvalidOperation(Token),
+    #[error("member access `.{0}` is not supported yet")]
+    MemberAccess(Token),
+    #[error("cannot evaluate expression {0:?}")]
+    UnhandledExpr(Expr),
+}
+
+pub type RuntimeResult<T> = Result<T, RuntimeError>;
+
+// Variables of a call, one map per nested block, and the names of the
+// ones that only hold integers.
+struct Scopes<'f> {
+    blocks: Vec<HashMap<String, Constant>>,
+    ints: &'f HashSet<String>,
+}
+// Parameters, return type, body and integer variables of a function.
+type Function = (Vec<Param>, Option<Type>, Vec<Box<Stmt>>, HashSet<String>);
+
+// How a statement finished, `Return` unwinds to the enclosing call and
+// `Break` and `Continue` to the enclosing loop.
+enum Flow {
+    Normal(Constant),
+    Return(Constant),
+    Break,
+    Continue,
+}
+
+/// Evaluates programs by walking the AST, following the same rules as
+/// the compiled code: integers stay `i64` until mixed with floats, while
+/// arguments, return values and booleans are `f64`.
+pub struct Interpreter {
+    functions: HashMap<String, Function>,
+    /// Top-level variables, shared by every call.
+    globals: RefCell<HashMap<String, Constant>>,
+    /// Slots of the arrays allocated so far, laid out as in compiled code.
+    heap: RefCell<Vec<f64>>,
+}
+
+fn name_of(token: &Token) -> String {
+    match token {
+        Token::Ident(id) => id.clone(),
+        tok => tok.to_string(),
+    }
+}
+
+fn bool_constant(value: bool) -> Constant {
+    Constant::Float(if value { 1.0 } else { 0.0 })
+}
+
+// Converts a value for a variable or parameter of the given type.
+fn coerce(value: Constant, ty: Type) -> Constant {
+    match (ty, value) {
+        (Type::Int, Constant::Float(f)) => Constant::Int(f as i64),
+        (Type::Int, value) => value,
+        (Type::Float, value) => Constant::Float(value.as_f64()),
+    }
+}
+
+impl Interpreter {
+    /// Collects the function definitions and globals of the program.
+    /// Other top-level statements are expected to have been moved into a
+    /// function.
+    pub fn new(statements: &[Box<Stmt>]) -> Self {
+        let mut functions = HashMap::new();
+        let mut globals = HashMap::new();
+        for stmt in statements.iter() {
+            match stmt.as_ref() {
+                Stmt::Function { name, params, return_type, body, .. } => {
+                    let ints = int_variables(params, body);
+                    functions.insert(name_of(name), (params.clone(), *return_type, body.clone(), ints));
+                },
+                Stmt::Var { name, initializer, .. } => {
+                    if let Some(value) = constant(&fold_expr(initializer)) {
+                        globals.insert(name_of(name), value);
+                    }
+                },
+                _ => {},
+            }
+        }
+        Self { functions, globals: RefCell::new(globals), heap: RefCell::new(vec!()) }
+    }
+
+    /// Calls a function of the program with the given arguments, returning
+    /// None for void functions.
+    pub fn call(&self, name: &str, args: &[f64]) -> RuntimeResult<Option<f64>> {
+        let args = args
+            .iter()
+            .map(|arg| Constant::Float(*arg))
+            .collect::<Vec<Constant>>();
+        self.invoke(name, &args)
+    }
+
+    fn invoke(&self, name: &str, args: &[Constant]) -> RuntimeResult<Option<f64>> {
+        let Some((params, return_type, body, ints)) = self.functions.get(name) else {
+            return Err(RuntimeError::UnknownFunction(name.to_string()));
+        };
+        if params.len() != args.len() {
+            return Err(RuntimeError::ArityMismatch {
+                name: name.to_string(),
+                expected: params.len(),
+                found: args.len(),
+            });
+        }
+        let params = params
+            .iter()
+            .zip(args.iter())
+            .map(|(param, arg)| (name_of(&param.name), coerce(*arg, param.ty())))
+            .collect::<HashMap<String, Constant>>();
+        let mut env = Scopes { blocks: vec!(params), ints };
+
+        // Falling off the end returns the value of a trailing expression
+        // or conditional, as in compiled code.
+        let value = match self.exec_block(body, &mut env)? {
+            Flow::Return(value) => value,
+            Flow::Normal(value) if ends_in_value(body) => value,
+            _ => Constant::Float(0.0),
+        };
+        if !returns_value(body) {
+            return Ok(None);
+        }
+        Ok(Some(coerce(value, return_type.unwrap_or(Type::Float)).as_f64()))
+    }
+
+    fn exec_block(&self, statements: &[Box<Stmt>], env: &mut Scopes) -> RuntimeResult<Flow> {
+        let mut value = Constant::Float(0.0);
+        for stmt in statements.iter() {
+            match self.exec(stmt, env)? {
+                Flow::Normal(v) => value = v,
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal(value))
+    }
+
+    fn exec(&self, stmt: &Stmt, env: &mut Scopes) -> RuntimeResult<Flow> {
+        match stmt {
+            Stmt::Block(statements) => {
+                env.blocks.push(HashMap::new());
+                let flow = self.exec_block(statements, env);
+                env.blocks.pop();
+                flow
+            },
+            Stmt::Expr(expr) => {
+                // Calls to void functions can only stand as statements.
+                if let Expr::Call { callee, args, .. } = expr.as_ref() {
+                    let value = self.eval_call(callee, args, env)?;
+                    return Ok(Flow::Normal(value.unwrap_or(Constant::Float(0.0))));
+                }
+                Ok(Flow::Normal(self.eval(expr, env)?))
+            },
+            Stmt::Print(expr) => {
+                let value = self.eval(expr, env)?;
+                println!("{}", value.as_f64());
+                Ok(Flow::Normal(value))
+            },
+            Stmt::Return { value, .. } => {
+                let value = match value {
+                    Some(value) => self.eval(value, env)?,
+                    None => Constant::Float(0.0),
+                };
+                Ok(Flow::Return(Constant::Float(value.as_f64())))
+            },
+            // Nested functions have been lifted to the top level.
+            Stmt::Function { .. } => Ok(Flow::Normal(Constant::Float(0.0))),
+            Stmt::If { cond, then_branch, else_branch } => {
+                let branch = match self.eval(cond, env)?.is_truthy() {
+                    true => Some(then_branch),
+                    false => else_branch.as_ref(),
+                };
+                // The conditional evaluates to the float value of the
+                // branch taken, or 0.0 without an else branch.
+                let Some(branch) = branch else {
+                    return Ok(Flow::Normal(Constant::Float(0.0)));
+                };
+                match self.exec(branch, env)? {
+                    Flow::Normal(value) => Ok(Flow::Normal(Constant::Float(value.as_f64()))),
+                    flow => Ok(flow),
+                }
+            },
+            Stmt::While { condition, body, increment } => {
+                while self.eval(condition, env)?.is_truthy() {
+                    match self.exec(body, env)? {
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Break => break,
+                        Flow::Normal(_) | Flow::Continue => {},
+                    }
+                    if let Some(increment) = increment {
+                        self.eval(increment, env)?;
+                    }
+                }
+                Ok(Flow::Normal(Constant::Float(0.0)))
+            },
+            Stmt::Break { .. } => Ok(Flow::Break),
+            Stmt::Continue { .. } => Ok(Flow::Continue),
+            Stmt::Comment { .. } => Ok(Flow::Normal(Constant::Float(0.0))),
+            Stmt::Var { name, initializer, .. } => {
+                // Only variables that are never assigned anything but an
+                // integer keep an integer initializer, as in compiled code.
+                let value = match self.eval(initializer, env)? {
+                    Constant::Int(i) if env.ints.contains(name_of(name).as_str()) => Constant::Int(i),
+                    value => Constant::Float(value.as_f64()),
+                };
+                if let Some(scope) = env.blocks.last_mut() {
+                    scope.insert(name_of(name), value);
+                }
+                Ok(Flow::Normal(value))
+            },
+        }
+    }
+
+    fn eval(&self, expr: &Expr, env: &mut Scopes) -> RuntimeResult<Constant> {
+        match expr {
+            Expr::Literal { value } => match Constant::from_literal(value) {
+                Some(value) => Ok(value),
+                None => Err(RuntimeError::UnhandledExpr(expr.clone())),
+            },
+            Expr::Grouping { expr } => self.eval(expr, env),
+            Expr::Variable { name, .. } => {
+                let name = name_of(name);
+                if let Some(value) = env.blocks.iter().rev().find_map(|scope| scope.get(name.as_str())) {
+                    return Ok(*value);
+                }
+                match self.globals.borrow().get(name.as_str()) {
+                    Some(value) => Ok(*value),
+                    None => Err(RuntimeError::UnknownVariable(name)),
+                }
+            },
+            Expr::Assign { name, value, .. } => {
+                let name = name_of(name);
+                let value = self.eval(value, env)?;
+                let mut globals = self.globals.borrow_mut();
+                let local = env.blocks.iter_mut().rev().find_map(|scope| scope.get_mut(name.as_str()));
+                let Some(slot) = local.or_else(|| globals.get_mut(name.as_str())) else {
+                    return Err(RuntimeError::UnknownVariable(name));
+                };
+                // A variable keeps the type it was declared with.
+                let ty = match *slot {
+                    Constant::Int(_) => Type::Int,
+                    Constant::Float(_) => Type::Float,
+                };
+                *slot = coerce(value, ty);
+                Ok(*slot)
+            },
+            Expr::UnaryExpr { op, right } => {
+                let value = self.eval(right, env)?;
+                match (op, value) {
+                    (Token::Minus, Constant::Int(i)) => Ok(Constant::Int(i.wrapping_neg())),
+                    (Token::Minus, Constant::Float(f)) => Ok(Constant::Float(-f)),
+                    (Token::Bang, value) => Ok(bool_constant(!value.is_truthy())),
+                    (op, _) => Err(RuntimeError::InvalidOperation(op.clone())),
+                }
+            },
+            Expr::BinaryExpr { op, left, right } => {
+                let lhs = self.eval(left, env)?;
+                let rhs = self.eval(right, env)?;
+                match fold_binary(op, lhs, rhs) {
+                    Some(value) => Ok(value),
+                    None => Err(RuntimeError::InvalidOperation(op.clone())),
+                }
+            },
+            Expr::Logical { op, left, right } => {
+                let lhs = self.eval(left, env)?.is_truthy();
+                let value = match op {
+                    Token::And if !lhs => false,
+                    Token::Or if lhs => true,
+                    Token::And | Token::Or => self.eval(right, env)?.is_truthy(),
+                    op => return Err(RuntimeError::InvalidOperation(op.clone())),
+                };
+                Ok(bool_constant(value))
+            },
+            Expr::Ternary { cond, then, els } => {
+                let value = match self.eval(cond, env)?.is_truthy() {
+                    true => self.eval(then, env)?,
+                    false => self.eval(els, env)?,
+                };
+                Ok(Constant::Float(value.as_f64()))
+            },
+            Expr::Index { array, index, .. } => {
+                let slot = self.element_slot(array, index, env)?;
+                Ok(Constant::Float(self.heap.borrow()[slot]))
+            },
+            Expr::SetIndex { array, index, value, .. } => {
+                let slot = self.element_slot(array, index, env)?;
+                let value = self.eval(value, env)?.as_f64();
+                self.heap.borrow_mut()[slot] = value;
+                Ok(Constant::Float(value))
+            },
+            Expr::Call { callee, args, .. } => match self.eval_call(callee, args, env)? {
+                Some(value) => Ok(value),
+                None => Err(RuntimeError::VoidCall(pretty::expr(callee))),
+            },
+            Expr::Get { name, .. } => Err(RuntimeError::MemberAccess(name.clone())),
+            Expr::StringLiteral { .. } => Err(RuntimeError::UnhandledExpr(expr.clone())),
+        }
+    }
+
+    fn eval_call(&self, callee: &Expr, args: &[Box<Expr>], env: &mut Scopes) -> RuntimeResult<Option<Constant>> {
+        let Expr::Variable { name, .. } = callee else {
+            return Err(RuntimeError::InvalidCallee);
+        };
+        let name = name_of(name);
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args.iter() {
+            values.push(self.eval(arg, env)?);
+        }
+        // Functions defined in the program take precedence.
+        if !self.functions.contains_key(name.as_str()) && BUILTINS.contains(&name.as_str()) {
+            let values = values.iter().map(|value| value.as_f64()).collect::<Vec<f64>>();
+            return self.call_builtin(name.as_str(), &values).map(|value| Some(Constant::Float(value)));
+        }
+        Ok(self.invoke(name.as_str(), &values)?.map(Constant::Float))
+    }
+
+    // Finds the heap slot of an array element. The handle of an array is
+    // the slot of its first element, with the length in the slot before.
+    fn element_slot(&self, array: &Expr, index: &Expr, env: &mut Scopes) -> RuntimeResult<usize> {
+        let value = self.eval(array, env)?.as_f64();
+        let index = self.eval(index, env)?.as_f64();
+        let heap = self.heap.borrow();
+        // Truncated towards zero, as the compiled code converts them.
+        let handle = value as i64;
+        if handle < 1 || handle as usize > heap.len() {
+            return Err(RuntimeError::InvalidArray(value));
+        }
+        let handle = handle as usize;
+        let len = heap[handle - 1] as usize;
+        if (index as i64) < 0 || index as usize >= len {
+            return Err(RuntimeError::IndexOutOfBounds { index, len });
+        }
+        Ok(handle + index as usize)
+    }
+
+    fn allocate(&self, len: f64) -> RuntimeResult<f64> {
+        let mut heap = self.heap.borrow_mut();
+        let end = heap.len() as i64 + 1 + len as i64;
+        if (len as i64) < 0 || end > HEAP_SLOTS as i64 {
+            return Err(RuntimeError::OutOfMemory(len));
+        }
+        heap.push(len.trunc());
+        let handle = heap.len();
+        heap.resize(end as usize, 0.0);
+        Ok(handle as f64)
+    }
+
+    fn call_builtin(&self, name: &str, args: &[f64]) -> RuntimeResult<f64> {
+        let arity = match name {
+            "abs" | "sqrt" | "floor" | "ceil" | "assert" | "array" => 1,
+            _ => 2,
+        };
+        if args.len() != arity {
+            return Err(RuntimeError::ArityMismatch {
+                name: name.to_string(),
+                expected: arity,
+                found: args.len(),
+            });
+        }
+        let value = match name {
+            "abs" => args[0].abs(),
+            "sqrt" => args[0].sqrt(),
+            "floor" => args[0].floor(),
+            "ceil" => args[0].ceil(),
+            "min" => args[0].min(args[1]),
+            "max" => args[0].max(args[1]),
+            "pow" => args[0].powf(args[1]),
+            "safe_sub" => (args[0] - args[1]).max(0.0),
+            "assert" if !Constant::Float(args[0]).is_truthy() => return Err(RuntimeError::AssertionFailed),
+            "assert" => 0.0,
+            "array" => self.allocate(args[0])?,
+            name => return Err(RuntimeError::UnknownFunction(name.to_string())),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_str;
+
+    fn interpreter(source: &str) -> Interpreter {
+        Interpreter::new(&parse_str(source).unwrap())
+    }
+
+    #[test]
+    fn evaluates_arithmetic() {
+        let interp = interpreter("
+            fun arith(a, b) { return a * b - (a + b) / 2 % 3; }
+            fun ints() { var x = 7; x = x % 4 * 6 & 7; return x << 2 | 1; }
+            fun quarter() { return 10 / 4; }
+            fun kept(a) { var x = 1; x = a; return x; }
+        ");
+        assert_eq!(interp.call("arith", &[6.0, 7.0]).unwrap(), Some(41.5));
+        assert_eq!(interp.call("ints", &[]).unwrap(), Some(9.0));
+        assert_eq!(interp.call("quarter", &[]).unwrap(), Some(2.5));
+        assert_eq!(interp.call("kept", &[2.7]).unwrap(), Some(2.7));
+    }
+
+    #[test]
+    fn recursive_fib() {
+        let interp = interpreter("fun fib(n) { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); }");
+        assert_eq!(interp.call("fib", &[0.0]).unwrap(), Some(0.0));
+        assert_eq!(interp.call("fib", &[15.0]).unwrap(), Some(610.0));
+    }
+
+    #[test]
+    fn loops_with_jumps() {
+        let interp = interpreter("
+            fun sum(n) {
+                var total = 0;
+                for (var i = 0; i < n; i += 1) {
+                    if (i == 3) { continue; }
+                    if (i > 6) { break; }
+                    total += i;
+                }
+                return total;
+            }
+        ");
+        assert_eq!(interp.call("sum", &[5.0]).unwrap(), Some(7.0));
+        assert_eq!(interp.call("sum", &[100.0]).unwrap(), Some(18.0));
+    }
+
+    #[test]
+    fn reports_bad_calls() {
+        let interp = interpreter("fun one(a) { return a; }");
+        assert!(matches!(interp.call("two", &[]), Err(RuntimeError::UnknownFunction(name)) if name == "two"));
+        assert!(matches!(
+            interp.call("one", &[]),
+            Err(RuntimeError::ArityMismatch { expected: 1, found: 0, .. })
+        ));
+    }
+}
@@ -5,30 +5,48 @@ use std::str::Chars;
 use thiserror::Error;
 use eyre::Result;
 
-use crate::token::Token;
+use crate::token::{Span,Spanned,Token};
 
 #[derive(Debug,Error)]
 pub enum LexingError {
     #[error("unknown token matched `{0}`")]
     UnknownToken(String),
+    #[error("malformed number literal `{0}`")]
+    MalformedNumber(String),
+    #[error("unterminated string literal")]
+    UnterminatedString,
+    #[error("unknown escape sequence `\\{0}`")]
+    UnknownEscape(char),
 }
 
-pub type LexResult = Result<Token, LexingError>;
+pub type LexResult = Result<Spanned<Token>, LexingError>;
 
 pub struct TokenLexer<'a> {
     input: &'a str,
     chars: Box<Peekable<Chars<'a>>>,
     curr: usize,
+    line: usize,
+    col: usize,
+    done: bool,
+    /// Token lexed ahead by `peek_token`, yielded by the next call to
+    /// `next`.
+    peeked: Option<Option<LexResult>>,
+    position: usize,
 }
 
+/// Yields tokens until the end of the input. An error is yielded once and
+/// ends the stream, as the lexer can't tell where the bad token stops.
+/// Comments after the last token are kept on a final `EOF` token, which
+/// is only yielded for them.
 impl<'a> Iterator for TokenLexer<'a> {
-    type Item = Token;
+    type Item = LexResult;
     fn next(&mut self) -> Option<Self::Item> {
-        match self.lex() {
-            Ok(Token::EOF) => None,
-            Err(_) => None,
-            Ok(token) => Some(token),
-        }
+        let result = match self.peeked.take() {
+            Some(peeked) => peeked,
+            None => self.lex_next(),
+        };
+        self.position = self.curr;
+        result
     }
 }
 
@@ -38,6 +56,46 @@ impl<'a> TokenLexer<'a> {
             input,
             chars: Box::new(input.chars().peekable()),
             curr: 0,
+            line: 1,
+            col: 1,
+            done: false,
+            peeked: None,
+            position: 0,
+        }
+    }
+
+    /// Returns the token the next call to `next` yields without consuming
+    /// it, or None at the end of the input or on a lexing error.
+    pub fn peek_token(&mut self) -> Option<Token> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lex_next());
+        }
+        match self.peeked.as_ref() {
+            Some(Some(Ok(token))) => Some(token.node.clone()),
+            _ => None,
+        }
+    }
+
+    /// Byte offset in the input just past the last token yielded by
+    /// `next`. Peeking doesn't move it.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    fn lex_next(&mut self) -> Option<LexResult> {
+        if self.done {
+            return None;
+        }
+        match self.lex() {
+            Ok(Spanned { node: Token::EOF, leading_trivia, .. }) if leading_trivia.is_empty() => {
+                self.done = true;
+                None
+            },
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            },
+            Ok(token) => Some(Ok(token)),
         }
     }
 
@@ -46,73 +104,177 @@ impl<'a> TokenLexer<'a> {
         let src = self.input;
 
         let mut curr = self.curr;
+        let mut line = self.line;
+        let mut col = self.col;
+        let mut leading_trivia = vec!();
         loop {
             {
                 let ch = chars.peek();
                 if ch.is_none() {
                     self.curr = curr;
-                    return Ok(Token::EOF);
+                    self.line = line;
+                    self.col = col;
+                    let mut eof = Spanned::new(Token::EOF, Span { line, col, len: 0 });
+                    eof.leading_trivia = leading_trivia;
+                    return Ok(eof);
+                }
+                let ch = *ch.unwrap();
+                // Line comments don't produce a token, they are kept as
+                // trivia of the token that follows.
+                if ch == '/' && chars.clone().nth(1) == Some('/') {
+                    let comment_start = curr;
+                    while let Some(ch) = chars.peek() {
+                        if *ch == '\n' {
+                            break;
+                        }
+                        curr += ch.len_utf8();
+                        chars.next();
+                        col += 1;
+                    }
+                    leading_trivia.push(src[comment_start..curr].trim_end().to_string());
+                    continue;
                 }
-                if !ch.unwrap().is_whitespace() {
+                if !ch.is_whitespace() {
                     break;
                 }
+                if ch == '\n' {
+                    line += 1;
+                    col = 1;
+                } else {
+                    col += 1;
+                }
+            }
+            // `curr` is a byte offset into the source, while the position
+            // counts characters.
+            if let Some(ch) = chars.next() {
+                curr += ch.len_utf8();
             }
-            chars.next();
-            curr += 1;
         }
 
         let start = curr;
-        let next = chars.next();
-
-        if next.is_none() {
-            return Ok(Token::EOF);
-        }
-
-        curr += 1;
+        let Some(next) = chars.next() else {
+            return Ok(Spanned::new(Token::EOF, Span { line, col, len: 0 }));
+        };
+        curr += next.len_utf8();
 
-        let result = match next.unwrap() {
+        let result = match next {
             '(' => Ok(Token::LParen),
             ')' => Ok(Token::RParen),
             ',' => Ok(Token::Comma),
             ';' => Ok(Token::Semicolon),
             '{' => Ok(Token::LBrace),
             '}' => Ok(Token::RBrace),
-            '0'..='9' | '.' => {
+            '[' => Ok(Token::LBracket),
+            ']' => Ok(Token::RBracket),
+            '"' => {
+                let mut value = String::new();
                 loop {
-                    let ch = match chars.peek() {
-                        Some(ch) => *ch,
-                        None => return Ok(Token::EOF),
+                    let ch = match chars.next() {
+                        Some(ch) => ch,
+                        None => return Err(LexingError::UnterminatedString),
                     };
-                    if ch != '.' && !ch.is_ascii_hexdigit() {
+                    curr += ch.len_utf8();
+                    match ch {
+                        '"' => break,
+                        '\\' => {
+                            let escaped = match chars.next() {
+                                Some(ch) => ch,
+                                None => return Err(LexingError::UnterminatedString),
+                            };
+                            curr += escaped.len_utf8();
+                            match escaped {
+                                'n' => value.push('\n'),
+                                't' => value.push('\t'),
+                                '"' => value.push('"'),
+                                '\\' => value.push('\\'),
+                                unknown => return Err(LexingError::UnknownEscape(unknown)),
+                            }
+                        },
+                        ch => value.push(ch),
+                    }
+                }
+                Ok(Token::Str(value))
+            },
+            '0' if matches!(chars.peek(), Some('x' | 'X')) => {
+                chars.next();
+                curr += 1;
+                while let Some(&ch) = chars.peek() {
+                    if !ch.is_ascii_hexdigit() {
+                        break;
+                    }
+                    chars.next();
+                    curr += 1;
+                }
+                // The literal keeps its spelling, so it is only checked here.
+                match i64::from_str_radix(&src[start+2..curr], 16) {
+                    Ok(_) => Ok(Token::HexNumber(src[start..curr].to_string())),
+                    Err(_) => return Err(LexingError::MalformedNumber(src[start..curr].to_string())),
+                }
+            },
+            // A leading `.` only starts a number when a digit follows, a lone
+            // `.` is left for the operators below.
+            digit @ ('0'..='9' | '.') if digit != '.' || matches!(chars.peek(), Some('0'..='9')) => {
+                // Allows a single `.` and an optional exponent with a sign.
+                let mut seen_dot = digit == '.';
+                let mut seen_exp = false;
+                while let Some(&ch) = chars.peek() {
+                    if ch == '.' {
+                        chars.next();
+                        curr += 1;
+                        if seen_dot || seen_exp {
+                            return Err(LexingError::MalformedNumber(src[start..curr].to_string()));
+                        }
+                        seen_dot = true;
+                        continue;
+                    }
+                    if (ch == 'e' || ch == 'E') && !seen_exp {
+                        seen_exp = true;
+                        chars.next();
+                        curr += 1;
+                        if let Some('+' | '-') = chars.peek() {
+                            chars.next();
+                            curr += 1;
+                        }
+                        match chars.peek() {
+                            Some(ch) if ch.is_ascii_digit() => {},
+                            _ => return Err(LexingError::MalformedNumber(src[start..curr].to_string())),
+                        }
+                        continue;
+                    }
+                    if !ch.is_ascii_digit() {
                         break;
                     }
                     chars.next();
                     curr += 1;
                 }
-                Ok(Token::Number(src[start..curr].parse().unwrap()))
+                let number = &src[start..curr];
+                if number.parse::<f64>().is_err() {
+                    return Err(LexingError::MalformedNumber(number.to_string()));
+                }
+                Ok(Token::Number(number.to_string()))
             },
 
             'a'..='z' | 'A'..='Z' | '_' => {
-                loop {
-                    let ch = match chars.peek() {
-                        Some(ch) => *ch,
-                        None => return Ok(Token::EOF),
-                    };
+                while let Some(&ch) = chars.peek() {
                     if ch != '_' && !ch.is_alphanumeric() {
                         break;
                     }
                     chars.next();
-                    curr += 1;
+                    curr += ch.len_utf8();
                 }
 
                 match &src[start..curr] {
                     "var" => Ok(Token::Var),
                     "if" => Ok(Token::If),
+                    "while" => Ok(Token::While),
                     "for" => Ok(Token::For),
+                    "break" => Ok(Token::Break),
+                    "continue" => Ok(Token::Continue),
                     "gm" => Ok(Token::Fun),
                     "mai" => Ok(Token::Fun),
                     "fun" => Ok(Token::Fun),
                     "return" => Ok(Token::Return),
+                    "print" => Ok(Token::Print),
                     "or" => Ok(Token::Or),
                     "and" => Ok(Token::And),
                     "else" => Ok(Token::Else),
@@ -141,19 +303,263 @@ impl<'a> TokenLexer<'a> {
                     };
                 }
                 match op {
-                    '+' => Ok(Token::Plus),
-                    '-' => Ok(Token::Minus),
-                    '*' => Ok(Token::Times),
-                    '/' => Ok(Token::Div),
-                    '!' => peek_next_otherwise!('=', Token::Neq, Token::Bang),
+                    '+' => peek_next_otherwise!('=', Token::PlusEq, Token::Plus),
+                    '-' => match chars.peek() {
+                        Some('>') => {
+                            chars.next();
+                            curr += 1;
+                            Ok(Token::Arrow)
+                        },
+                        _ => peek_next_otherwise!('=', Token::MinusEq, Token::Minus),
+                    },
+                    '*' => match chars.peek() {
+                        Some('*') => {
+                            chars.next();
+                            curr += 1;
+                            Ok(Token::StarStar)
+                        },
+                        _ => peek_next_otherwise!('=', Token::TimesEq, Token::Times),
+                    },
+                    '/' => peek_next_otherwise!('=', Token::DivEq, Token::Div),
+                    '%' => peek_next_otherwise!('=', Token::PercentEq, Token::Percent),
+                    '!' => peek_next_otherwise!('=', Token::BangEq, Token::Bang),
                     '=' => peek_next_otherwise!('=', Token::Eqq, Token::Eq),
-                    '<' => peek_next_otherwise!('=', Token::Leq, Token::Less),
-                    '>' => peek_next_otherwise!('=', Token::Geq, Token::Greater),
+                    // The longest operator wins, `<<=` is never `<<` then `=`.
+                    '<' => match chars.peek() {
+                        Some('<') => {
+                            chars.next();
+                            curr += 1;
+                            peek_next_otherwise!('=', Token::ShiftLeftEq, Token::ShiftLeft)
+                        },
+                        _ => peek_next_otherwise!('=', Token::Leq, Token::Less),
+                    },
+                    '>' => match chars.peek() {
+                        Some('>') => {
+                            chars.next();
+                            curr += 1;
+                            peek_next_otherwise!('=', Token::ShiftRightEq, Token::ShiftRight)
+                        },
+                        _ => peek_next_otherwise!('=', Token::Geq, Token::Greater),
+                    },
+                    '&' => peek_next_otherwise!('=', Token::AmpEq, Token::Amp),
+                    '|' => peek_next_otherwise!('=', Token::PipeEq, Token::Pipe),
+                    '^' => peek_next_otherwise!('=', Token::CaretEq, Token::Caret),
+                    '?' => Ok(Token::Question),
+                    ':' => Ok(Token::Colon),
+                    // Not followed by a digit, or it would start a number.
+                    '.' => Ok(Token::Dot),
                     unknown => Err(LexingError::UnknownToken(unknown.to_string()))
                 }
             },
         };
+
+        // Tokens such as strings may span several lines, so advance the
+        // position over the consumed text rather than by its length.
+        let span = Span { line, col, len: src[start..curr].chars().count() };
+        for ch in src[start..curr].chars() {
+            if ch == '\n' {
+                line += 1;
+                col = 1;
+            } else {
+                col += 1;
+            }
+        }
+
+        // A comment on the rest of the line trails the token.
+        let mut rest = chars.clone().skip_while(|ch| *ch == ' ' || *ch == '\t');
+        let mut trailing_trivia = None;
+        if rest.next() == Some('/') && rest.next() == Some('/') {
+            while let Some(' ' | '\t') = chars.peek() {
+                chars.next();
+                curr += 1;
+                col += 1;
+            }
+            let comment_start = curr;
+            while let Some(ch) = chars.peek() {
+                if *ch == '\n' {
+                    break;
+                }
+                curr += ch.len_utf8();
+                chars.next();
+                col += 1;
+            }
+            trailing_trivia = Some(src[comment_start..curr].trim_end().to_string());
+        }
+
         self.curr = curr;
-        result
+        self.line = line;
+        self.col = col;
+        result.map(|token| Spanned { node: token, span, leading_trivia, trailing_trivia })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimize::Constant;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        TokenLexer::new(input).map(|token| token.unwrap().node).collect()
+    }
+
+    fn ident(name: &str) -> Token {
+        Token::Ident(name.to_string())
+    }
+
+    #[test]
+    fn bang_eq_is_one_token() {
+        assert_eq!(tokens("a != b"), vec!(ident("a"), Token::BangEq, ident("b")));
+        assert_eq!(tokens("!a"), vec!(Token::Bang, ident("a")));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(tokens(r#""hello""#), vec!(Token::Str("hello".to_string())));
+        assert_eq!(
+            tokens(r#""tab\t, newline\n, quote \" and backslash \\""#),
+            vec!(Token::Str("tab\t, newline\n, quote \" and backslash \\".to_string())),
+        );
+    }
+
+    #[test]
+    fn unterminated_string() {
+        for input in [r#""no end"#, r#""escaped end\""#] {
+            let result = TokenLexer::new(input).next().unwrap();
+            assert!(matches!(result, Err(LexingError::UnterminatedString)), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn unknown_escape() {
+        let result = TokenLexer::new(r#""\q""#).next().unwrap();
+        assert!(matches!(result, Err(LexingError::UnknownEscape('q'))), "{:?}", result);
+    }
+
+    #[test]
+    fn spans_are_one_indexed_across_lines() {
+        let spans = TokenLexer::new("var x = 1;\n  print x;\n\nx")
+            .map(|token| {
+                let token = token.unwrap();
+                (token.node, token.span.line, token.span.col, token.span.len)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec!(
+            (Token::Var, 1, 1, 3),
+            (ident("x"), 1, 5, 1),
+            (Token::Eq, 1, 7, 1),
+            (Token::Number("1".to_string()), 1, 9, 1),
+            (Token::Semicolon, 1, 10, 1),
+            (Token::Print, 2, 3, 5),
+            (ident("x"), 2, 9, 1),
+            (Token::Semicolon, 2, 10, 1),
+            (ident("x"), 4, 1, 1),
+        ));
+    }
+
+    #[test]
+    fn keywords() {
+        let keywords = [
+            ("var", Token::Var),
+            ("if", Token::If),
+            ("else", Token::Else),
+            ("while", Token::While),
+            ("for", Token::For),
+            ("break", Token::Break),
+            ("continue", Token::Continue),
+            ("fun", Token::Fun),
+            ("mai", Token::Fun),
+            ("gm", Token::Fun),
+            ("return", Token::Return),
+            ("print", Token::Print),
+            ("and", Token::And),
+            ("or", Token::Or),
+            ("true", Token::True),
+            ("false", Token::False),
+            ("wagmi", Token::Wagmi),
+        ];
+        for (keyword, token) in keywords {
+            assert_eq!(tokens(keyword), vec!(token), "{}", keyword);
+        }
+        // A keyword at the start of a longer name is part of the name.
+        assert_eq!(tokens("funny"), vec!(ident("funny")));
+        assert_eq!(tokens("format returned"), vec!(ident("format"), ident("returned")));
+    }
+
+    fn number(value: &str) -> Token {
+        Token::Number(value.to_string())
+    }
+
+    #[test]
+    fn float_literals() {
+        assert_eq!(tokens("3.14"), vec!(number("3.14")));
+        assert_eq!(tokens("1e10 2.5E-3 7e+2"), vec!(number("1e10"), number("2.5E-3"), number("7e+2")));
+        // A trailing dot is part of the number.
+        assert_eq!(tokens("1. + 2"), vec!(number("1."), Token::Plus, number("2")));
+        for input in ["1.2.3", "1e", "1e+", "1e5.0"] {
+            let result = TokenLexer::new(input).next().unwrap();
+            assert!(matches!(result, Err(LexingError::MalformedNumber(_))), "{}: {:?}", input, result);
+        }
+    }
+
+    #[test]
+    fn decimal_stops_at_letters() {
+        assert_eq!(tokens("12abc"), vec!(number("12"), ident("abc")));
+        assert_eq!(tokens("0xFF 0x1f"), vec!(Token::HexNumber("0xFF".to_string()), Token::HexNumber("0x1f".to_string())));
+        assert_eq!(Constant::from_literal("0xFF"), Some(Constant::Int(255)));
+        let result = TokenLexer::new("0x").next().unwrap();
+        assert!(matches!(result, Err(LexingError::MalformedNumber(ref n)) if n == "0x"), "{:?}", result);
+    }
+
+    #[test]
+    fn leading_dot() {
+        assert_eq!(tokens(".5"), vec!(number(".5")));
+        assert_eq!(tokens("-.5"), vec!(Token::Minus, number(".5")));
+        // Without a digit after it, a `.` is not a number.
+        assert_eq!(tokens("."), vec!(Token::Dot));
+        assert_eq!(tokens(". 5"), vec!(Token::Dot, number("5")));
+    }
+
+    #[test]
+    fn unknown_token_is_an_error() {
+        let results = TokenLexer::new("var x = 1 @ 2;").collect::<Vec<LexResult>>();
+        assert_eq!(results.len(), 5, "{:?}", results);
+        assert!(matches!(results.last(), Some(Err(LexingError::UnknownToken(token))) if token == "@"), "{:?}", results);
+        assert!(crate::lex("@").is_err());
+    }
+
+    #[test]
+    fn peeking_does_not_consume() {
+        let mut lexer = TokenLexer::new("var x = 10;");
+        assert_eq!(lexer.next().unwrap().unwrap().node, Token::Var);
+        assert_eq!(lexer.position(), 3);
+        for expected in [ident("x"), Token::Eq, number("10"), Token::Semicolon] {
+            let position = lexer.position();
+            assert_eq!(lexer.peek_token(), Some(expected.clone()));
+            assert_eq!(lexer.peek_token(), Some(expected.clone()));
+            assert_eq!(lexer.position(), position);
+            assert_eq!(lexer.next().unwrap().unwrap().node, expected);
+            assert!(lexer.position() > position);
+        }
+        assert_eq!(lexer.position(), 11);
+        assert_eq!(lexer.peek_token(), None);
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn three_character_operators() {
+        assert_eq!(tokens("a<<=2"), vec!(ident("a"), Token::ShiftLeftEq, number("2")));
+        assert_eq!(tokens("a>>=2"), vec!(ident("a"), Token::ShiftRightEq, number("2")));
+        // The longest operator wins, and spaces split them.
+        assert_eq!(tokens("<<= << <= <"), vec!(Token::ShiftLeftEq, Token::ShiftLeft, Token::Leq, Token::Less));
+        assert_eq!(tokens(">>= >> >= >"), vec!(Token::ShiftRightEq, Token::ShiftRight, Token::Geq, Token::Greater));
+        assert_eq!(tokens("<< ="), vec!(Token::ShiftLeft, Token::Eq));
+        assert_eq!(tokens("<<=="), vec!(Token::ShiftLeftEq, Token::Eq));
+    }
+
+    #[test]
+    fn dot_between_names() {
+        assert_eq!(tokens("1.5"), vec!(number("1.5")));
+        assert_eq!(tokens("x.y"), vec!(ident("x"), Token::Dot, ident("y")));
+        assert_eq!(tokens("a.b.c"), vec!(ident("a"), Token::Dot, ident("b"), Token::Dot, ident("c")));
     }
 }
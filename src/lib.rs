@@ -0,0 +1,916 @@
+// The tree is boxed throughout and functions end with an explicit `return`.
+#![allow(clippy::needless_return,clippy::borrowed_box,clippy::vec_box)]
+
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::process::{self,Command,Stdio};
+use std::ptr::NonNull;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use execute::Execute;
+use eyre::WrapErr;
+
+use inkwell::{AddressSpace,OptimizationLevel};
+use inkwell::context::Context;
+use inkwell::module::Module;
+use inkwell::passes::PassManager;
+use inkwell::targets::{CodeModel,FileType,InitializationConfig,RelocMode,Target,TargetMachine,TargetTriple};
+use inkwell::values::{BasicValueEnum,FunctionValue};
+use wasmer::CompilerConfig;
+use wasmer_middlewares::Metering;
+use wasmer_middlewares::metering::{get_remaining_points,MeteringPoints};
+
+pub mod token;
+pub mod ast;
+pub mod lexer;
+pub mod parser;
+pub mod llvm_translator;
+pub mod optimize;
+pub mod interp;
+pub mod pretty;
+pub mod resolver;
+pub mod lift;
+pub mod repl;
+
+use llvm_translator::{DebugInfo,Translator};
+use parser::{Parser,Stmt};
+use lexer::{LexingError,TokenLexer};
+use token::{Span,Spanned,Token};
+
+/// Name of the function that top-level statements outside of any
+/// function are gathered into.
+pub const MAIN_FN: &str = "main";
+
+/// Optimization level used when none is given, from 0 (no passes) to 3.
+pub const DEFAULT_OPT_LEVEL: u8 = 2;
+
+/// Runs only the front end, lexing, parsing and resolving the source,
+/// and reports its diagnostics without touching LLVM.
+pub fn check(source: &str) -> eyre::Result<()> {
+    let statements = parse(lex(source)?)?;
+    resolve(&with_implicit_main(&statements))
+}
+
+/// Compiles the source into textual LLVM IR.
+pub fn compile_to_ir(source: &str) -> eyre::Result<String> {
+    Compiler::new().compile(source)
+}
+
+/// Compiles many programs with one LLVM context, which is cheaper than
+/// creating a context per program as `compile_to_ir` does.
+///
+/// Every LLVM module borrows the context it was created in. Each call to
+/// `compile` makes a fresh module borrowing the context owned here and
+/// drops it before returning, so only the printed IR outlives the call
+/// and callers never have to name the context's lifetime. LLVM contexts
+/// are not thread safe, so each thread needs its own `Compiler`.
+pub struct Compiler {
+    context: Context,
+    options: TranslateOptions<'static>,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self::with_options(TranslateOptions::default())
+    }
+
+    pub fn with_options(options: TranslateOptions<'static>) -> Self {
+        Self { context: Context::create(), options }
+    }
+
+    /// Compiles the source into textual LLVM IR.
+    pub fn compile(&self, source: &str) -> eyre::Result<String> {
+        let statements = parse(lex(source)?)?;
+        let module = self.context.create_module("tmp");
+        translate(&self.context, &module, &statements, &self.options)?;
+        Ok(module.print_to_string().to_string())
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compiles the source into a wasm binary, linking it with the first
+/// `wasm-ld` found.
+pub fn compile_to_wasm(source: &str) -> eyre::Result<Vec<u8>> {
+    let statements = parse(lex(source)?)?;
+    let context = Context::create();
+    let module = context.create_module("tmp");
+    let exports = translate(&context, &module, &statements, &TranslateOptions::default())?;
+
+    let dir = TempDir::new()?;
+    let object_path = dir.path().join("main.o");
+    compile_to_object(&module, &object_path, DEFAULT_OPT_LEVEL, WASM_TRIPLE)?;
+    let linker = find_tool(WASM_LINKERS)?;
+    link_wasm(&object_path, linker.as_str(), &exports)
+}
+
+/// Limits on running a wasm module, none are applied by default.
+#[derive(Debug,Clone,Default)]
+pub struct RunOptions {
+    /// Number of wasm instructions the call may execute before it is
+    /// stopped, so a runaway loop can't hang.
+    pub fuel: Option<u64>,
+    /// Largest memory, in 64 KiB pages, the module may use. A module
+    /// needing more is refused, and growing past it fails.
+    pub max_memory_pages: Option<u32>,
+}
+
+// Creates memories as the base tunables do, with their maximum lowered to
+// the limit so `memory.grow` can't go past it.
+struct LimitingTunables {
+    base: wasmer::BaseTunables,
+    limit: wasmer::Pages,
+}
+
+impl LimitingTunables {
+    fn limited(&self, ty: &wasmer::MemoryType) -> Result<wasmer::MemoryType, wasmer::vm::MemoryError> {
+        if ty.minimum > self.limit {
+            return Err(wasmer::vm::MemoryError::Generic(format!(
+                "the module needs {} memory pages, more than the limit of {}",
+                ty.minimum.0, self.limit.0,
+            )));
+        }
+        let mut limited = *ty;
+        limited.maximum = Some(ty.maximum.map_or(self.limit, |maximum| maximum.min(self.limit)));
+        Ok(limited)
+    }
+}
+
+impl wasmer::Tunables for LimitingTunables {
+    fn memory_style(&self, memory: &wasmer::MemoryType) -> wasmer::vm::MemoryStyle {
+        self.base.memory_style(memory)
+    }
+
+    fn table_style(&self, table: &wasmer::TableType) -> wasmer::vm::TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(
+        &self,
+        ty: &wasmer::MemoryType,
+        style: &wasmer::vm::MemoryStyle,
+    ) -> Result<wasmer::vm::VMMemory, wasmer::vm::MemoryError> {
+        self.base.create_host_memory(&self.limited(ty)?, style)
+    }
+
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &wasmer::MemoryType,
+        style: &wasmer::vm::MemoryStyle,
+        vm_definition_location: NonNull<wasmer::vm::VMMemoryDefinition>,
+    ) -> Result<wasmer::vm::VMMemory, wasmer::vm::MemoryError> {
+        self.base.create_vm_memory(&self.limited(ty)?, style, vm_definition_location)
+    }
+
+    fn create_host_table(
+        &self,
+        ty: &wasmer::TableType,
+        style: &wasmer::vm::TableStyle,
+    ) -> Result<wasmer::vm::VMTable, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(
+        &self,
+        ty: &wasmer::TableType,
+        style: &wasmer::vm::TableStyle,
+        vm_definition_location: NonNull<wasmer::vm::VMTableDefinition>,
+    ) -> Result<wasmer::vm::VMTable, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
+
+/// Instantiates a wasm binary, or its text format, and calls one of its
+/// exported functions. Returns None when the function is void.
+pub fn run_wasm(bytes: &[u8], func: &str, args: &[f64]) -> eyre::Result<Option<f64>> {
+    run_wasm_with_options(bytes, func, args, &RunOptions::default())
+}
+
+/// Same as `run_wasm`, within the given limits.
+pub fn run_wasm_with_options(bytes: &[u8], func: &str, args: &[f64], options: &RunOptions) -> eyre::Result<Option<f64>> {
+    let mut compiler = wasmer::Cranelift::default();
+    // Every instruction costs one unit of fuel.
+    if let Some(fuel) = options.fuel {
+        compiler.push_middleware(Arc::new(Metering::new(fuel, |_: &wasmer::wasmparser::Operator| 1)));
+    }
+    let engine = wasmer::EngineBuilder::new(compiler).engine();
+    let mut store = match options.max_memory_pages {
+        Some(max_pages) => {
+            let base = wasmer::BaseTunables::for_target(engine.target());
+            wasmer::Store::new_with_tunables(engine, LimitingTunables { base, limit: wasmer::Pages(max_pages) })
+        },
+        None => wasmer::Store::new(engine),
+    };
+    let instance = instantiate(&mut store, bytes)?;
+    let result = call_export(&mut store, &instance, func, args);
+    if let Some(fuel) = options.fuel {
+        if let MeteringPoints::Exhausted = get_remaining_points(&mut store, &instance) {
+            eyre::bail!("`{}` ran out of fuel after {} instructions", func, fuel);
+        }
+    }
+    result
+}
+
+/// Compiles the source to wasm once and makes each of the calls, given as
+/// a function name and its arguments, in order on the same instance. The
+/// calls share its memory, so arrays allocated by one stay allocated.
+pub fn run_all(source: &str, calls: &[(&str, Vec<f64>)]) -> eyre::Result<Vec<f64>> {
+    let wasm = compile_to_wasm(source)?;
+    let mut store = wasmer::Store::default();
+    let instance = instantiate(&mut store, &wasm)?;
+    let mut results = Vec::with_capacity(calls.len());
+    for (func, args) in calls.iter() {
+        match call_export(&mut store, &instance, func, args)? {
+            Some(value) => results.push(value),
+            None => eyre::bail!("`{}` returns no value", func),
+        }
+    }
+    Ok(results)
+}
+
+fn instantiate(store: &mut wasmer::Store, bytes: &[u8]) -> eyre::Result<wasmer::Instance> {
+    let module = wasmer::Module::new(&*store, bytes)?;
+    // Provide the host functions the module may import. LLVM lowers some
+    // float operations to libm calls, which are left as imports as the
+    // module is linked without a libc.
+    let import_object = wasmer::imports! {
+        "env" => {
+            llvm_translator::PRINT_FN => wasmer::Function::new_typed(store, print_f64),
+            "pow" => wasmer::Function::new_typed(store, |x: f64, y: f64| x.powf(y)),
+            "fmin" => wasmer::Function::new_typed(store, |x: f64, y: f64| x.min(y)),
+            "fmax" => wasmer::Function::new_typed(store, |x: f64, y: f64| x.max(y)),
+            "fmod" => wasmer::Function::new_typed(store, |x: f64, y: f64| x % y),
+        },
+    };
+    Ok(wasmer::Instance::new(store, &module, &import_object)?)
+}
+
+fn call_export(
+    store: &mut wasmer::Store,
+    instance: &wasmer::Instance,
+    func: &str,
+    args: &[f64],
+) -> eyre::Result<Option<f64>> {
+    let function = instance.exports.get_function(func)?;
+    let params = function.ty(&*store).params().to_vec();
+    if params.len() != args.len() {
+        eyre::bail!("`{}` expects {} arguments, found {}", func, params.len(), args.len());
+    }
+    // Parameters annotated as `int` take an i64.
+    let args = args
+        .iter()
+        .zip(params.iter())
+        .map(|(arg, param)| match param {
+            wasmer::Type::I64 => wasmer::Value::I64(*arg as i64),
+            _ => wasmer::Value::F64(*arg),
+        })
+        .collect::<Vec<wasmer::Value>>();
+    // Traps, such as a failed `assert`, surface as runtime errors.
+    let result = function
+        .call(store, &args)
+        .map_err(|err| eyre::eyre!("`{}` trapped: {}", func, err.message()))?;
+    match result.first() {
+        Some(wasmer::Value::F64(value)) => Ok(Some(*value)),
+        // Functions declared `-> int` return an i64.
+        Some(wasmer::Value::I64(value)) => Ok(Some(*value as f64)),
+        None => Ok(None),
+        other => eyre::bail!("expected `{}` to return an f64, got {:?}", func, other),
+    }
+}
+
+fn print_f64(value: f64) {
+    println!("{}", value);
+}
+
+pub fn lex(input: &str) -> eyre::Result<Vec<Spanned<Token>>> {
+    TokenLexer::new(input)
+        .collect::<Result<Vec<Spanned<Token>>, LexingError>>()
+        .map_err(|err| eyre::eyre!("failed to lex input: {}", err))
+}
+
+pub fn parse(tokens: Vec<Spanned<Token>>) -> eyre::Result<Vec<Box<Stmt>>> {
+    Parser::new(tokens.into_iter()).parse().map_err(parse_errors)
+}
+
+/// Parses keeping the comments as `Stmt::Comment`, so `pretty::print`
+/// reproduces them. The statements are only meant for formatting.
+pub fn parse_with_comments(tokens: Vec<Spanned<Token>>) -> eyre::Result<Vec<Box<Stmt>>> {
+    Parser::with_comments(tokens.into_iter()).parse().map_err(parse_errors)
+}
+
+/// Parses tokens as the lexer produces them, without collecting them
+/// first. Gives the same statements as `parse(lex(source)?)`.
+pub fn parse_streaming(source: &str) -> eyre::Result<Vec<Box<Stmt>>> {
+    let mut lex_error = None;
+    let tokens = TokenLexer::new(source).map_while(|token| token.map_err(|err| lex_error = Some(err)).ok());
+    let result = Parser::new(tokens).parse();
+    // The parser sees a lexing error as the end of the input, so it is
+    // reported before any parse errors it caused.
+    if let Some(err) = lex_error {
+        eyre::bail!("failed to lex input: {}", err);
+    }
+    result.map_err(parse_errors)
+}
+
+fn parse_errors(errors: Vec<parser::ParseError>) -> eyre::Report {
+    let messages = errors
+        .iter()
+        .map(|err| err.to_string())
+        .collect::<Vec<String>>();
+    eyre::eyre!("failed to parse input:\n{}", messages.join("\n"))
+}
+
+/// Lexes and parses the source without touching the filesystem or any
+/// global state. Malformed input is reported as an error and never
+/// panics, which makes this suitable as a fuzzing target.
+pub fn parse_str(source: &str) -> eyre::Result<Vec<Box<Stmt>>> {
+    parse(lex(source)?)
+}
+
+/// Moves the top-level statements that aren't function definitions or
+/// globals into a generated `main` function, so scripts run without
+/// boilerplate.
+pub fn with_implicit_main(statements: &[Box<Stmt>]) -> Vec<Box<Stmt>> {
+    let (mut functions, rest): (Vec<Box<Stmt>>, Vec<Box<Stmt>>) = statements
+        .iter()
+        .cloned()
+        .partition(|stmt| matches!(stmt.as_ref(), Stmt::Function { .. }) || is_global(stmt));
+    if !rest.is_empty() {
+        functions.push(Box::new(Stmt::Function {
+            name: Token::Ident(MAIN_FN.to_string()),
+            params: vec!(),
+            return_type: None,
+            body: rest,
+            span: Span::default(),
+            entry: false,
+        }));
+    }
+    functions
+}
+
+/// Whether the statement is a top-level variable that becomes a global
+/// every function can use, which needs a constant initializer.
+pub fn is_global(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Var { initializer, .. } => optimize::constant(&optimize::fold_expr(initializer)).is_some(),
+        _ => false,
+    }
+}
+
+/// Name of the function run when none is chosen: the one marked with
+/// `wagmi`, otherwise `main` if there is one.
+pub fn entry_point(statements: &[Box<Stmt>]) -> Option<String> {
+    let functions = with_implicit_main(statements);
+    let marked = functions.iter().find_map(|stmt| match stmt.as_ref() {
+        Stmt::Function { name: Token::Ident(name), entry: true, .. } => Some(name.clone()),
+        _ => None,
+    });
+    let main = functions.iter().find_map(|stmt| match stmt.as_ref() {
+        Stmt::Function { name: Token::Ident(name), .. } if name == MAIN_FN => Some(name.clone()),
+        _ => None,
+    });
+    marked.or(main)
+}
+
+/// Evaluates the program with the tree-walking interpreter instead of
+/// compiling it, calling `func` with the given arguments.
+pub fn interpret(statements: &[Box<Stmt>], func: &str, args: &[f64]) -> eyre::Result<Option<f64>> {
+    let statements = with_implicit_main(statements);
+    resolve(&statements)?;
+    let statements = lift::lift_functions(&statements);
+    interp::Interpreter::new(&statements)
+        .call(func, args)
+        .wrap_err_with(|| format!("failed to interpret `{}`", func))
+}
+
+/// Reports every undefined variable and function before translation.
+pub fn resolve(statements: &[Box<Stmt>]) -> eyre::Result<()> {
+    resolver::resolve(statements).map_err(|errors| {
+        let messages = errors
+            .iter()
+            .map(|err| err.to_string())
+            .collect::<Vec<String>>();
+        eyre::eyre!("failed to resolve input:\n{}", messages.join("\n"))
+    })
+}
+
+type AddPass = for<'ctx> fn(&PassManager<FunctionValue<'ctx>>);
+
+/// Function passes run at an optimization level, in order, by name. Level
+/// 0 runs no passes, leaving the IR as it was generated.
+fn passes(opt_level: u8) -> Vec<(&'static str, AddPass)> {
+    let mut passes: Vec<(&'static str, AddPass)> = vec!();
+    if opt_level >= 1 {
+        passes.push(("mem2reg", |fpm| fpm.add_promote_memory_to_register_pass()));
+        passes.push(("instcombine", |fpm| fpm.add_instruction_combining_pass()));
+        passes.push(("simplifycfg", |fpm| fpm.add_cfg_simplification_pass()));
+    }
+    if opt_level >= 2 {
+        passes.push(("reassociate", |fpm| fpm.add_reassociate_pass()));
+        passes.push(("gvn", |fpm| fpm.add_gvn_pass()));
+        passes.push(("simplifycfg", |fpm| fpm.add_cfg_simplification_pass()));
+        passes.push(("basic-aa", |fpm| fpm.add_basic_alias_analysis_pass()));
+        passes.push(("instcombine", |fpm| fpm.add_instruction_combining_pass()));
+        passes.push(("reassociate", |fpm| fpm.add_reassociate_pass()));
+    }
+    if opt_level >= 3 {
+        passes.push(("licm", |fpm| fpm.add_licm_pass()));
+        passes.push(("loop-unroll", |fpm| fpm.add_loop_unroll_pass()));
+        passes.push(("adce", |fpm| fpm.add_aggressive_dce_pass()));
+        passes.push(("tailcallelim", |fpm| fpm.add_tail_call_elimination_pass()));
+        passes.push(("instcombine", |fpm| fpm.add_instruction_combining_pass()));
+        passes.push(("simplifycfg", |fpm| fpm.add_cfg_simplification_pass()));
+    }
+    passes
+}
+
+type AddModulePass = for<'ctx> fn(&PassManager<Module<'ctx>>);
+
+/// Module passes run at an optimization level once every function has
+/// been translated, in order, by name. Inlining needs the callees to have
+/// bodies, so it can't run with the function passes.
+fn module_passes(opt_level: u8) -> Vec<(&'static str, AddModulePass)> {
+    let mut passes: Vec<(&'static str, AddModulePass)> = vec!();
+    if opt_level >= 2 {
+        passes.push(("inline", |mpm| mpm.add_function_inlining_pass()));
+        passes.push(("instcombine", |mpm| mpm.add_instruction_combining_pass()));
+        passes.push(("simplifycfg", |mpm| mpm.add_cfg_simplification_pass()));
+        passes.push(("adce", |mpm| mpm.add_aggressive_dce_pass()));
+        passes.push(("globaldce", |mpm| mpm.add_global_dce_pass()));
+    }
+    passes
+}
+
+fn run_module_passes(module: &Module, opt_level: u8) {
+    let mpm = PassManager::create(());
+    for (_, add_pass) in module_passes(opt_level) {
+        add_pass(&mpm);
+    }
+    mpm.run_on(module);
+}
+
+/// Builds the function pass manager for an optimization level.
+fn create_pass_manager<'ctx>(module: &Module<'ctx>, opt_level: u8) -> PassManager<FunctionValue<'ctx>> {
+    // Pass manager for functions.
+    let fpm = PassManager::create(module);
+    for (_, add_pass) in passes(opt_level) {
+        add_pass(&fpm);
+    }
+    fpm.initialize();
+    fpm
+}
+
+/// Runs the passes of an optimization level one at a time over every
+/// function of a module translated at level 0, followed by the module
+/// passes. Returns the name of each pass with the IR of the module after
+/// it.
+pub fn dump_passes(module: &Module, opt_level: u8) -> Vec<(String, String)> {
+    let mut snapshots = vec!();
+    for (name, add_pass) in passes(opt_level) {
+        let fpm = PassManager::create(module);
+        add_pass(&fpm);
+        fpm.initialize();
+        for fun in module.get_functions() {
+            fpm.run_on(&fun);
+        }
+        fpm.finalize();
+        snapshots.push((name.to_string(), module.print_to_string().to_string()));
+    }
+    for (name, add_pass) in module_passes(opt_level) {
+        let mpm = PassManager::create(());
+        add_pass(&mpm);
+        mpm.run_on(module);
+        snapshots.push((name.to_string(), module.print_to_string().to_string()));
+    }
+    snapshots
+}
+
+fn optimization_level(opt_level: u8) -> OptimizationLevel {
+    match opt_level {
+        0 => OptimizationLevel::None,
+        1 => OptimizationLevel::Less,
+        2 => OptimizationLevel::Default,
+        _ => OptimizationLevel::Aggressive,
+    }
+}
+
+/// Settings for translating a program into LLVM IR.
+#[derive(Debug,Clone)]
+pub struct TranslateOptions<'a> {
+    /// Optimization level from 0, which runs no passes, to 3.
+    pub opt_level: u8,
+    /// Source file that debug info points back to, none is attached when
+    /// unset.
+    pub source: Option<&'a Path>,
+    /// Trap on overflow in integer `+`, `-` and `*` instead of wrapping.
+    pub checked_arith: bool,
+}
+
+impl<'a> Default for TranslateOptions<'a> {
+    fn default() -> Self {
+        Self { opt_level: DEFAULT_OPT_LEVEL, source: None, checked_arith: false }
+    }
+}
+
+/// Translates the statements into the given module, returning the names
+/// of the functions that should be exported from it.
+pub fn translate<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    statements: &[Box<Stmt>],
+    options: &TranslateOptions,
+) -> eyre::Result<Vec<String>> {
+    let statements = with_implicit_main(statements);
+    resolve(&statements)?;
+    let statements = lift::lift_functions(&statements);
+
+    let builder = context.create_builder();
+    let fpm = create_pass_manager(module, options.opt_level);
+    // Folding wraps on overflow, so it is skipped when overflow should
+    // trap at runtime.
+    let statements = match options.checked_arith {
+        true => statements,
+        false => optimize::fold_statements(&statements),
+    };
+
+    let debug_info = options.source.map(|source| DebugInfo::new(context, module, source));
+    let functions = Translator::translate(
+        context,
+        &builder,
+        &fpm,
+        module,
+        &statements,
+        debug_info.as_ref(),
+        options.checked_arith,
+    )?;
+    if let Some(debug_info) = &debug_info {
+        debug_info.finalize();
+    }
+    run_module_passes(module, options.opt_level);
+
+    // Functions with a leading underscore are private helpers, unless
+    // marked as the entry point.
+    let entry = entry_point(&statements);
+    let exports = functions
+        .iter()
+        .map(|fun| fun.get_name().to_string_lossy().to_string())
+        .filter(|name| !name.starts_with('_') || entry.as_ref() == Some(name))
+        .collect::<Vec<String>>();
+    Ok(exports)
+}
+
+/// Target compiled for unless another is chosen.
+pub const WASM_TRIPLE: &str = "wasm32-unknown-unknown";
+
+/// Target name standing for the machine the compiler runs on.
+pub const NATIVE_TARGET: &str = "native";
+
+pub const WASM_LINKERS: &[&str] = &["wasm-ld", "wasm-ld-15", "wasm-ld-16", "wasm-ld-14"];
+
+/// C compilers used to link native executables against the C runtime.
+pub const NATIVE_LINKERS: &[&str] = &["cc", "clang", "gcc"];
+
+/// Name a function of the program called `main` is given in native
+/// executables, where `main` belongs to the C runtime.
+pub const NATIVE_MAIN_FN: &str = "__mai_main";
+
+/// A fresh directory for the files passed between LLVM and the external
+/// tools, removed when dropped. Each compile gets its own, so concurrent
+/// compiles don't overwrite each other's files.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> eyre::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("mai-{}-{}", process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        fs::create_dir_all(&path).wrap_err_with(|| format!("failed to create {:?}", path))?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Returns the first of the candidate tools that can be run.
+pub fn find_tool(candidates: &[&str]) -> eyre::Result<String> {
+    for candidate in candidates.iter() {
+        let found = Command::new(candidate)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok();
+        if found {
+            return Ok(candidate.to_string());
+        }
+    }
+    eyre::bail!("could not find any of {:?}", candidates)
+}
+
+/// Emits an object file for the target, a triple or `native`, directly
+/// through LLVM's target machine.
+pub fn compile_to_object(module: &Module, path: &Path, opt_level: u8, target: &str) -> eyre::Result<()> {
+    let (triple, cpu, features) = match target {
+        NATIVE_TARGET => {
+            Target::initialize_native(&InitializationConfig::default()).map_err(|e| eyre::eyre!(e))?;
+            (
+                TargetMachine::get_default_triple(),
+                TargetMachine::get_host_cpu_name().to_string(),
+                TargetMachine::get_host_cpu_features().to_string(),
+            )
+        },
+        WASM_TRIPLE => {
+            Target::initialize_webassembly(&InitializationConfig::default());
+            (TargetTriple::create(target), "generic".to_string(), String::new())
+        },
+        _ => {
+            Target::initialize_all(&InitializationConfig::default());
+            (TargetTriple::create(target), "generic".to_string(), String::new())
+        },
+    };
+    let llvm_target = Target::from_triple(&triple).map_err(|e| eyre::eyre!(e.to_string()))?;
+    // Native executables are linked as position independent by default.
+    let reloc = match target {
+        WASM_TRIPLE => RelocMode::Default,
+        _ => RelocMode::PIC,
+    };
+    let Some(machine) = llvm_target.create_target_machine(
+        &triple,
+        cpu.as_str(),
+        features.as_str(),
+        optimization_level(opt_level),
+        reloc,
+        CodeModel::Default,
+    ) else {
+        eyre::bail!("could not create a target machine for {}", target);
+    };
+    module.set_triple(&triple);
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+
+    machine
+        .write_to_file(module, FileType::Object, path)
+        .map_err(|e| eyre::eyre!(e.to_string()))
+}
+
+/// Links an object file into a wasm binary, written next to the object,
+/// and returns its bytes.
+pub fn link_wasm(object_path: &Path, linker: &str, exports: &[String]) -> eyre::Result<Vec<u8>> {
+    // Execute wasm-ld to translate the object into web assembly.
+    let wasm_path = object_path.with_extension("wasm");
+    let mut command = Command::new(linker);
+    command.arg(object_path);
+    command.arg("-o");
+    command.arg(&wasm_path);
+    command.arg("--no-entry");
+    // Host functions such as print are left undefined and imported.
+    command.arg("--allow-undefined");
+    // Only export the functions the program defines. The memory is
+    // exported by wasm-ld on its own.
+    for name in exports.iter() {
+        command.arg(format!("--export={}", name));
+    }
+
+    let status = command
+        .execute()
+        .wrap_err_with(|| format!("failed to run linker `{}`", linker))?;
+    let Some(0) = status else {
+        eyre::bail!("could not link wasm binary, `{}` exited with {:?}", linker, status);
+    };
+
+    fs::read(&wasm_path).wrap_err_with(|| format!("failed to read {:?}", wasm_path))
+}
+
+/// Prepares a translated module to be linked into a native executable.
+/// The print host function is defined with `printf`, and a C `main` is
+/// added that calls the entry point and prints its result, if any.
+pub fn add_native_main<'ctx>(context: &'ctx Context, module: &Module<'ctx>, entry: &str) -> eyre::Result<()> {
+    let builder = context.create_builder();
+    let f64_type = context.f64_type();
+    let i32_type = context.i32_type();
+
+    if let Some(fun) = module.get_function(MAIN_FN) {
+        fun.as_global_value().set_name(NATIVE_MAIN_FN);
+    }
+    let entry = match entry {
+        MAIN_FN => NATIVE_MAIN_FN,
+        entry => entry,
+    };
+    let Some(entry_fn) = module.get_function(entry) else {
+        eyre::bail!("unknown function `{}`", entry);
+    };
+    if entry_fn.count_params() != 0 {
+        eyre::bail!("`{}` takes arguments, which native executables can't be given", entry);
+    }
+
+    let printf = module.get_function("printf").unwrap_or_else(|| {
+        let format_type = context.i8_type().ptr_type(AddressSpace::default());
+        module.add_function("printf", i32_type.fn_type(&[format_type.into()], true), None)
+    });
+    let print_fn = module.get_function(llvm_translator::PRINT_FN).unwrap_or_else(|| {
+        let fn_type = context.void_type().fn_type(&[f64_type.into()], false);
+        module.add_function(llvm_translator::PRINT_FN, fn_type, None)
+    });
+    if print_fn.count_basic_blocks() == 0 {
+        builder.position_at_end(context.append_basic_block(print_fn, "entry"));
+        let format = builder.build_global_string_ptr("%g\n", "format")?;
+        let value = print_fn.get_nth_param(0).unwrap();
+        builder.build_call(printf, &[format.as_pointer_value().into(), value.into()], "")?;
+        builder.build_return(None)?;
+    }
+
+    let main_fn = module.add_function(MAIN_FN, i32_type.fn_type(&[], false), None);
+    builder.position_at_end(context.append_basic_block(main_fn, "entry"));
+    let result = builder
+        .build_call(entry_fn, &[], "result")?
+        .try_as_basic_value()
+        .left();
+    if let Some(result) = result {
+        // Functions declared `-> int` return an i64.
+        let result = match result {
+            BasicValueEnum::IntValue(value) => builder.build_signed_int_to_float(value, f64_type, "tmpconv")?,
+            value => value.into_float_value(),
+        };
+        builder.build_call(print_fn, &[result.into()], "")?;
+    }
+    builder.build_return(Some(&i32_type.const_zero()))?;
+    Ok(())
+}
+
+/// Links an object file into a native executable at the output path.
+pub fn link_native(object_path: &Path, linker: &str, output: &Path) -> eyre::Result<()> {
+    let mut command = Command::new(linker);
+    command.arg(object_path);
+    command.arg("-o");
+    command.arg(output);
+    // Builtins such as `sqrt` may be lowered to libm calls.
+    command.arg("-lm");
+
+    let status = command
+        .execute()
+        .wrap_err_with(|| format!("failed to run linker `{}`", linker))?;
+    let Some(0) = status else {
+        eyre::bail!("could not link executable, `{}` exited with {:?}", linker, status);
+    };
+    Ok(())
+}
+
+pub fn wasm_to_wat(wasm: &[u8]) -> eyre::Result<String> {
+    let dir = TempDir::new()?;
+    let wasm_path = dir.path().join("main.wasm");
+    fs::write(&wasm_path, wasm).wrap_err_with(|| format!("failed to write {:?}", wasm_path))?;
+    let mut command = Command::new("wasm2wat");
+    command.arg(&wasm_path);
+
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+
+    let output = command.execute_output().wrap_err("failed to run wasm2wat")?;
+    let Some(0) = output.status.code() else {
+        eyre::bail!(
+            "could not show wat for compiled wasm:\n{}",
+            String::from_utf8_lossy(&output.stderr),
+        );
+    };
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "
+        fun square(x) { return x * x; }
+        fun sum(n) {
+            var total = 0;
+            for (var i = 0; i < n; i += 1) { total += square(i); }
+            return total;
+        }
+    ";
+
+    fn ir(source: &str, opt_level: u8) -> String {
+        let options = TranslateOptions { opt_level, ..TranslateOptions::default() };
+        Compiler::with_options(options).compile(source).unwrap()
+    }
+
+    // The definition of one function in the IR of a module.
+    fn function_ir<'a>(ir: &'a str, name: &str) -> &'a str {
+        let start = ir.find(&format!(" @{}(", name)).unwrap_or_else(|| panic!("no function `{}` in\n{}", name, ir));
+        let end = start + ir[start..].find("\n}").unwrap();
+        &ir[start..end]
+    }
+
+    #[test]
+    fn snapshots_follow_each_pass() {
+        let statements = parse_str(PROGRAM).unwrap();
+        let context = Context::create();
+        let module = context.create_module("tmp");
+        let options = TranslateOptions { opt_level: 0, ..TranslateOptions::default() };
+        translate(&context, &module, &statements, &options).unwrap();
+        let unoptimized = module.print_to_string().to_string();
+        assert!(function_ir(&unoptimized, "sum").contains("alloca"), "{}", unoptimized);
+
+        let snapshots = dump_passes(&module, 2);
+        let snapshot = |pass: &str| snapshots
+            .iter()
+            .position(|(name, _)| name == pass)
+            .unwrap_or_else(|| panic!("no snapshot after {}", pass));
+        // mem2reg comes first and leaves no stack slots behind.
+        let (name, ir) = &snapshots[0];
+        assert_eq!(name, "mem2reg");
+        assert!(!ir.contains("alloca"), "{}", ir);
+        // `square` is only inlined into `sum` by the inline pass.
+        let inline = snapshot("inline");
+        assert!(function_ir(&snapshots[inline - 1].1, "sum").contains("call double @square("), "{}", snapshots[inline - 1].1);
+        assert!(!function_ir(&snapshots[inline].1, "sum").contains("call"), "{}", snapshots[inline].1);
+
+        assert!(dump_passes(&module, 0).is_empty());
+    }
+
+    #[test]
+    fn calls_are_inlined_from_level_2() {
+        let source = include_str!("../tests/programs/inline.mai");
+        let optimized = ir(source, 2);
+        assert!(!function_ir(&optimized, "main").contains("call"), "{}", optimized);
+        // `add` is still exported.
+        function_ir(&optimized, "add");
+
+        let unoptimized = ir(source, 1);
+        assert!(function_ir(&unoptimized, "main").contains("call double @add("), "{}", unoptimized);
+    }
+
+    #[test]
+    fn blocks_add_no_basic_blocks() {
+        let ir = ir(include_str!("../tests/programs/blocks.mai"), 0);
+        // Labels start a line, instructions are indented.
+        let blocks = |name| function_ir(&ir, name)
+            .lines()
+            .filter(|line| !line.starts_with(' ') && line.split(';').next().unwrap().trim_end().ends_with(':'))
+            .count();
+        assert_eq!(blocks("flat"), 1);
+        assert_eq!(blocks("wrapped"), blocks("flat"));
+    }
+
+    #[test]
+    fn private_helpers_are_not_exported() {
+        let statements = parse_str("
+            fun _double(x) { return x * 2; }
+            fun main() { return _double(21); }
+        ").unwrap();
+        let context = Context::create();
+        let module = context.create_module("tmp");
+        let exports = translate(&context, &module, &statements, &TranslateOptions::default()).unwrap();
+        assert_eq!(exports, vec!("main"));
+    }
+
+    #[test]
+    fn level_2_promotes_memory_to_registers() {
+        let source = include_str!("../main.mai");
+        let unoptimized = ir(source, 0);
+        let raw = function_ir(&unoptimized, "safe_sub");
+        assert!(raw.contains("alloca") && raw.contains("store") && raw.contains("load"), "{}", unoptimized);
+
+        let optimized = ir(source, 2);
+        let promoted = function_ir(&optimized, "safe_sub");
+        assert!(!promoted.contains("alloca") && !promoted.contains("load"), "{}", optimized);
+    }
+
+    #[test]
+    fn wagmi_marks_the_entry_point() {
+        let statements = parse_str("fun main() { return 1; } wagmi _start() { return 2; }").unwrap();
+        assert_eq!(entry_point(&statements).as_deref(), Some("_start"));
+        // Marked, the leading underscore doesn't keep it private.
+        let context = Context::create();
+        let module = context.create_module("tmp");
+        let exports = translate(&context, &module, &statements, &TranslateOptions::default()).unwrap();
+        assert_eq!(exports, vec!("main", "_start"));
+
+        let statements = parse_str("fun helper() { return 1; } fun main() { return helper(); }").unwrap();
+        assert_eq!(entry_point(&statements).as_deref(), Some("main"));
+    }
+
+    #[test]
+    fn streaming_parse_matches_collected() {
+        for source in [PROGRAM, include_str!("../main.mai"), include_str!("../tests/programs/nested.mai")] {
+            let collected = parse(lex(source).unwrap()).unwrap();
+            let streamed = parse_streaming(source).unwrap();
+            assert_eq!(format!("{:?}", streamed), format!("{:?}", collected));
+        }
+        let err = parse_streaming("fun f() { return 1 @ 2; }").unwrap_err();
+        assert!(err.to_string().starts_with("failed to lex input"), "{}", err);
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::{HashMap,HashSet};
+
+use crate::ast::{walk_expr,walk_stmt,Expr,Param,Stmt,Visitor};
+use crate::token::{Span,Token};
+
+// Nested functions in scope, by name, with the name of their lifted
+// function and the variables it captures.
+type Locals = HashMap<String, (String, Vec<String>)>;
+
+/// Moves functions defined inside other functions to the top level, so
+/// later stages only deal with top-level functions.
+///
+/// The variables of the enclosing functions used by a nested function are
+/// captured by value: they become extra float parameters of the lifted
+/// function, and every call passes their values at the time of the call.
+/// Assigning to a captured variable only changes the copy. Lifted
+/// functions are named after their enclosing functions, as in `_outer_inner`,
+/// so they are not exported.
+pub fn lift_functions(statements: &[Box<Stmt>]) -> Vec<Box<Stmt>> {
+    let mut lifter = Lifter { lifted: vec!() };
+    let mut result = vec!();
+    for stmt in statements.iter() {
+        match stmt.as_ref() {
+            Stmt::Function { name, .. } => {
+                let prefix = format!("_{}", name_of(name));
+                result.push(Box::new(lifter.function(stmt, &prefix, &Locals::new())));
+            },
+            _ => result.push(stmt.clone()),
+        }
+    }
+    result.extend(lifter.lifted);
+    result
+}
+
+fn name_of(token: &Token) -> String {
+    match token {
+        Token::Ident(id) => id.clone(),
+        tok => tok.to_string(),
+    }
+}
+
+struct Lifter {
+    lifted: Vec<Box<Stmt>>,
+}
+
+impl Lifter {
+    fn function(&mut self, fun: &Stmt, prefix: &str, locals: &Locals) -> Stmt {
+        let Stmt::Function { name, params, return_type, body, span, entry } = fun else {
+            return fun.clone();
+        };
+        Stmt::Function {
+            name: name.clone(),
+            params: params.clone(),
+            return_type: *return_type,
+            body: self.block(body, prefix, locals.clone()),
+            span: *span,
+            entry: *entry,
+        }
+    }
+
+    fn block(&mut self, statements: &[Box<Stmt>], prefix: &str, mut locals: Locals) -> Vec<Box<Stmt>> {
+        let mut result = vec!();
+        for stmt in statements.iter() {
+            let Stmt::Function { name, params, return_type, body, span, .. } = stmt.as_ref() else {
+                result.push(Box::new(self.stmt(stmt, prefix, &locals)));
+                continue;
+            };
+            // Calls after the definition, including recursive ones, go to
+            // the lifted function.
+            let inner = name_of(name);
+            let lifted_name = format!("{}_{}", prefix, inner);
+            let captured = captures(params, body, &locals);
+            locals.insert(inner, (lifted_name.clone(), captured.clone()));
+
+            let mut params = params.clone();
+            params.extend(captured.iter().map(|name| Param { name: Token::Ident(name.clone()), ty: None }));
+            let fun = Stmt::Function {
+                name: Token::Ident(lifted_name.clone()),
+                params,
+                return_type: *return_type,
+                body: body.clone(),
+                span: *span,
+                entry: false,
+            };
+            let fun = self.function(&fun, &lifted_name, &locals);
+            self.lifted.push(Box::new(fun));
+        }
+        result
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, prefix: &str, locals: &Locals) -> Stmt {
+        match stmt {
+            Stmt::Block(statements) => Stmt::Block(self.block(statements, prefix, locals.clone())),
+            Stmt::Expr(expr) => Stmt::Expr(Box::new(rewrite_expr(expr, locals))),
+            Stmt::Print(expr) => Stmt::Print(Box::new(rewrite_expr(expr, locals))),
+            Stmt::Return { keyword, value, span } => Stmt::Return {
+                keyword: keyword.clone(),
+                value: value.as_ref().map(|value| Box::new(rewrite_expr(value, locals))),
+                span: *span,
+            },
+            // A function that is the whole branch of an `if` or a loop
+            // can't be called, it is lifted and leaves an empty block.
+            Stmt::Function { .. } => Stmt::Block(self.block(&[Box::new(stmt.clone())], prefix, locals.clone())),
+            Stmt::If { cond, then_branch, else_branch } => Stmt::If {
+                cond: Box::new(rewrite_expr(cond, locals)),
+                then_branch: Box::new(self.stmt(then_branch, prefix, locals)),
+                else_branch: else_branch.as_ref().map(|stmt| Box::new(self.stmt(stmt, prefix, locals))),
+            },
+            Stmt::While { condition, body, increment } => Stmt::While {
+                condition: Box::new(rewrite_expr(condition, locals)),
+                body: Box::new(self.stmt(body, prefix, locals)),
+                increment: increment.as_ref().map(|expr| Box::new(rewrite_expr(expr, locals))),
+            },
+            Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Comment { .. } => stmt.clone(),
+            Stmt::Var { name, initializer, span } => Stmt::Var {
+                name: name.clone(),
+                initializer: Box::new(rewrite_expr(initializer, locals)),
+                span: *span,
+            },
+        }
+    }
+}
+
+// Points calls of nested functions at their lifted function, passing the
+// captured variables after the arguments.
+fn rewrite_expr(expr: &Expr, locals: &Locals) -> Expr {
+    let rewrite = |expr: &Box<Expr>| Box::new(rewrite_expr(expr, locals));
+    match expr {
+        Expr::BinaryExpr { op, left, right } => Expr::BinaryExpr { op: op.clone(), left: rewrite(left), right: rewrite(right) },
+        Expr::UnaryExpr { op, right } => Expr::UnaryExpr { op: op.clone(), right: rewrite(right) },
+        Expr::Logical { op, left, right } => Expr::Logical { op: op.clone(), left: rewrite(left), right: rewrite(right) },
+        Expr::Ternary { cond, then, els } => Expr::Ternary { cond: rewrite(cond), then: rewrite(then), els: rewrite(els) },
+        Expr::Grouping { expr } => Expr::Grouping { expr: rewrite(expr) },
+        Expr::Assign { name, value, span } => Expr::Assign { name: name.clone(), value: rewrite(value), span: *span },
+        Expr::Index { array, index, span } => Expr::Index { array: rewrite(array), index: rewrite(index), span: *span },
+        Expr::SetIndex { array, index, value, span } => Expr::SetIndex {
+            array: rewrite(array),
+            index: rewrite(index),
+            value: rewrite(value),
+            span: *span,
+        },
+        Expr::Call { callee, paren, args } => {
+            let mut args = args.iter().map(rewrite).collect::<Vec<Box<Expr>>>();
+            let callee = match callee.as_ref() {
+                Expr::Variable { name, span } => match locals.get(name_of(name).as_str()) {
+                    Some((lifted_name, captured)) => {
+                        args.extend(captured.iter().map(|name| Box::new(Expr::Variable {
+                            name: Token::Ident(name.clone()),
+                            span: Span::default(),
+                        })));
+                        Box::new(Expr::Variable { name: Token::Ident(lifted_name.clone()), span: *span })
+                    },
+                    None => callee.clone(),
+                },
+                callee => Box::new(rewrite_expr(callee, locals)),
+            };
+            Expr::Call { callee, paren: paren.clone(), args }
+        },
+        Expr::Get { object, name } => Expr::Get { object: rewrite(object), name: name.clone() },
+        Expr::Literal { .. } | Expr::StringLiteral { .. } | Expr::Variable { .. } => expr.clone(),
+    }
+}
+
+// Variables a nested function uses without defining them, in the order
+// they are first used. Calls to other nested functions use the variables
+// those capture.
+fn captures(params: &[Param], body: &[Box<Stmt>], locals: &Locals) -> Vec<String> {
+    let mut free = FreeVariables {
+        scopes: vec!(params.iter().map(|param| name_of(&param.name)).collect()),
+        locals,
+        found: vec!(),
+    };
+    for stmt in body.iter() {
+        free.visit_stmt(stmt);
+    }
+    free.found
+}
+
+struct FreeVariables<'a> {
+    scopes: Vec<HashSet<String>>,
+    locals: &'a Locals,
+    found: Vec<String>,
+}
+
+impl<'a> FreeVariables<'a> {
+    fn use_variable(&mut self, name: &str) {
+        let bound = self.scopes.iter().any(|scope| scope.contains(name));
+        if !bound && !self.found.iter().any(|found| found == name) {
+            self.found.push(name.to_string());
+        }
+    }
+}
+
+impl<'a> Visitor for FreeVariables<'a> {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(_) => {
+                self.scopes.push(HashSet::new());
+                walk_stmt(self, stmt);
+                self.scopes.pop();
+            },
+            Stmt::Function { params, .. } => {
+                self.scopes.push(params.iter().map(|param| name_of(&param.name)).collect());
+                walk_stmt(self, stmt);
+                self.scopes.pop();
+            },
+            Stmt::Var { name, .. } => {
+                walk_stmt(self, stmt);
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(name_of(name));
+                }
+            },
+            stmt => walk_stmt(self, stmt),
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign { name, .. } => {
+                walk_expr(self, expr);
+                self.use_variable(name_of(name).as_str());
+            },
+            Expr::Variable { name, .. } => self.use_variable(name_of(name).as_str()),
+            Expr::Call { callee, args, .. } => {
+                match callee.as_ref() {
+                    Expr::Variable { name, .. } => {
+                        if let Some((_, captured)) = self.locals.get(name_of(name).as_str()) {
+                            for name in captured.iter() {
+                                self.use_variable(name);
+                            }
+                        }
+                    },
+                    callee => self.visit_expr(callee),
+                }
+                for arg in args.iter() {
+                    self.visit_expr(arg);
+                }
+            },
+            expr => walk_expr(self, expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::Interpreter;
+    use crate::parse_str;
+
+    const OUTER: &str = "
+        fun outer(x, factor) {
+            fun scale(n) {
+                return n * factor;
+            }
+            return scale(x) + 1;
+        }
+    ";
+
+    #[test]
+    fn captured_parameter_is_passed() {
+        let lifted = lift_functions(&parse_str(OUTER).unwrap());
+        let names = lifted
+            .iter()
+            .filter_map(|stmt| match stmt.as_ref() {
+                Stmt::Function { name, params, .. } => {
+                    Some((name_of(name), params.iter().map(|param| name_of(&param.name)).collect::<Vec<String>>()))
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!(
+            ("outer".to_string(), vec!("x".to_string(), "factor".to_string())),
+            ("_outer_scale".to_string(), vec!("n".to_string(), "factor".to_string())),
+        ));
+
+        let interp = Interpreter::new(&lifted);
+        assert_eq!(interp.call("outer", &[3.0, 4.0]).unwrap(), Some(13.0));
+        assert_eq!(interp.call("outer", &[2.5, -2.0]).unwrap(), Some(-4.0));
+    }
+}
@@ -1,27 +1,221 @@
-use std::collections::HashMap;
-use inkwell::module::Module;
-use inkwell::builder::Builder;
+use std::collections::{BTreeMap,HashSet};
+use std::path::Path;
+use inkwell::module::{FlagBehavior,Linkage,Module};
+use inkwell::builder::{Builder,BuilderError};
 use inkwell::context::Context;
 use inkwell::passes::PassManager;
-use inkwell::types::BasicMetadataTypeEnum;
+use inkwell::types::{BasicMetadataTypeEnum,BasicType,BasicTypeEnum};
 use inkwell::basic_block::BasicBlock;
-use inkwell::values::{BasicValue,FloatValue,FunctionValue,PointerValue};
-use inkwell::FloatPredicate;
+use inkwell::debug_info::{
+    AsDIScope,DICompileUnit,DIFlags,DIFlagsConstants,DIType,DWARFEmissionKind,DWARFSourceLanguage,DebugInfoBuilder,
+};
+use inkwell::values::{
+    BasicMetadataValueEnum,BasicValue,BasicValueEnum,FloatValue,FunctionValue,IntValue,PointerValue,
+};
+use inkwell::{FloatPredicate,IntPredicate};
+use thiserror::Error;
 
+use crate::optimize::{constant,fold_expr,int_variables,Constant};
 use crate::parser::*;
-use crate::token::Token;
+use crate::token::{Span,Token};
+
+/// Host function imported by compiled modules to print a value.
+pub const PRINT_FN: &str = "print_f64";
+
+/// Functions that can be called without being defined in the program.
+pub const BUILTINS: &[&str] = &["abs", "sqrt", "floor", "ceil", "min", "max", "pow", "safe_sub", "assert", "array"];
+
+/// Number of f64 slots in the heap arrays are allocated from, 1 MiB.
+pub const HEAP_SLOTS: u64 = 1 << 17;
+const HEAP: &str = "__mai_heap";
+const HEAP_NEXT: &str = "__mai_heap_next";
+
+/// Debug info attached to the translated functions, so the wasm can be
+/// mapped back to the `.mai` source.
+pub struct DebugInfo<'ctx> {
+    pub builder: DebugInfoBuilder<'ctx>,
+    pub unit: DICompileUnit<'ctx>,
+    int_type: DIType<'ctx>,
+    float_type: DIType<'ctx>,
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    pub fn new(context: &'ctx Context, module: &Module<'ctx>, source: &Path) -> Self {
+        module.add_basic_value_flag(
+            "Debug Info Version",
+            FlagBehavior::Warning,
+            context.i32_type().const_int(3, false),
+        );
+        let filename = source.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let directory = source.parent().map(|dir| dir.to_string_lossy()).unwrap_or_default();
+        let (builder, unit) = module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &filename,
+            &directory,
+            "mai",
+            false,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+        // DW_ATE_signed and DW_ATE_float
+        let int_type = builder
+            .create_basic_type("i64", 64, 0x05, DIFlags::PUBLIC)
+            .unwrap()
+            .as_type();
+        let float_type = builder
+            .create_basic_type("f64", 64, 0x04, DIFlags::PUBLIC)
+            .unwrap()
+            .as_type();
+        Self { builder, unit, int_type, float_type }
+    }
+
+    /// Resolves the debug info of the functions translated so far. Each
+    /// function needs it before it can be verified, and the module once
+    /// every function has been translated.
+    pub fn finalize(&self) {
+        self.builder.finalize();
+    }
+}
+
+/// Whether a function body produces a value: when some `return` has one,
+/// or when it ends in an expression or conditional whose value falls off
+/// the end. An empty body returns 0.0, as does falling off the end of any
+/// other body that produces a value. Other functions are void.
+pub fn returns_value(body: &[Box<Stmt>]) -> bool {
+    body.is_empty() || ends_in_value(body) || body.iter().any(|stmt| has_value_return(stmt))
+}
+
+/// Whether the value of the last statement is what falling off the end of
+/// the statements produces.
+pub fn ends_in_value(statements: &[Box<Stmt>]) -> bool {
+    match statements.last().map(|stmt| stmt.as_ref()) {
+        Some(Stmt::Expr(_) | Stmt::If { .. }) => true,
+        Some(Stmt::Block(statements)) => ends_in_value(statements),
+        _ => false,
+    }
+}
+
+fn has_value_return(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return { value, .. } => value.is_some(),
+        Stmt::Block(statements) => statements.iter().any(|stmt| has_value_return(stmt)),
+        Stmt::If { then_branch, else_branch, .. } => {
+            has_value_return(then_branch) || else_branch.as_ref().is_some_and(|stmt| has_value_return(stmt))
+        },
+        Stmt::While { body, .. } => has_value_return(body),
+        _ => false,
+    }
+}
+
+#[derive(Debug,Error)]
+pub enum TranslateError {
+    #[error("unknown variable `{0}`")]
+    UnknownVariable(String),
+    #[error("unknown function `{0}`")]
+    UnknownFunction(String),
+    #[error("unsupported operation `{0}`")]
+    UnsupportedOp(Token),
+    #[error("operation `{0}` requires integer operands")]
+    IntegerOnlyOp(Token),
+    #[error("`{name}` expects {expected} arguments, found {found}")]
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("expected identifier, found `{0}`")]
+    ExpectedIdentifier(Token),
+    #[error("only named functions can be called")]
+    InvalidCallee,
+    #[error("call to `{0}` produced no value, it can only be used as a statement")]
+    VoidCall(String),
+    #[error("`{name}` is declared to return `{ty}` but never produces a value")]
+    MissingReturnValue {
+        name: String,
+        ty: Type,
+    },
+    #[error("member access `.{0}` is not supported yet")]
+    MemberAccess(Token),
+    #[error("global `{0}` must be initialized with a constant")]
+    NonConstantGlobal(String),
+    #[error("generated function `{0}` failed verification")]
+    InvalidFunction(String),
+    #[error("could not build instruction: {0}")]
+    Builder(#[from] BuilderError),
+    #[error("cannot translate statement {0:?}")]
+    UnhandledStmt(Stmt),
+    #[error("cannot translate expression {0:?}")]
+    UnhandledExpr(Expr),
+}
+
+pub type TranslateResult<T> = Result<T, TranslateError>;
+
+fn identifier(token: &Token) -> TranslateResult<String> {
+    match token {
+        Token::Ident(id) => Ok(id.clone()),
+        tok => Err(TranslateError::ExpectedIdentifier(tok.clone())),
+    }
+}
+
+/// A translated value. Integers stay `i64` and comparison results stay
+/// `i1` until they are mixed with floats, passed to a function or
+/// returned, where they become `f64`.
+#[derive(Debug,Clone,Copy)]
+pub enum Value<'ctx> {
+    Float(FloatValue<'ctx>),
+    Int(IntValue<'ctx>),
+    Bool(IntValue<'ctx>),
+}
+
+impl<'ctx> Value<'ctx> {
+    pub fn as_basic_value(&self) -> BasicValueEnum<'ctx> {
+        match self {
+            Value::Float(v) => v.as_basic_value_enum(),
+            Value::Int(v) | Value::Bool(v) => v.as_basic_value_enum(),
+        }
+    }
+
+    fn from_basic_value(value: BasicValueEnum<'ctx>) -> Self {
+        match value {
+            BasicValueEnum::IntValue(v) if v.get_type().get_bit_width() == 1 => Value::Bool(v),
+            BasicValueEnum::IntValue(v) => Value::Int(v),
+            v => Value::Float(v.into_float_value()),
+        }
+    }
+}
 
 pub struct Translator<'a, 'ctx> {
     pub context: &'ctx Context,
     pub builder: &'a Builder<'ctx>,
     pub fpm: &'a PassManager<FunctionValue<'ctx>>,
     pub module: &'a Module<'ctx>,
-    pub variables: HashMap<String, PointerValue<'ctx>>,
+    /// Variables of the current function, one map per nested block.
+    pub variables: Vec<BTreeMap<String, (PointerValue<'ctx>, BasicTypeEnum<'ctx>)>>,
+    /// Continue and break targets of the loops being translated, innermost
+    /// last.
+    pub loops: Vec<(BasicBlock<'ctx>, BasicBlock<'ctx>)>,
+    /// Number of parameters of every function declared so far.
+    pub arities: BTreeMap<String, usize>,
+    /// Top-level variables, which every function can use.
+    pub globals: BTreeMap<String, (PointerValue<'ctx>, BasicTypeEnum<'ctx>)>,
+    /// Variables of the current function that only ever hold integers.
+    pub int_variables: HashSet<String>,
     pub fn_value_opt: Option<FunctionValue<'ctx>>,
+    pub debug_info: Option<&'a DebugInfo<'ctx>>,
+    /// Trap on integer overflow instead of wrapping.
+    pub checked_arith: bool,
 }
 
 impl<'a, 'ctx> Translator<'a, 'ctx> {
-    fn create_stack_alloc(&self, name: &str) -> PointerValue<'ctx> {
+    fn create_stack_alloc(&self, name: &str, ty: BasicTypeEnum<'ctx>) -> TranslateResult<PointerValue<'ctx>> {
         let builder = self.context.create_builder();
 
         let entry = self.fn_value_opt.unwrap().get_first_basic_block().unwrap();
@@ -31,61 +225,254 @@ impl<'a, 'ctx> Translator<'a, 'ctx> {
             None => builder.position_at_end(entry),
         }
 
-        builder.build_alloca(self.context.f64_type(), name)
+        Ok(builder.build_alloca(ty, name)?)
     }
 
-    pub fn translate_function_sig(&self, fun: &Stmt) -> Result<FunctionValue<'ctx>, &'static str> {
-        let Stmt::Function { name: Token::Ident(fn_name), params, body: _ } = fun else {
-            panic!("Not a function");
+    fn to_float(&self, value: Value<'ctx>) -> TranslateResult<FloatValue<'ctx>> {
+        let value = match value {
+            Value::Float(v) => v,
+            Value::Int(v) => self
+                .builder
+                .build_signed_int_to_float(v, self.context.f64_type(), "tmpconv")?,
+            // Booleans become 1.0 and 0.0.
+            Value::Bool(v) => self
+                .builder
+                .build_unsigned_int_to_float(v, self.context.f64_type(), "tmpbool")?,
         };
-        let return_type = self.context.f64_type();
-        let arg_types = std::iter::repeat(return_type)
-            .take(params.len())
+        Ok(value)
+    }
+
+    // Converts a value into the type of the slot it is stored in.
+    fn coerce(&self, value: Value<'ctx>, ty: BasicTypeEnum<'ctx>) -> TranslateResult<BasicValueEnum<'ctx>> {
+        let value = match (value, ty) {
+            (Value::Float(v), BasicTypeEnum::IntType(int_type)) => self
+                .builder
+                .build_float_to_signed_int(v, int_type, "tmpconv")?
+                .as_basic_value_enum(),
+            (Value::Int(_), BasicTypeEnum::IntType(_)) => value.as_basic_value(),
+            (Value::Bool(v), BasicTypeEnum::IntType(int_type)) => self
+                .builder
+                .build_int_z_extend(v, int_type, "tmpconv")?
+                .as_basic_value_enum(),
+            (value, _) => self.to_float(value)?.as_basic_value_enum(),
+        };
+        Ok(value)
+    }
+
+    // Compares a value against zero, producing an i1 that is set when
+    // the value is truthy.
+    fn truthy(&self, value: Value<'ctx>, name: &str) -> TranslateResult<IntValue<'ctx>> {
+        let cmp = match value {
+            Value::Float(v) => {
+                let zero_const = self.context.f64_type().const_float(0.0);
+                self.builder.build_float_compare(FloatPredicate::ONE, v, zero_const, name)?
+            },
+            Value::Int(v) => {
+                let zero_const = self.context.i64_type().const_zero();
+                self.builder.build_int_compare(IntPredicate::NE, v, zero_const, name)?
+            },
+            Value::Bool(v) => v,
+        };
+        Ok(cmp)
+    }
+
+    fn basic_type(&self, ty: Type) -> BasicTypeEnum<'ctx> {
+        match ty {
+            Type::Int => self.context.i64_type().into(),
+            Type::Float => self.context.f64_type().into(),
+        }
+    }
+
+    fn is_void(&self) -> bool {
+        match self.fn_value_opt {
+            Some(fun) => fun.get_type().get_return_type().is_none(),
+            None => false,
+        }
+    }
+
+    // Returns the value converted to the return type of the current
+    // function.
+    fn build_value_return(&self, value: Value<'ctx>) -> TranslateResult<()> {
+        let ty = self.fn_value_opt
+            .and_then(|fun| fun.get_type().get_return_type())
+            .unwrap_or_else(|| self.context.f64_type().into());
+        let value = self.coerce(value, ty)?;
+        self.builder.build_return(Some(&value))?;
+        Ok(())
+    }
+
+    // Whether the current block already ends in a terminator such as a
+    // return, after which nothing more can be emitted into it.
+    fn is_terminated(&self) -> bool {
+        match self.builder.get_insert_block() {
+            Some(block) => block.get_terminator().is_some(),
+            None => false,
+        }
+    }
+
+    // Declares the host print function on first use. It is left undefined
+    // in the module so the linker turns it into a wasm import.
+    fn print_function(&self) -> FunctionValue<'ctx> {
+        if let Some(fun) = self.module.get_function(PRINT_FN) {
+            return fun;
+        }
+        let fn_type = self
+            .context
+            .void_type()
+            .fn_type(&[self.context.f64_type().into()], false);
+        self.module.add_function(PRINT_FN, fn_type, None)
+    }
+
+    // Declares an LLVM intrinsic taking and returning f64s on first use.
+    fn float_intrinsic(&self, name: &str, arity: usize) -> FunctionValue<'ctx> {
+        if let Some(fun) = self.module.get_function(name) {
+            return fun;
+        }
+        let f64_type = self.context.f64_type();
+        let arg_types = std::iter::repeat_n(f64_type, arity)
             .map(|f| f.into())
             .collect::<Vec<BasicMetadataTypeEnum>>();
+        let fn_type = f64_type.fn_type(arg_types.as_slice(), false);
+        self.module.add_function(name, fn_type, None)
+    }
+
+    fn call_float_intrinsic(&self, name: &str, args: &[FloatValue<'ctx>]) -> TranslateResult<FloatValue<'ctx>> {
+        let fun = self.float_intrinsic(name, args.len());
+        let argsv: Vec<BasicMetadataValueEnum> = args
+            .iter()
+            .map(|&val| val.into())
+            .collect();
+        let value = self.builder
+            .build_call(fun, argsv.as_slice(), "tmpintr")?
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_float_value();
+        Ok(value)
+    }
+
+    // Lowers calls to builtin functions, returning None when the name is
+    // not a builtin. Functions defined in the program take precedence.
+    fn translate_builtin(&self, name: &str, args: &[Box<Expr>]) -> TranslateResult<Option<Value<'ctx>>> {
+        let arity = match name {
+            "abs" | "sqrt" | "floor" | "ceil" | "assert" | "array" => 1,
+            "min" | "max" | "pow" | "safe_sub" => 2,
+            _ => return Ok(None),
+        };
+        if args.len() != arity {
+            return Err(TranslateError::ArityMismatch {
+                name: name.to_string(),
+                expected: arity,
+                found: args.len(),
+            });
+        }
+        let mut compiled_args = Vec::with_capacity(args.len());
+        for arg in args.iter() {
+            let arg = self.translate_expr(arg)?;
+            compiled_args.push(self.to_float(arg)?);
+        }
+
+        let intrinsic = match name {
+            "abs" => "llvm.fabs.f64",
+            "sqrt" => "llvm.sqrt.f64",
+            "floor" => "llvm.floor.f64",
+            "ceil" => "llvm.ceil.f64",
+            "min" => "llvm.minnum.f64",
+            "max" => "llvm.maxnum.f64",
+            "pow" => "llvm.pow.f64",
+            // Traps when the value is falsy, otherwise evaluates to 0.0.
+            "assert" => {
+                let holds = self.truthy(Value::Float(compiled_args[0]), "tmpassert")?;
+                let failed = self.builder.build_not(holds, "tmpfailed")?;
+                self.build_trap(failed, "assertfail", "assertok")?;
+                return Ok(Some(Value::Float(self.context.f64_type().const_zero())));
+            },
+            "array" => return Ok(Some(Value::Float(self.build_array(compiled_args[0])?))),
+            // Subtraction clamped at zero, `max(a - b, 0)`.
+            "safe_sub" => {
+                let diff = self.builder.build_float_sub(compiled_args[0], compiled_args[1], "tmpsub")?;
+                let zero_const = self.context.f64_type().const_zero();
+                let value = self.call_float_intrinsic("llvm.maxnum.f64", &[diff, zero_const])?;
+                return Ok(Some(Value::Float(value)));
+            },
+            _ => unreachable!(),
+        };
+        Ok(Some(Value::Float(self.call_float_intrinsic(intrinsic, &compiled_args)?)))
+    }
+
+    pub fn translate_function_sig(&mut self, fun: &Stmt) -> TranslateResult<FunctionValue<'ctx>> {
+        let Stmt::Function { name, params, return_type, body, .. } = fun else {
+            return Err(TranslateError::UnhandledStmt(fun.clone()));
+        };
+        let fn_name = identifier(name)?;
+        let arg_types = params
+            .iter()
+            .map(|param| self.basic_type(param.ty()).into())
+            .collect::<Vec<BasicMetadataTypeEnum>>();
         let args = arg_types.as_slice();
 
-        let fn_type = self.context.f64_type().fn_type(args, false); // No var args.
+        // No var args.
+        let fn_type = match (return_type, returns_value(body)) {
+            (Some(ty), true) => self.basic_type(*ty).fn_type(args, false),
+            (Some(ty), false) => return Err(TranslateError::MissingReturnValue { name: fn_name, ty: *ty }),
+            (None, true) => self.context.f64_type().fn_type(args, false),
+            (None, false) => self.context.void_type().fn_type(args, false),
+        };
         let fn_val = self.module.add_function(fn_name.as_str(), fn_type, None);
+        self.arities.insert(fn_name, params.len());
 
         for (i, arg) in fn_val.get_param_iter().enumerate() {
-            let param = params[i].clone();
-            let Token::Ident(arg_ident) = param else {
-                panic!("Not an arg ident");
-            };
-            arg.into_float_value().set_name(arg_ident.as_str());
+            let arg_ident = identifier(&params[i].name)?;
+            arg.set_name(arg_ident.as_str());
         }
 
         Ok(fn_val)
     }
 
-    pub fn translate_function(&mut self, fun: &Stmt) -> Result<FunctionValue<'ctx>, &'static str> {
-        let Stmt::Function { name: _, params, body } = fun else {
-            panic!("Not a function");
+    pub fn translate_function(&mut self, fun: &Stmt) -> TranslateResult<FunctionValue<'ctx>> {
+        let Stmt::Function { name, params, body, span, .. } = fun else {
+            return Err(TranslateError::UnhandledStmt(fun.clone()));
+        };
+        let fn_name = identifier(name)?;
+        // Signatures are normally declared up front by `translate`.
+        let sig = match self.module.get_function(fn_name.as_str()) {
+            Some(sig) => sig,
+            None => self.translate_function_sig(fun)?,
         };
-        let sig = self.translate_function_sig(fun)?;
-        if body.is_empty() {
-            return Ok(sig);
-        }
         let entry = self.context.append_basic_block(sig, "entry");
         self.builder.position_at_end(entry);
         self.fn_value_opt = Some(sig);
-        self.variables.reserve(params.len());
+        if let Some(debug_info) = self.debug_info {
+            self.attach_debug_info(debug_info, sig, fn_name.as_str(), *span);
+        }
+        self.variables = vec!(BTreeMap::new());
+        self.loops.clear();
+        self.int_variables = int_variables(params, body);
 
         for (i, arg) in sig.get_param_iter().enumerate() {
-            let param = params[i].clone();
-            let Token::Ident(arg_ident) = param else {
-                panic!("Not an arg ident");
-            };
-            let alloca = self.create_stack_alloc(arg_ident.as_str());
-            self.builder.build_store(alloca, arg);
-            self.variables.insert(arg_ident, alloca);
+            let arg_ident = identifier(&params[i].name)?;
+            let ty = self.basic_type(params[i].ty());
+            let alloca = self.create_stack_alloc(arg_ident.as_str(), ty)?;
+            self.builder.build_store(alloca, arg)?;
+            self.declare_variable(arg_ident, alloca, ty);
         }
 
-        let body = self.translate_stmt(body.first().unwrap())?;
-
-        self.builder.build_return(Some(&body));
+        // Falling off the end returns the value of a trailing expression
+        // or conditional, and 0.0 after anything else such as a loop.
+        let value = self.translate_block(body)?;
+        let value = match ends_in_value(body) {
+            true => value,
+            false => Value::Float(self.context.f64_type().const_zero()),
+        };
+        if !self.is_terminated() && self.is_void() {
+            self.builder.build_return(None)?;
+        } else if !self.is_terminated() {
+            self.build_value_return(value)?;
+        }
 
+        if let Some(debug_info) = self.debug_info {
+            debug_info.finalize();
+        }
         if sig.verify(true) {
             self.fpm.run_on(&sig);
             return Ok(sig);
@@ -94,96 +481,519 @@ impl<'a, 'ctx> Translator<'a, 'ctx> {
             sig.delete();
         }
 
-        Err("Invalid generated function")
+        Err(TranslateError::InvalidFunction(fn_name))
+    }
+
+    // Describes the function and points the instructions built before its
+    // first statement at the line of its definition.
+    fn attach_debug_info(
+        &self,
+        debug_info: &DebugInfo<'ctx>,
+        fun: FunctionValue<'ctx>,
+        name: &str,
+        span: Span,
+    ) {
+        let file = debug_info.unit.get_file();
+        let di_type = |is_int: bool| match is_int {
+            true => debug_info.int_type,
+            false => debug_info.float_type,
+        };
+        let params = fun
+            .get_param_iter()
+            .map(|param| di_type(param.is_int_value()))
+            .collect::<Vec<DIType>>();
+        let ret = fun.get_type().get_return_type().map(|ty| di_type(ty.is_int_type()));
+        let ty = debug_info.builder.create_subroutine_type(file, ret, &params, DIFlags::PUBLIC);
+        let line = span.line as u32;
+        let subprogram = debug_info.builder.create_function(
+            debug_info.unit.as_debug_info_scope(),
+            name,
+            None,
+            file,
+            line,
+            ty,
+            false,
+            true,
+            line,
+            DIFlags::PUBLIC,
+            false,
+        );
+        fun.set_subprogram(subprogram);
+        self.set_debug_location(span);
+    }
+
+    // Points the instructions built from here on at the span, within the
+    // function being translated. Does nothing without debug info.
+    fn set_debug_location(&self, span: Span) {
+        let (Some(debug_info), Some(subprogram)) = (
+            self.debug_info,
+            self.fn_value_opt.and_then(|fun| fun.get_subprogram()),
+        ) else {
+            return;
+        };
+        let location = debug_info.builder.create_debug_location(
+            self.context,
+            span.line as u32,
+            span.col as u32,
+            subprogram.as_debug_info_scope(),
+            None,
+        );
+        self.builder.set_current_debug_location(location);
+    }
+
+    // Calls a function of the program or a builtin, returning None for
+    // void functions.
+    fn translate_call(&self, fn_name: &str, args: &[Box<Expr>]) -> TranslateResult<Option<Value<'ctx>>> {
+        let (Some(fun), Some(&arity)) = (
+            self.module.get_function(fn_name),
+            self.arities.get(fn_name),
+        ) else {
+            return match self.translate_builtin(fn_name, args)? {
+                Some(value) => Ok(Some(value)),
+                None => Err(TranslateError::UnknownFunction(fn_name.to_string())),
+            };
+        };
+        if arity != args.len() {
+            return Err(TranslateError::ArityMismatch {
+                name: fn_name.to_string(),
+                expected: arity,
+                found: args.len(),
+            });
+        }
+
+        // Arguments are converted to the type of their parameter.
+        let mut compiled_args = Vec::with_capacity(args.len());
+        for (arg, param) in args.iter().zip(fun.get_param_iter()) {
+            let arg = self.translate_expr(arg)?;
+            compiled_args.push(self.coerce(arg, param.get_type())?);
+        }
+        let argsv: Vec<BasicMetadataValueEnum> = compiled_args
+            .iter()
+            .map(|&val| val.into())
+            .collect();
+
+        let value = self.builder
+            .build_call(fun, argsv.as_slice(), "tmpcall")?
+            .try_as_basic_value()
+            .left();
+        Ok(value.map(Value::from_basic_value))
+    }
+
+    fn declare_variable(&mut self, name: String, ptr: PointerValue<'ctx>, ty: BasicTypeEnum<'ctx>) {
+        if let Some(scope) = self.variables.last_mut() {
+            scope.insert(name, (ptr, ty));
+        }
+    }
+
+    // Finds the innermost variable with the given name, falling back to
+    // the globals.
+    fn lookup_variable(&self, name: &str) -> Option<(PointerValue<'ctx>, BasicTypeEnum<'ctx>)> {
+        self.variables
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+            .or_else(|| self.globals.get(name).copied())
     }
 
-    fn translate_stmt(&mut self, stmt: &Box<Stmt>) -> Result<FloatValue<'ctx>, &'static str> {
+    // Adds a top-level variable to the module as a global holding the
+    // constant value of its initializer.
+    fn translate_global(&mut self, name: &Token, initializer: &Expr) -> TranslateResult<()> {
+        let id = identifier(name)?;
+        let Some(value) = constant(&fold_expr(initializer)) else {
+            return Err(TranslateError::NonConstantGlobal(id));
+        };
+        let (ty, init): (BasicTypeEnum, BasicValueEnum) = match value {
+            Constant::Int(i) => {
+                let int_type = self.context.i64_type();
+                (int_type.into(), int_type.const_int(i as u64, true).into())
+            },
+            Constant::Float(f) => {
+                let float_type = self.context.f64_type();
+                (float_type.into(), float_type.const_float(f).into())
+            },
+        };
+        let global = self.module.add_global(ty, None, id.as_str());
+        global.set_initializer(&init);
+        global.set_constant(true);
+        global.set_linkage(Linkage::Internal);
+        self.globals.insert(id, (global.as_pointer_value(), ty));
+        Ok(())
+    }
+
+    fn translate_stmt(&mut self, stmt: &Box<Stmt>) -> TranslateResult<Value<'ctx>> {
+        if let Some(span) = stmt.span() {
+            self.set_debug_location(span);
+        }
         match stmt.as_ref() {
-            Stmt::Expr(expr) => self.translate_expr(expr),
+            Stmt::Expr(expr) => {
+                // Calls to void functions can only stand as statements.
+                if let Expr::Call { callee, args, .. } = expr.as_ref() {
+                    if let Expr::Variable { name: Token::Ident(fn_name), .. } = callee.as_ref() {
+                        let value = self.translate_call(fn_name, args)?;
+                        return Ok(value.unwrap_or(Value::Float(self.context.f64_type().const_zero())));
+                    }
+                }
+                self.translate_expr(expr)
+            },
+            Stmt::Print(expr) => {
+                let value = self.translate_expr(expr)?;
+                let print_fn = self.print_function();
+                self.builder.build_call(print_fn, &[self.to_float(value)?.into()], "")?;
+                Ok(value)
+            },
             Stmt::If { 
                 cond, 
                 then_branch, 
                 else_branch,
             } => self.translate_conditional(cond, then_branch, else_branch),
+            Stmt::While { condition, body, increment } => self.translate_while(condition, body, increment),
+            Stmt::Break { .. } | Stmt::Continue { .. } => {
+                let Some((continue_bb, break_bb)) = self.loops.last().copied() else {
+                    return Err(TranslateError::UnhandledStmt(stmt.as_ref().clone()));
+                };
+                let target = match stmt.as_ref() {
+                    Stmt::Break { .. } => break_bb,
+                    _ => continue_bb,
+                };
+                self.builder.build_unconditional_branch(target)?;
+                Ok(Value::Float(self.context.f64_type().const_zero()))
+            },
             Stmt::Block(statements) => {
-                return self.translate_stmt(statements.first().unwrap());
-            }
-            Stmt::Return { keyword: _, value } => {
-                if value.is_some() {
-                    let value = value.as_ref().unwrap();
-                    return self.translate_expr(value);
-                }
-                return Ok(self.context.f64_type().const_zero());
+                // Declarations inside the block shadow outer ones and go
+                // out of scope at its end. Its statements go into the
+                // current basic block, only `if`, loops and jumps split it.
+                self.variables.push(BTreeMap::new());
+                let value = self.translate_block(statements);
+                self.variables.pop();
+                value
+            },
+            Stmt::Return { value, .. } => {
+                let value = match value {
+                    Some(value) => self.translate_expr(value)?,
+                    None => Value::Float(self.context.f64_type().const_zero()),
+                };
+                match self.is_void() {
+                    true => {
+                        self.builder.build_return(None)?;
+                    },
+                    false => self.build_value_return(value)?,
+                };
+                Ok(value)
             },
-            item => panic!("Could not handle value: {:?}", item)
+            Stmt::Var { name, initializer, .. } => {
+                let var_name = identifier(name)?;
+                // The variable takes the type of its initializer, unless it
+                // is assigned something other than an integer later on.
+                // Booleans are stored as floats.
+                let value = match self.translate_expr(initializer)? {
+                    Value::Int(v) if self.int_variables.contains(var_name.as_str()) => Value::Int(v),
+                    value => Value::Float(self.to_float(value)?),
+                };
+                let ty = value.as_basic_value().get_type();
+                let alloca = self.create_stack_alloc(var_name.as_str(), ty)?;
+                self.builder.build_store(alloca, value.as_basic_value())?;
+                self.declare_variable(var_name, alloca, ty);
+                Ok(value)
+            },
+            item => Err(TranslateError::UnhandledStmt(item.clone())),
         }
     }
 
+    fn translate_block(&mut self, statements: &[Box<Stmt>]) -> TranslateResult<Value<'ctx>> {
+        let mut value = Value::Float(self.context.f64_type().const_zero());
+        for stmt in statements.iter() {
+            value = self.translate_stmt(stmt)?;
+            // Nothing after a return in the same block is reachable.
+            if self.is_terminated() {
+                break;
+            }
+        }
+        Ok(value)
+    }
+
     pub fn translate_conditional(
         &mut self,
         cond: &Box<Expr>, 
         then_branch: &Box<Stmt>, 
         else_branch: &Option<Box<Stmt>>
-    ) -> Result<FloatValue<'ctx>, &'static str> {
+    ) -> TranslateResult<Value<'ctx>> {
+        // A constant condition, as left by the folder, only needs the
+        // branch it takes and no merge block.
+        if let Some(cond) = constant(cond) {
+            let branch = match cond.is_truthy() {
+                true => Some(then_branch),
+                false => else_branch.as_ref(),
+            };
+            let value = match branch {
+                Some(branch) => self.translate_stmt(branch)?,
+                None => Value::Float(self.context.f64_type().const_zero()),
+            };
+            if self.is_terminated() {
+                return Ok(Value::Float(self.context.f64_type().const_zero()));
+            }
+            return Ok(Value::Float(self.to_float(value)?));
+        }
+
         let parent = self.fn_value_opt.unwrap();
-        let zero_const = self.context.f64_type().const_float(0.0);
 
-        // create condition by comparing without 0.0 and returning an int
+        // create condition by comparing without 0 and returning an int
         let cond = self.translate_expr(cond)?;
-        let cond = self
-            .builder
-            .build_float_compare(FloatPredicate::ONE, cond, zero_const, "ifcond");
+        let cond = self.truthy(cond, "ifcond")?;
 
         // build branch
         let then_bb = self.context.append_basic_block(parent, "then");
         let else_bb = self.context.append_basic_block(parent, "else");
         let cont_bb = self.context.append_basic_block(parent, "ifcont");
 
-        self.builder.build_conditional_branch(cond, then_bb, else_bb);
+        self.builder.build_conditional_branch(cond, then_bb, else_bb)?;
 
-        // build then block
+        // build then block, branches that return don't flow into the
+        // merge block
         self.builder.position_at_end(then_bb);
         let then_val = self.translate_stmt(then_branch)?;
-        self.builder.build_unconditional_branch(cont_bb);
-
+        let then_falls_through = !self.is_terminated();
+        let then_val = match then_falls_through {
+            true => self.to_float(then_val)?,
+            false => self.context.f64_type().const_zero(),
+        };
         let then_bb = self.builder.get_insert_block().unwrap();
-
-        let mut incoming: Vec<(&dyn BasicValue, BasicBlock)> = vec![];
-        incoming.push((&then_val, then_bb));
+        if then_falls_through {
+            self.builder.build_unconditional_branch(cont_bb)?;
+        }
 
         // build else block
         self.builder.position_at_end(else_bb);
-        let else_b = *else_branch.clone().unwrap();
-        let else_box = Box::new(else_b);
-        let else_val = self.translate_stmt(&else_box)?;
+        // without an else branch the conditional evaluates to 0.0
+        let else_val = match else_branch {
+            Some(else_branch) => self.translate_stmt(else_branch)?,
+            None => Value::Float(self.context.f64_type().const_zero()),
+        };
+        let else_falls_through = !self.is_terminated();
+        let else_val = match else_falls_through {
+            true => self.to_float(else_val)?,
+            false => self.context.f64_type().const_zero(),
+        };
         let else_bb = self.builder.get_insert_block().unwrap();
-        self.builder.build_unconditional_branch(cont_bb);
-        incoming.push((&else_val, else_bb));
+        if else_falls_through {
+            self.builder.build_unconditional_branch(cont_bb)?;
+        }
+
+        let mut incoming: Vec<(&dyn BasicValue, BasicBlock)> = vec![];
+        if then_falls_through {
+            incoming.push((&then_val, then_bb));
+        }
+        if else_falls_through {
+            incoming.push((&else_val, else_bb));
+        }
 
-        // emit merge block
+        // emit merge block, which is unreachable when both branches return
         self.builder.position_at_end(cont_bb);
+        if incoming.is_empty() {
+            return Ok(Value::Float(self.context.f64_type().const_zero()));
+        }
 
-        let phi = self.builder.build_phi(self.context.f64_type(), "iftmp");
+        let phi = self.builder.build_phi(self.context.f64_type(), "iftmp")?;
         phi.add_incoming(incoming.as_slice());
 
-        Ok(phi.as_basic_value().into_float_value())
+        Ok(Value::Float(phi.as_basic_value().into_float_value()))
     }
 
-    pub fn translate_expr(&self, expr: &Box<Expr>) -> Result<FloatValue<'ctx>, &'static str> {
+    pub fn translate_while(
+        &mut self,
+        condition: &Box<Expr>,
+        body: &Box<Stmt>,
+        increment: &Option<Box<Expr>>,
+    ) -> TranslateResult<Value<'ctx>> {
+        let parent = self.fn_value_opt.unwrap();
+
+        let loop_bb = self.context.append_basic_block(parent, "loop");
+        let body_bb = self.context.append_basic_block(parent, "body");
+        // `continue` runs the increment before checking the condition.
+        let step_bb = match increment {
+            Some(_) => self.context.append_basic_block(parent, "step"),
+            None => loop_bb,
+        };
+        let after_bb = self.context.append_basic_block(parent, "afterloop");
+
+        self.builder.build_unconditional_branch(loop_bb)?;
+
+        // re-evaluate the condition against 0 on every iteration
+        self.builder.position_at_end(loop_bb);
+        let cond = self.translate_expr(condition)?;
+        let cond = self.truthy(cond, "loopcond")?;
+        self.builder.build_conditional_branch(cond, body_bb, after_bb)?;
+
+        // build body block and jump back to the condition
+        self.builder.position_at_end(body_bb);
+        self.loops.push((step_bb, after_bb));
+        let body = self.translate_stmt(body);
+        self.loops.pop();
+        body?;
+        if !self.is_terminated() {
+            self.builder.build_unconditional_branch(step_bb)?;
+        }
+
+        if let Some(increment) = increment {
+            self.builder.position_at_end(step_bb);
+            self.translate_expr(increment)?;
+            self.builder.build_unconditional_branch(loop_bb)?;
+        }
+
+        self.builder.position_at_end(after_bb);
+
+        Ok(Value::Float(self.context.f64_type().const_zero()))
+    }
+
+    fn translate_logical(
+        &self,
+        op: &Token,
+        left: &Box<Expr>,
+        right: &Box<Expr>,
+    ) -> TranslateResult<Value<'ctx>> {
+        let parent = self.fn_value_opt.unwrap();
+        let false_const = self.context.bool_type().const_zero();
+        let true_const = self.context.bool_type().const_all_ones();
+
+        let lhs = self.translate_expr(left)?;
+        let lhs = self.truthy(lhs, "lhscond")?;
+        let lhs_bb = self.builder.get_insert_block().unwrap();
+
+        let rhs_bb = self.context.append_basic_block(parent, "logicrhs");
+        let cont_bb = self.context.append_basic_block(parent, "logiccont");
+
+        // `and` only evaluates the right operand when the left is true,
+        // `or` only when it is false. Otherwise the left decides the result.
+        let short_circuit = match op {
+            Token::And => {
+                self.builder.build_conditional_branch(lhs, rhs_bb, cont_bb)?;
+                false_const
+            },
+            Token::Or => {
+                self.builder.build_conditional_branch(lhs, cont_bb, rhs_bb)?;
+                true_const
+            },
+            op => return Err(TranslateError::UnsupportedOp(op.clone())),
+        };
+
+        self.builder.position_at_end(rhs_bb);
+        let rhs = self.translate_expr(right)?;
+        let rhs = self.truthy(rhs, "rhscond")?;
+        let rhs_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cont_bb)?;
+
+        self.builder.position_at_end(cont_bb);
+        let phi = self.builder.build_phi(self.context.bool_type(), "logictmp")?;
+        phi.add_incoming(&[(&short_circuit, lhs_bb), (&rhs, rhs_bb)]);
+
+        Ok(Value::Bool(phi.as_basic_value().into_int_value()))
+    }
+
+    fn translate_ternary(
+        &self,
+        cond: &Box<Expr>,
+        then: &Box<Expr>,
+        els: &Box<Expr>,
+    ) -> TranslateResult<Value<'ctx>> {
+        let parent = self.fn_value_opt.unwrap();
+
+        let cond = self.translate_expr(cond)?;
+        let cond = self.truthy(cond, "ternarycond")?;
+
+        let then_bb = self.context.append_basic_block(parent, "ternarythen");
+        let else_bb = self.context.append_basic_block(parent, "ternaryelse");
+        let cont_bb = self.context.append_basic_block(parent, "ternarycont");
+
+        self.builder.build_conditional_branch(cond, then_bb, else_bb)?;
+
+        // Only the selected operand is evaluated.
+        self.builder.position_at_end(then_bb);
+        let then_val = self.translate_expr(then)?;
+        let then_val = self.to_float(then_val)?;
+        let then_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cont_bb)?;
+
+        self.builder.position_at_end(else_bb);
+        let else_val = self.translate_expr(els)?;
+        let else_val = self.to_float(else_val)?;
+        let else_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cont_bb)?;
+
+        self.builder.position_at_end(cont_bb);
+        let phi = self.builder.build_phi(self.context.f64_type(), "ternarytmp")?;
+        phi.add_incoming(&[(&then_val, then_bb), (&else_val, else_bb)]);
+
+        Ok(Value::Float(phi.as_basic_value().into_float_value()))
+    }
+
+    pub fn translate_expr(&self, expr: &Box<Expr>) -> TranslateResult<Value<'ctx>> {
         match expr.as_ref() {
-            Expr::Literal{ value: nb } => {
-                let f: f64 = nb.parse::<f64>().unwrap();
-                Ok(self.context.f64_type().const_float(f))
+            // Numbers without a fractional part or exponent are integers,
+            // booleans are represented as 1.0 and 0.0.
+            Expr::Literal { value } => match Constant::from_literal(value) {
+                Some(Constant::Int(i)) => Ok(Value::Int(self.context.i64_type().const_int(i as u64, true))),
+                Some(Constant::Float(f)) => Ok(Value::Float(self.context.f64_type().const_float(f))),
+                None => Err(TranslateError::UnhandledExpr(expr.as_ref().clone())),
+            },
+            Expr::Grouping { expr } => self.translate_expr(expr),
+            Expr::Variable { name, .. } => {
+                let id = identifier(name)?;
+                match self.lookup_variable(id.as_str()) {
+                    Some((var, _)) => Ok(Value::from_basic_value(self.builder.build_load(var, id.as_str())?)),
+                    None => Err(TranslateError::UnknownVariable(id)),
+                }
+            },
+            Expr::Assign { name, value, .. } => {
+                let id = identifier(name)?;
+                let value = self.translate_expr(value)?;
+                match self.lookup_variable(id.as_str()) {
+                    Some((var, ty)) => {
+                        let value = self.coerce(value, ty)?;
+                        self.builder.build_store(var, value)?;
+                        Ok(Value::from_basic_value(value))
+                    },
+                    None => Err(TranslateError::UnknownVariable(id)),
+                }
             },
-            Expr::Variable { name } => {
-                let Token::Ident(id) = name else {
-                    panic!("Not an ident");
+            Expr::Call { callee, paren: _, args } => {
+                let Expr::Variable { name: Token::Ident(fn_name), .. } = callee.as_ref() else {
+                    return Err(TranslateError::InvalidCallee);
                 };
-                match self.variables.get(id.as_str()) {
-                    Some(var) => Ok(self.builder.build_load(*var, id.as_str()).into_float_value()),
-                    None => Err("Could not find a matching variable"),
+                match self.translate_call(fn_name, args)? {
+                    Some(value) => Ok(value),
+                    None => Err(TranslateError::VoidCall(fn_name.clone())),
                 }
             },
+            Expr::UnaryExpr { op, right } => {
+                let operand = self.translate_expr(right)?;
+
+                match (op, operand) {
+                    (Token::Minus, Value::Float(v)) => Ok(Value::Float(self.builder.build_float_neg(v, "tmpneg")?)),
+                    (Token::Minus, Value::Int(v)) => Ok(Value::Int(self.builder.build_int_neg(v, "tmpneg")?)),
+                    (Token::Minus, operand) => {
+                        let v = self.to_float(operand)?;
+                        Ok(Value::Float(self.builder.build_float_neg(v, "tmpneg")?))
+                    },
+                    (Token::Bang, operand) => {
+                        let cmp = self.truthy(operand, "tmpnot")?;
+                        Ok(Value::Bool(self.builder.build_not(cmp, "tmpnot")?))
+                    },
+                    (op, _) => Err(TranslateError::UnsupportedOp(op.clone())),
+                }
+            },
+            Expr::Index { array, index, .. } => {
+                let ptr = self.build_element_ptr(array, index)?;
+                let value = self.builder.build_load(ptr, "tmpelem")?;
+                Ok(Value::Float(value.into_float_value()))
+            },
+            Expr::SetIndex { array, index, value, .. } => {
+                let ptr = self.build_element_ptr(array, index)?;
+                let value = self.translate_expr(value)?;
+                let value = self.to_float(value)?;
+                self.builder.build_store(ptr, value)?;
+                Ok(Value::Float(value))
+            },
+            Expr::Logical { op, left, right } => self.translate_logical(op, left, right),
+            Expr::Ternary { cond, then, els } => self.translate_ternary(cond, then, els),
             Expr::BinaryExpr {
                 op,
                 ref left,
@@ -192,49 +1002,442 @@ impl<'a, 'ctx> Translator<'a, 'ctx> {
                     let lhs = self.translate_expr(left)?;
                     let rhs = self.translate_expr(right)?;
 
-                    match op {
-                        Token::Plus => Ok(self.builder.build_float_add(lhs, rhs, "tmpadd")),
-                        Token::Minus => Ok(self.builder.build_float_sub(lhs, rhs, "tmpsub")),
-                        Token::Less => Ok({
-                            let cmp = self
-                                .builder
-                                .build_float_compare(FloatPredicate::ULT, lhs, rhs, "tmpcmp");
-
-                            self.builder
-                                .build_unsigned_int_to_float(cmp, self.context.f64_type(), "tmpbool")
-                        }),
-                        Token::Greater => Ok({
-                            let cmp = self
-                                .builder
-                                .build_float_compare(FloatPredicate::ULT, rhs, lhs, "tmpcmp");
-
-                            self.builder
-                                .build_unsigned_int_to_float(cmp, self.context.f64_type(), "tmpbool")
-                        }),
-                        _ => Err("unsupported binary operation"),
+                    match (lhs, rhs) {
+                        (Value::Int(lhs), Value::Int(rhs)) => self.translate_int_binary(op, lhs, rhs),
+                        (lhs, rhs) => {
+                            let lhs = self.to_float(lhs)?;
+                            let rhs = self.to_float(rhs)?;
+                            self.translate_float_binary(op, lhs, rhs)
+                        },
                     }
             },
-            _ => Err("unable to compile expression to LLVM")
+            Expr::Get { name, .. } => Err(TranslateError::MemberAccess(name.clone())),
+            expr => Err(TranslateError::UnhandledExpr(expr.clone())),
         }
     }
 
+    fn translate_float_binary(
+        &self,
+        op: &Token,
+        lhs: FloatValue<'ctx>,
+        rhs: FloatValue<'ctx>,
+    ) -> TranslateResult<Value<'ctx>> {
+        let predicate = match op {
+            Token::Plus => return Ok(Value::Float(self.builder.build_float_add(lhs, rhs, "tmpadd")?)),
+            Token::Minus => return Ok(Value::Float(self.builder.build_float_sub(lhs, rhs, "tmpsub")?)),
+            Token::Times => return Ok(Value::Float(self.builder.build_float_mul(lhs, rhs, "tmpmul")?)),
+            Token::Div => return Ok(Value::Float(self.builder.build_float_div(lhs, rhs, "tmpdiv")?)),
+            Token::Percent => return Ok(Value::Float(self.builder.build_float_rem(lhs, rhs, "tmprem")?)),
+            Token::StarStar => return Ok(Value::Float(self.call_float_intrinsic("llvm.pow.f64", &[lhs, rhs])?)),
+            // Ordered predicates, so comparing NaN is false. `!=` is the
+            // negation of `==` and so is true for NaN.
+            Token::Less => FloatPredicate::OLT,
+            Token::Greater => FloatPredicate::OGT,
+            Token::Geq => FloatPredicate::OGE,
+            Token::Leq => FloatPredicate::OLE,
+            Token::Eqq => FloatPredicate::OEQ,
+            Token::BangEq => FloatPredicate::UNE,
+            Token::Amp
+            | Token::Pipe
+            | Token::Caret
+            | Token::ShiftLeft
+            | Token::ShiftRight => return Err(TranslateError::IntegerOnlyOp(op.clone())),
+            op => return Err(TranslateError::UnsupportedOp(op.clone())),
+        };
+        let cmp = self
+            .builder
+            .build_float_compare(predicate, lhs, rhs, "tmpcmp")?;
+
+        Ok(Value::Bool(cmp))
+    }
+
+    // Calls one of the `llvm.*.with.overflow` intrinsics and traps when
+    // the result doesn't fit.
+    fn build_checked_int_op(
+        &self,
+        intrinsic: &str,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+    ) -> TranslateResult<IntValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let fun = self.module.get_function(intrinsic).unwrap_or_else(|| {
+            let result = self.context.struct_type(&[i64_type.into(), self.context.bool_type().into()], false);
+            let fn_type = result.fn_type(&[i64_type.into(), i64_type.into()], false);
+            self.module.add_function(intrinsic, fn_type, None)
+        });
+        let result = self.builder
+            .build_call(fun, &[lhs.into(), rhs.into()], "tmpchecked")?
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_struct_value();
+        let value = self.builder.build_extract_value(result, 0, "tmpvalue")?.into_int_value();
+        let overflow = self.builder.build_extract_value(result, 1, "tmpoverflow")?.into_int_value();
+        self.build_trap(overflow, "overflow", "nooverflow")?;
+        Ok(value)
+    }
+
+    // Multiplies and traps when the product doesn't fit. On wasm32,
+    // `llvm.smul.with.overflow.i64` becomes a call to `__multi3` from a
+    // runtime library that isn't linked, so the wrapped product is divided
+    // back instead. It overflowed unless that gives the other operand,
+    // where 0 never overflows and -1 only does with `i64::MIN`, which
+    // can't be divided by -1 itself.
+    fn build_checked_mul(&self, lhs: IntValue<'ctx>, rhs: IntValue<'ctx>) -> TranslateResult<IntValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let zero = i64_type.const_zero();
+        let minus_one = i64_type.const_all_ones();
+        let one = i64_type.const_int(1, false);
+        let min = i64_type.const_int(i64::MIN as u64, true);
+
+        let product = self.builder.build_int_mul(lhs, rhs, "tmpmul")?;
+        let is_zero = self.builder.build_int_compare(IntPredicate::EQ, lhs, zero, "tmpzero")?;
+        let is_minus_one = self.builder.build_int_compare(IntPredicate::EQ, lhs, minus_one, "tmpminusone")?;
+        let is_small = self.builder.build_or(is_zero, is_minus_one, "tmpsmall")?;
+        let divisor = self.builder.build_select(is_small, one, lhs, "tmpdivisor")?.into_int_value();
+        let quotient = self.builder.build_int_signed_div(product, divisor, "tmpquotient")?;
+        let wrapped = self.builder.build_int_compare(IntPredicate::NE, quotient, rhs, "tmpwrapped")?;
+        let is_min = self.builder.build_int_compare(IntPredicate::EQ, rhs, min, "tmpmin")?;
+        let negated_min = self.builder.build_and(is_minus_one, is_min, "tmpnegatedmin")?;
+        let overflow = self.builder
+            .build_select(is_small, negated_min, wrapped, "tmpoverflow")?
+            .into_int_value();
+        self.build_trap(overflow, "overflow", "nooverflow")?;
+        Ok(product)
+    }
+
+    // Traps when the i1 condition is set and carries on in a new block
+    // otherwise. A trap aborts the wasm instance.
+    fn build_trap(&self, cond: IntValue<'ctx>, trap_name: &str, cont_name: &str) -> TranslateResult<()> {
+        let parent = self.fn_value_opt.unwrap();
+        let trap_bb = self.context.append_basic_block(parent, trap_name);
+        let cont_bb = self.context.append_basic_block(parent, cont_name);
+        self.builder.build_conditional_branch(cond, trap_bb, cont_bb)?;
+
+        self.builder.position_at_end(trap_bb);
+        let trap = self.module.get_function("llvm.trap").unwrap_or_else(|| {
+            let fn_type = self.context.void_type().fn_type(&[], false);
+            self.module.add_function("llvm.trap", fn_type, None)
+        });
+        self.builder.build_call(trap, &[], "")?;
+        self.builder.build_unreachable()?;
+
+        self.builder.position_at_end(cont_bb);
+        Ok(())
+    }
+
+    // Declares the heap and its bump pointer on first use. Both are
+    // internal, so the heap ends up zeroed in the exported wasm memory.
+    fn heap(&self) -> (PointerValue<'ctx>, PointerValue<'ctx>) {
+        let heap = self.module.get_global(HEAP).unwrap_or_else(|| {
+            let heap_type = self.context.f64_type().array_type(HEAP_SLOTS as u32);
+            let global = self.module.add_global(heap_type, None, HEAP);
+            global.set_initializer(&heap_type.const_zero());
+            global.set_linkage(Linkage::Internal);
+            global
+        });
+        let next = self.module.get_global(HEAP_NEXT).unwrap_or_else(|| {
+            let global = self.module.add_global(self.context.i64_type(), None, HEAP_NEXT);
+            global.set_initializer(&self.context.i64_type().const_zero());
+            global.set_linkage(Linkage::Internal);
+            global
+        });
+        (heap.as_pointer_value(), next.as_pointer_value())
+    }
+
+    // Points at a slot of the heap.
+    fn heap_slot(&self, slot: IntValue<'ctx>) -> TranslateResult<PointerValue<'ctx>> {
+        let (heap, _) = self.heap();
+        let zero = self.context.i64_type().const_zero();
+        // Callers check the slot is below the next free one.
+        let ptr = unsafe { self.builder.build_in_bounds_gep(heap, &[zero, slot], "tmpslot")? };
+        Ok(ptr)
+    }
+
+    // Allocates an array of `len` zeroed elements from the heap. The slot
+    // before the elements holds the length, and the handle is the slot of
+    // the first element, so no array has the handle 0. Traps when the
+    // length is negative or the heap is full.
+    fn build_array(&self, len: FloatValue<'ctx>) -> TranslateResult<FloatValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let (_, next_ptr) = self.heap();
+        let n = self.builder.build_float_to_signed_int(len, i64_type, "tmplen")?;
+        let next = self.builder.build_load(next_ptr, "tmpnext")?.into_int_value();
+        let handle = self.builder.build_int_add(next, i64_type.const_int(1, false), "tmphandle")?;
+        let end = self.builder.build_int_add(handle, n, "tmpend")?;
+
+        let negative = self.builder.build_int_compare(IntPredicate::SLT, n, i64_type.const_zero(), "tmpneg")?;
+        let full = self.builder.build_int_compare(IntPredicate::UGT, end, i64_type.const_int(HEAP_SLOTS, false), "tmpfull")?;
+        let failed = self.builder.build_or(negative, full, "tmpfailed")?;
+        self.build_trap(failed, "outofmemory", "allocated")?;
+
+        let len = self.builder.build_signed_int_to_float(n, self.context.f64_type(), "tmplen")?;
+        self.builder.build_store(self.heap_slot(next)?, len)?;
+        self.builder.build_store(next_ptr, end)?;
+        Ok(self.builder.build_signed_int_to_float(handle, self.context.f64_type(), "tmphandle")?)
+    }
+
+    // Points at an element of an array, trapping when the handle was not
+    // returned by `array` or the index is out of bounds.
+    fn build_element_ptr(&self, array: &Box<Expr>, index: &Box<Expr>) -> TranslateResult<PointerValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let (_, next_ptr) = self.heap();
+        let array = self.translate_expr(array)?;
+        let handle = self.builder.build_float_to_signed_int(self.to_float(array)?, i64_type, "tmphandle")?;
+        let index = self.translate_expr(index)?;
+        let index = self.builder.build_float_to_signed_int(self.to_float(index)?, i64_type, "tmpindex")?;
+
+        // An unsigned compare also catches handles of zero or less.
+        let header = self.builder.build_int_sub(handle, i64_type.const_int(1, false), "tmpheader")?;
+        let next = self.builder.build_load(next_ptr, "tmpnext")?.into_int_value();
+        let invalid = self.builder.build_int_compare(IntPredicate::UGE, header, next, "tmpinvalid")?;
+        self.build_trap(invalid, "invalidarray", "validarray")?;
+
+        let len = self.builder.build_load(self.heap_slot(header)?, "tmplen")?;
+        let len = self.builder.build_float_to_signed_int(len.into_float_value(), i64_type, "tmplen")?;
+        let outside = self.builder.build_int_compare(IntPredicate::UGE, index, len, "tmpoutside")?;
+        self.build_trap(outside, "outofbounds", "inbounds")?;
+
+        let slot = self.builder.build_int_add(handle, index, "tmpslot")?;
+        self.heap_slot(slot)
+    }
+
+    fn translate_int_binary(
+        &self,
+        op: &Token,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+    ) -> TranslateResult<Value<'ctx>> {
+        if self.checked_arith {
+            let intrinsic = match op {
+                Token::Plus => Some("llvm.sadd.with.overflow.i64"),
+                Token::Minus => Some("llvm.ssub.with.overflow.i64"),
+                Token::Times => return Ok(Value::Int(self.build_checked_mul(lhs, rhs)?)),
+                _ => None,
+            };
+            if let Some(intrinsic) = intrinsic {
+                return Ok(Value::Int(self.build_checked_int_op(intrinsic, lhs, rhs)?));
+            }
+        }
+        let predicate = match op {
+            Token::Plus => return Ok(Value::Int(self.builder.build_int_add(lhs, rhs, "tmpadd")?)),
+            Token::Minus => return Ok(Value::Int(self.builder.build_int_sub(lhs, rhs, "tmpsub")?)),
+            Token::Times => return Ok(Value::Int(self.builder.build_int_mul(lhs, rhs, "tmpmul")?)),
+            Token::Percent => return Ok(Value::Int(self.builder.build_int_signed_rem(lhs, rhs, "tmprem")?)),
+            Token::Amp => return Ok(Value::Int(self.builder.build_and(lhs, rhs, "tmpand")?)),
+            Token::Pipe => return Ok(Value::Int(self.builder.build_or(lhs, rhs, "tmpor")?)),
+            Token::Caret => return Ok(Value::Int(self.builder.build_xor(lhs, rhs, "tmpxor")?)),
+            Token::ShiftLeft => return Ok(Value::Int(self.builder.build_left_shift(lhs, rhs, "tmpshl")?)),
+            Token::ShiftRight => return Ok(Value::Int(self.builder.build_right_shift(lhs, rhs, true, "tmpshr")?)),
+            // Quotients and powers are always floats, so `10 / 4` is 2.5
+            // and negative exponents give fractions.
+            Token::Div | Token::StarStar => {
+                let lhs = self.to_float(Value::Int(lhs))?;
+                let rhs = self.to_float(Value::Int(rhs))?;
+                return self.translate_float_binary(op, lhs, rhs);
+            },
+            Token::Less => IntPredicate::SLT,
+            Token::Greater => IntPredicate::SGT,
+            Token::Geq => IntPredicate::SGE,
+            Token::Leq => IntPredicate::SLE,
+            Token::Eqq => IntPredicate::EQ,
+            Token::BangEq => IntPredicate::NE,
+            op => return Err(TranslateError::UnsupportedOp(op.clone())),
+        };
+        let cmp = self
+            .builder
+            .build_int_compare(predicate, lhs, rhs, "tmpcmp")?;
+
+        Ok(Value::Bool(cmp))
+    }
+
     pub fn translate(
         context: &'ctx Context,
         builder: &'a Builder<'ctx>,
         pass_manager: &'a PassManager<FunctionValue<'ctx>>,
         module: &'a Module<'ctx>,
-        stmt: &Stmt,
-    ) -> Result<FunctionValue<'ctx>, &'static str> {
+        statements: &[Box<Stmt>],
+        debug_info: Option<&'a DebugInfo<'ctx>>,
+        checked_arith: bool,
+    ) -> TranslateResult<Vec<FunctionValue<'ctx>>> {
         let mut tr = Translator {
             context,
             builder,
             fpm: pass_manager,
             module,
             fn_value_opt: None,
-            variables: HashMap::new(),
+            debug_info,
+            checked_arith,
+            variables: vec!(),
+            loops: vec!(),
+            arities: BTreeMap::new(),
+            globals: BTreeMap::new(),
+            int_variables: HashSet::new(),
         };
 
-        tr.translate_function(stmt)
+        // Translation happens in two phases: every function signature is
+        // declared in the module first, then the bodies are compiled. This
+        // lets a body call itself or any function defined after it.
+        // Only functions are translated, top-level statements are
+        // expected to have been moved into a generated entry point.
+        // Functions are added to the module in source order, and the maps
+        // kept here are ordered, so the same program always produces the
+        // same IR.
+        let functions = statements
+            .iter()
+            .filter(|stmt| matches!(stmt.as_ref(), Stmt::Function { .. }))
+            .collect::<Vec<&Box<Stmt>>>();
+        for fun in functions.iter() {
+            tr.translate_function_sig(fun)?;
+        }
+        // Globals are added after the functions so an exported function
+        // keeps its name when a global shares it.
+        for stmt in statements.iter() {
+            if let Stmt::Var { name, initializer, .. } = stmt.as_ref() {
+                tr.translate_global(name, initializer)?;
+            }
+        }
+
+        let mut translated = vec!();
+        for fun in functions.iter() {
+            translated.push(tr.translate_function(fun)?);
+        }
+        Ok(translated)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_str,Compiler,TranslateOptions};
+
+    // The unoptimized IR of a single function.
+    fn function_ir(source: &str) -> String {
+        let options = TranslateOptions { opt_level: 0, ..TranslateOptions::default() };
+        Compiler::with_options(options).compile(source).unwrap()
+    }
+
+    #[test]
+    fn integer_arithmetic_stays_integer() {
+        let ir = function_ir("fun f(n: int) -> int { var x = n * 3 + 7 % 3; x = x & 6 | 1; return x << 1; }");
+        assert!(ir.contains("%x = alloca i64"), "{}", ir);
+        assert!(!ir.contains("double"), "{}", ir);
+    }
+
+    #[test]
+    fn integer_division_gives_a_float() {
+        let ir = function_ir("fun f(a: int, b: int) { return a / b; }");
+        assert!(ir.contains("fdiv double"), "{}", ir);
+        assert!(!ir.contains("sdiv"), "{}", ir);
+    }
+
+    #[test]
+    fn variable_assigned_a_float_is_a_float() {
+        let ir = function_ir("fun f(a) { var x = 1; var n = 1; x = a; n += 1; return x + n; }");
+        assert!(ir.contains("%x = alloca double"), "{}", ir);
+        assert!(ir.contains("%n = alloca i64"), "{}", ir);
+        assert!(!ir.contains("fptosi"), "{}", ir);
+    }
+
+    #[test]
+    fn globals_are_constants() {
+        let ir = function_ir("var PI = 3.5; var N = 2; fun area(r) { return PI * r * r; } fun twice() { return N * 2; }");
+        assert!(ir.contains("@PI = internal constant double 3.500000e+00"), "{}", ir);
+        assert!(ir.contains("@N = internal constant i64 2"), "{}", ir);
+    }
+
+    // Translates the functions without resolving names first, returning
+    // the error.
+    fn translate_error(statements: &[Box<Stmt>]) -> TranslateError {
+        let context = Context::create();
+        let module = context.create_module("tmp");
+        let builder = context.create_builder();
+        let fpm = PassManager::create(&module);
+        match Translator::translate(&context, &builder, &fpm, &module, statements, None, false) {
+            Ok(_) => panic!("expected {:?} to fail", statements),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn specific_errors() {
+        let err = translate_error(&parse_str("fun f() { return y + 1; }").unwrap());
+        assert!(matches!(err, TranslateError::UnknownVariable(ref name) if name == "y"), "{:?}", err);
+
+        let err = translate_error(&parse_str("fun f(x) { return x & 1; }").unwrap());
+        assert!(matches!(err, TranslateError::IntegerOnlyOp(Token::Amp)), "{:?}", err);
+
+        // The parser never produces `,` as an operator.
+        let literal = |value: &str| Box::new(Expr::Literal { value: value.to_string() });
+        let fun = Stmt::Function {
+            name: Token::Ident("f".to_string()),
+            params: vec!(),
+            return_type: None,
+            body: vec!(Box::new(Stmt::Return {
+                keyword: Token::Return,
+                value: Some(Box::new(Expr::BinaryExpr { op: Token::Comma, left: literal("1.5"), right: literal("2.5") })),
+                span: Span::default(),
+            })),
+            span: Span::default(),
+            entry: false,
+        };
+        let err = translate_error(&[Box::new(fun)]);
+        assert!(matches!(err, TranslateError::UnsupportedOp(Token::Comma)), "{:?}", err);
+    }
+
+    #[test]
+    fn conditions_use_the_comparison_directly() {
+        let ir = function_ir("fun f(a) { if (a < 1) { return 1; } while (a > 5 and a < 10) { a -= 1; } return 2; }");
+        assert!(ir.contains("fcmp olt double"), "{}", ir);
+        assert!(!ir.contains("uitofp"), "{}", ir);
+        assert!(!ir.contains("fcmp one"), "{}", ir);
+
+        // Returned comparisons are widened to 1.0 or 0.0.
+        let ir = function_ir("fun less(a, b) { return a < b; }");
+        assert!(ir.contains("uitofp i1"), "{}", ir);
+    }
+
+    #[test]
+    fn calls_match_the_parameter_count() {
+        let arity = |call: &str| {
+            let source = format!("fun add(a, b) {{ return a + b; }} fun main() {{ return {}; }}", call);
+            translate_error(&parse_str(&source).unwrap())
+        };
+        let err = arity("add(1)");
+        assert!(matches!(err, TranslateError::ArityMismatch { ref name, expected: 2, found: 1 } if name == "add"), "{:?}", err);
+        let err = arity("add(1, 2, 3)");
+        assert!(matches!(err, TranslateError::ArityMismatch { expected: 2, found: 3, .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn statements_carry_their_lines() {
+        let source = "fun f(a) {\n    var b = a * 2;\n    if (b > 3) {\n        return b;\n    }\n    return 0;\n}";
+        let options = TranslateOptions { opt_level: 0, source: Some(Path::new("debug.mai")), ..TranslateOptions::default() };
+        let ir = Compiler::with_options(options).compile(source).unwrap();
+        assert!(ir.contains("!DISubprogram(name: \"f\""), "{}", ir);
+        for (line, col) in [(1, 5), (2, 5), (3, 9), (4, 9), (6, 5)] {
+            let location = format!("!DILocation(line: {}, column: {}", line, col);
+            assert!(ir.contains(&location), "no {} in\n{}", location, ir);
+        }
+        // Both returns point at their own line.
+        let returns = ir.lines().filter(|line| line.trim_start().starts_with("ret ")).collect::<Vec<&str>>();
+        assert_eq!(returns.len(), 2, "{}", ir);
+        assert!(returns.iter().all(|ret| ret.contains("!dbg")), "{}", ir);
+    }
+
+    #[test]
+    fn side_effects_only_return_void() {
+        let ir = function_ir("fun show(x) { print x; if (x > 1) { return; } print x * 2; } fun twice(x) { return x * 2; }");
+        assert!(ir.contains("define void @show(double %x)"), "{}", ir);
+        assert!(ir.contains("ret void"), "{}", ir);
+        assert!(ir.contains("define double @twice(double %x)"), "{}", ir);
+    }
+
+    #[test]
+    fn constant_conditions_keep_one_branch() {
+        let ir = function_ir(include_str!("../tests/programs/constant_if.mai"));
+        assert!(ir.contains("define double @taken() {\nentry:\n  ret double 1.000000e+00\n}"), "{}", ir);
+        assert!(ir.contains("define double @fallback() {\nentry:\n  ret double 2.000000e+00\n}"), "{}", ir);
+        assert!(!ir.contains("br ") && !ir.contains("phi"), "{}", ir);
+    }
+}
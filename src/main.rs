@@ -1,147 +1,485 @@
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
-use std::process::{Command,Stdio};
-use execute::Execute;
+use std::process::Command;
 
+use eyre::WrapErr;
 use structopt::StructOpt;
 
 use inkwell::context::Context;
-use inkwell::passes::PassManager;
+use inkwell::module::Module;
 
-mod token;
-mod lexer;
-mod parser;
-mod llvm_translator;
+use mai::{
+    add_native_main,compile_to_object,dump_passes,entry_point,find_tool,interpret,lex,link_native,link_wasm,parse,parse_with_comments,pretty,repl,
+    resolve,run_wasm_with_options,translate,wasm_to_wat,with_implicit_main,RunOptions,TempDir,TranslateOptions,NATIVE_LINKERS,WASM_LINKERS,WASM_TRIPLE,
+};
+use mai::token::Token;
 
-use llvm_translator::Translator;
-use parser::Parser;
-use lexer::TokenLexer;
-use token::Token;
+/// The artifact the compiler should stop at and output.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+    LlvmIr,
+    Wasm,
+    Wat,
+    Run,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokens" => Ok(Emit::Tokens),
+            "ast" => Ok(Emit::Ast),
+            "llvm-ir" => Ok(Emit::LlvmIr),
+            "wasm" => Ok(Emit::Wasm),
+            "wat" => Ok(Emit::Wat),
+            "run" => Ok(Emit::Run),
+            other => Err(format!("unknown emit kind `{}`", other)),
+        }
+    }
+}
+
+/// How `--emit tokens` and `--emit ast` are printed.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown format `{}`", other)),
+        }
+    }
+}
+
+/// How the value returned by the program is printed.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum ResultFormat {
+    /// Shortest form, `7` or `0.5`.
+    Plain,
+    /// Mantissa with six decimals and exponent, `7.000000e0`.
+    Scientific,
+    /// `{"func":"safe_sub","result":7.0}`, with a null result for void
+    /// functions.
+    Json,
+}
+
+impl FromStr for ResultFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(ResultFormat::Plain),
+            "scientific" => Ok(ResultFormat::Scientific),
+            "json" => Ok(ResultFormat::Json),
+            other => Err(format!("unknown result format `{}`", other)),
+        }
+    }
+}
 
 #[derive(StructOpt,Debug)]
 #[structopt(name = "mai")]
 struct Opts {
     #[structopt(short,long,default_value="main.mai")]
     input: PathBuf,
+    /// What to output: tokens, ast, llvm-ir, wasm, wat, or run the program.
+    #[structopt(long,default_value="run",possible_values=&["tokens","ast","llvm-ir","wasm","wat","run"])]
+    emit: Emit,
+    /// Print tokens or the AST as text or as JSON, with spans, for tooling.
+    #[structopt(long,default_value="text",possible_values=&["text","json"])]
+    format: Format,
+    /// Print the value returned by the program as plain, scientific or
+    /// JSON.
+    #[structopt(long,default_value="plain",possible_values=&["plain","scientific","json"])]
+    result_format: ResultFormat,
+    /// Where to write the binary for `--emit wasm`, or the executable for
+    /// other targets. Defaults to the input path with a `.wasm` extension,
+    /// or without an extension.
+    #[structopt(short,long)]
+    output: Option<PathBuf>,
+    /// Target triple to compile for, or `native` to build an executable
+    /// for this machine that runs without wasmer.
+    #[structopt(long,default_value=WASM_TRIPLE)]
+    target: String,
+    /// Linker used to turn the object file into wasm, or an executable for
+    /// other targets. Probes for `wasm-ld` and versioned variants, or a C
+    /// compiler, when not set.
+    #[structopt(long)]
+    linker: Option<String>,
+    /// Optimization level from 0, which runs no passes, to 3.
+    #[structopt(long,default_value="2",possible_values=&["0","1","2","3"])]
+    opt_level: u8,
+    /// Attach source locations to the generated code. Optimizations are
+    /// turned off so they don't scramble the locations.
+    #[structopt(long)]
+    debug_info: bool,
+    /// Print the IR after each optimization pass, in the order they run.
+    #[structopt(long)]
+    dump_passes: bool,
+    /// Trap on integer overflow in `+`, `-` and `*` instead of wrapping.
+    #[structopt(long)]
+    checked_arith: bool,
+    /// Exported function to call when running the program, instead of the
+    /// function marked with `wagmi` or `main`.
+    #[structopt(long)]
+    call: Option<String>,
+    /// Comma separated arguments passed to the function given by `--call`.
+    #[structopt(long,use_delimiter=true,allow_hyphen_values=true,requires="call")]
+    args: Vec<f64>,
+    /// Exit with a status from the sign of the `--call` result: 0 for
+    /// zero, 10 for positive and 11 for negative results.
+    #[structopt(long,requires="call")]
+    exit_code: bool,
+    /// Stop the wasm program after it executes this many instructions,
+    /// rather than letting a runaway loop hang.
+    #[structopt(long)]
+    fuel: Option<u64>,
+    /// Refuse to run a wasm program needing more memory than this many
+    /// 64 KiB pages, or to grow its memory past them.
+    #[structopt(long)]
+    max_memory_pages: Option<u32>,
+    /// Evaluate the program with the interpreter rather than compiling
+    /// it, calling `--call` or else the default entry point.
+    #[structopt(long)]
+    interpret: bool,
+    /// Only lex, parse and resolve the input, reporting any errors.
+    #[structopt(long)]
+    check: bool,
+    /// Start an interactive session instead of compiling the input file.
+    #[structopt(long)]
+    repl: bool,
+    /// Print the input file in canonical formatting instead of compiling
+    /// it, keeping its comments.
+    #[structopt(long)]
+    fmt: bool,
+    /// With `--fmt`, overwrite the input file rather than printing it.
+    #[structopt(long,requires="fmt")]
+    write: bool,
+}
+
+const NO_ENTRY_POINT: &str = "Nothing to run, mark a function with `wagmi` or choose one with `--call`";
+
+/// Stage of the pipeline an error comes from, attached to the error as
+/// context. It decides the exit code.
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Stage {
+    Lex,
+    Parse,
+    /// Resolving names and translating to LLVM IR.
+    Codegen,
+    /// Compiling the IR to an object file.
+    Llc,
+    Link,
+    Run,
+}
+
+impl Stage {
+    fn exit_code(self) -> i32 {
+        match self {
+            Stage::Lex => 2,
+            Stage::Parse => 3,
+            Stage::Codegen => 4,
+            Stage::Llc => 5,
+            Stage::Link => 6,
+            Stage::Run => 7,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stage = match self {
+            Stage::Lex => "lexing",
+            Stage::Parse => "parsing",
+            Stage::Codegen => "code generation",
+            Stage::Llc => "compiling to an object file",
+            Stage::Link => "linking",
+            Stage::Run => "running the program",
+        };
+        write!(f, "{} failed", stage)
+    }
 }
 
-fn main() -> eyre::Result<()> {
+fn main() {
     let opts = Opts::from_args();
-    println!("Input file path: {:?}", opts.input);
+    if let Err(err) = run(&opts) {
+        eprintln!("Error: {:?}", err);
+        // Errors outside the stages, such as a missing input file, exit
+        // with 1.
+        let code = err.downcast_ref::<Stage>().map_or(1, |stage| stage.exit_code());
+        std::process::exit(code);
+    }
+}
+
+fn run(opts: &Opts) -> eyre::Result<()> {
+    if opts.repl {
+        return repl::run();
+    }
+    if opts.fmt {
+        return format(opts);
+    }
+    if opts.check {
+        let input = read_input(opts)?;
+        let statements = parse(lex(input.as_str()).wrap_err(Stage::Lex)?).wrap_err(Stage::Parse)?;
+        resolve(&with_implicit_main(&statements)).wrap_err(Stage::Codegen)?;
+        println!("{:?}: no errors found", opts.input);
+        return Ok(());
+    }
+    if opts.interpret {
+        return run_interpreter(opts);
+    }
+    emit(opts)
+}
 
-    let input = fs::read_to_string(opts.input).unwrap();
-    println!("Raw input contents:");
-    println!("{:?}", input);
-    println!("");
+fn read_input(opts: &Opts) -> eyre::Result<String> {
+    fs::read_to_string(&opts.input)
+        .wrap_err_with(|| format!("failed to read input file {:?}", opts.input))
+}
+
+/// Re-emits the input file as formatted source.
+fn format(opts: &Opts) -> eyre::Result<()> {
+    let input = read_input(opts)?;
+    let tokens = lex(input.as_str()).wrap_err(Stage::Lex)?;
+    let statements = parse_with_comments(tokens).wrap_err(Stage::Parse)?;
+    let formatted = pretty::print(&statements);
+    if opts.write {
+        fs::write(&opts.input, formatted)
+            .wrap_err_with(|| format!("failed to write input file {:?}", opts.input))?;
+    } else {
+        print!("{}", formatted);
+    }
+    Ok(())
+}
+
+/// Evaluates the input file without going through LLVM.
+fn run_interpreter(opts: &Opts) -> eyre::Result<()> {
+    let input = read_input(opts)?;
+    let statements = parse(lex(input.as_str()).wrap_err(Stage::Lex)?).wrap_err(Stage::Parse)?;
+    if statements.is_empty() {
+        println!("no statements to compile");
+        return Ok(());
+    }
+    let Some(func) = opts.call.clone().or_else(|| entry_point(&statements)) else {
+        println!("{}", NO_ENTRY_POINT);
+        return Ok(());
+    };
+    resolve(&with_implicit_main(&statements)).wrap_err(Stage::Codegen)?;
+    let result = interpret(&statements, func.as_str(), &opts.args).wrap_err(Stage::Run)?;
+    report(func.as_str(), result, opts);
+    Ok(())
+}
+
+/// Runs the pipeline up to the stage selected by `--emit`, printing or
+/// writing its artifact. Only `run` prints every intermediate stage.
+fn emit(opts: &Opts) -> eyre::Result<()> {
+    let run = opts.emit == Emit::Run;
+    if run {
+        println!("Input file path: {:?}", opts.input);
+    }
 
-    let lexer_res = TokenLexer::new(input.as_str()).collect::<Vec<Token>>();
-    println!("Lexed tokens:");
-    println!("{:?}", lexer_res);
-    println!("");
+    let input = read_input(opts)?;
+    if run {
+        println!("Raw input contents:");
+        println!("{:?}", input);
+        println!();
+    }
 
+    let lexer_res = lex(input.as_str()).wrap_err(Stage::Lex)?;
+    let tokens = lexer_res.iter().map(|t| &t.node).collect::<Vec<&Token>>();
+    match opts.emit {
+        Emit::Tokens => {
+            match opts.format {
+                Format::Text => println!("{:?}", tokens),
+                Format::Json => println!("{}", serde_json::to_string_pretty(&lexer_res)?),
+            }
+            return Ok(());
+        },
+        Emit::Run => {
+            println!("Lexed tokens:");
+            println!("{:?}", tokens);
+            println!();
+        },
+        _ => {},
+    }
 
-    let parsed_statements = Parser::new(lexer_res).parse();
-    println!("Parsed expression:");
-    println!("{:?}", parsed_statements);
-    println!("");
+    let parsed_statements = parse(lexer_res).wrap_err(Stage::Parse)?;
+    match opts.emit {
+        Emit::Ast => {
+            match opts.format {
+                Format::Text => print!("{}", pretty::print(&parsed_statements)),
+                Format::Json => println!("{}", serde_json::to_string_pretty(&parsed_statements)?),
+            }
+            return Ok(());
+        },
+        Emit::Run => {
+            println!("Parsed expression:");
+            println!("{}", pretty::print(&parsed_statements));
+            println!();
+        },
+        _ => {},
+    }
+    if parsed_statements.is_empty() {
+        println!("no statements to compile");
+        return Ok(());
+    }
+    let native = opts.target != WASM_TRIPLE;
+    if native && matches!(opts.emit, Emit::Wasm | Emit::Wat) {
+        eyre::bail!("`--emit wasm` and `--emit wat` need the {} target", WASM_TRIPLE);
+    }
 
     let context = Context::create();
     let module = context.create_module("tmp");
-    let builder = context.create_builder();
-
-    // Pass manager for functions.
-    let fpm = PassManager::create(&module);
-
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
-    fpm.add_gvn_pass();
-    fpm.add_cfg_simplification_pass();
-    fpm.add_basic_alias_analysis_pass();
-    fpm.add_promote_memory_to_register_pass();
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
-
-    fpm.initialize();
-
-    // TODO: Translate all statements into LLVM IR.
-    let first_stmt = parsed_statements.first().unwrap();
-    let translated = Translator::translate(
-        &context, 
-        &builder, 
-        &fpm, 
-        &module, 
-        &first_stmt,
-    ).unwrap();
-    let result = translated
-        .to_string()
-        .replace("\"", "")
-        .replace("\\n", "\n");
-
-    // Write an IR file to the temporary dir.
-    let mut file = File::create("/tmp/main.ll")?;
-    file.write_all(result.into_bytes().as_slice())?;
-
-    // Execute LLC to translate into an object file targeted at the 
-    // wasm32-unknown-unknown triple.
-    // TODO: Use llvm-sys to programmatically perform the following actions rather than
-    // hardcoding llvm 15 toolchain commands.
-    let mut command = Command::new("llc-15");
-    command.arg("-march=wasm32");
-    command.arg("-filetype=obj");
-    command.arg("/tmp/main.ll");
-    command.arg("-o=/tmp/main.o");
-
-    let Some(0) = command.execute().unwrap() else {
-        panic!("Could not compile bitcode");
+    let opt_level = match opts.debug_info {
+        true => 0,
+        false => opts.opt_level,
     };
-
-    // Execute wasm-ld to translate the bitcode into web assembly.
-    let mut command = Command::new("wasm-ld-15");
-    command.arg("/tmp/main.o");
-    command.arg("-o");
-    command.arg("/tmp/main.wasm");
-    command.arg("--no-entry");
-    // TODO: Do not export all, as it is dangerous.
-    command.arg("--export-all");
-
-    let Some(0) = command.execute().unwrap() else {
-        panic!("Could not compile wasm binary");
+    // Passes are run separately afterwards to snapshot the IR after each.
+    let options = TranslateOptions {
+        opt_level: match opts.dump_passes {
+            true => 0,
+            false => opt_level,
+        },
+        source: opts.debug_info.then_some(opts.input.as_path()),
+        checked_arith: opts.checked_arith,
     };
+    let exports = translate(&context, &module, &parsed_statements, &options).wrap_err(Stage::Codegen)?;
+    if opts.dump_passes {
+        for (pass, ir) in dump_passes(&module, opt_level) {
+            println!("; IR after {}", pass);
+            print!("{}", ir);
+        }
+    }
+    let result = module.print_to_string().to_string();
+    if opts.emit == Emit::LlvmIr {
+        print!("{}", result);
+        return Ok(());
+    }
 
-    let mut command = Command::new("wasm2wat");
-    command.arg("/tmp/main.wasm");
+    if native {
+        return run_native(&context, &module, opts, opt_level, entry_point(&parsed_statements));
+    }
 
-    command.stdout(Stdio::piped());
-    command.stderr(Stdio::piped());
+    let dir = TempDir::new()?;
+    let object_path = dir.path().join("main.o");
+    compile_to_object(&module, &object_path, opt_level, WASM_TRIPLE).wrap_err(Stage::Llc)?;
 
-    let output = command.execute_output()?;
-    let Some(0) = output.status.code() else {
-        panic!("Could not show wat for compiled wasm");
+    let linker = match &opts.linker {
+        Some(linker) => linker.clone(),
+        None => find_tool(WASM_LINKERS).wrap_err(Stage::Link)?,
     };
+    let wasm = link_wasm(&object_path, linker.as_str(), &exports).wrap_err(Stage::Link)?;
+    if opts.emit == Emit::Wasm {
+        let output = match &opts.output {
+            Some(output) => output.clone(),
+            None => opts.input.with_extension("wasm"),
+        };
+        fs::write(&output, &wasm)
+            .wrap_err_with(|| format!("failed to write output file {:?}", output))?;
+        println!("Wrote {:?}", output);
+        return Ok(());
+    }
 
-    let wat_output = String::from_utf8(output.stdout)?;
+    let wat_output = wasm_to_wat(&wasm).wrap_err(Stage::Link)?;
+    if opts.emit == Emit::Wat {
+        print!("{}", wat_output);
+        return Ok(());
+    }
     println!("Compiled wasm to wat:");
     println!("{}", wat_output);
 
-    let mut file = File::create("/tmp/main.wat")?;
-    file.write_all(wat_output.clone().into_bytes().as_slice())?;
-
-    // Running the web assembly module with wasmer;
-    let mut store = wasmer::Store::default();
-    let module = wasmer::Module::new(&store, &wat_output)?;
-    // The module doesn't import anything, so we create an empty import object.
-    let import_object = wasmer::imports! {};
-    let instance = wasmer::Instance::new(&mut store, &module, &import_object)?;
-
-    let safe_sub = instance.exports.get_function("safe_sub")?;
-    println!("Trying inputs 3.0 and 4.0 into safe_sub");
-    let result = safe_sub.call(&mut store, &[wasmer::Value::F64(3.0), wasmer::Value::F64(4.0)])?;
-    println!("{:?}", result[0]);
-    println!("Trying inputs 3.0 and 1.0 into safe_sub");
-    let result = safe_sub.call(&mut store, &[wasmer::Value::F64(3.0), wasmer::Value::F64(1.0)])?;
-    println!("{:?}", result[0]);
+    run_wat(&wat_output, opts, entry_point(&parsed_statements))
+}
+
+/// Links the module into an executable for the chosen target and runs
+/// it, letting it print its own result.
+fn run_native<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    opts: &Opts,
+    opt_level: u8,
+    entry: Option<String>,
+) -> eyre::Result<()> {
+    let Some(func) = opts.call.clone().or(entry) else {
+        println!("{}", NO_ENTRY_POINT);
+        return Ok(());
+    };
+    if !opts.args.is_empty() {
+        eyre::bail!("`--args` can't be passed to native executables");
+    }
+    add_native_main(context, module, func.as_str()).wrap_err(Stage::Codegen)?;
+
+    let dir = TempDir::new()?;
+    let object_path = dir.path().join("main.o");
+    compile_to_object(module, &object_path, opt_level, opts.target.as_str()).wrap_err(Stage::Llc)?;
+    let linker = match &opts.linker {
+        Some(linker) => linker.clone(),
+        None => find_tool(NATIVE_LINKERS).wrap_err(Stage::Link)?,
+    };
+    let output = match &opts.output {
+        Some(output) => output.clone(),
+        None => opts.input.with_extension(""),
+    };
+    link_native(&object_path, linker.as_str(), &output).wrap_err(Stage::Link)?;
+    println!("Wrote {:?}", output);
+
+    println!("Running {}", func);
+    let status = Command::new(&output)
+        .status()
+        .wrap_err_with(|| format!("failed to run {:?}", output))
+        .wrap_err(Stage::Run)?;
+    if !status.success() {
+        return Err(eyre::eyre!("{:?} exited with {}", output, status).wrap_err(Stage::Run));
+    }
+    Ok(())
+}
+
+fn run_wat(wat: &str, opts: &Opts, entry: Option<String>) -> eyre::Result<()> {
+    // Running the web assembly module with wasmer.
+    let Some(func) = opts.call.clone().or(entry) else {
+        println!("{}", NO_ENTRY_POINT);
+        return Ok(());
+    };
+    println!("Running {}", func);
+    let options = RunOptions { fuel: opts.fuel, max_memory_pages: opts.max_memory_pages };
+    let result = run_wasm_with_options(wat.as_bytes(), func.as_str(), &opts.args, &options).wrap_err(Stage::Run)?;
+    report(func.as_str(), result, opts);
     Ok(())
 }
 
+// Prints the value returned by the program in the chosen format, if it
+// returned one, and exits with its sign when asked to. Void functions
+// count as zero.
+fn report(func: &str, result: Option<f64>, opts: &Opts) {
+    match (opts.result_format, result) {
+        (ResultFormat::Json, result) => println!("{}", serde_json::json!({ "func": func, "result": result })),
+        (ResultFormat::Plain, Some(result)) => println!("{}", result),
+        (ResultFormat::Scientific, Some(result)) => println!("{:.6e}", result),
+        (_, None) => {},
+    }
+    if opts.exit_code {
+        std::process::exit(sign_code(result.unwrap_or(0.0)));
+    }
+}
+
+// Exit status for the sign of a result, kept apart from the 1 to 7 used
+// for errors so a negative result can't be taken for a failure. NaN
+// counts as zero.
+fn sign_code(value: f64) -> i32 {
+    if value > 0.0 {
+        return 10;
+    }
+    if value < 0.0 {
+        return 11;
+    }
+    0
+}
@@ -0,0 +1,457 @@
+use std::collections::HashSet;
+
+use crate::parser::{Expr,Param,Stmt,Type};
+use crate::token::Token;
+
+/// A literal value known at compile time. Mirrors how the translator
+/// treats literals: numbers without a fractional part or exponent are
+/// integers, everything else including booleans is a float.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Constant {
+    Int(i64),
+    Float(f64),
+}
+
+impl Constant {
+    pub fn from_literal(value: &str) -> Option<Self> {
+        match value {
+            "true" => Some(Constant::Float(1.0)),
+            "false" => Some(Constant::Float(0.0)),
+            v if v.starts_with("0x") || v.starts_with("0X") => i64::from_str_radix(&v[2..], 16).ok().map(Constant::Int),
+            v if v.contains(&['.', 'e', 'E'][..]) => v.parse::<f64>().ok().map(Constant::Float),
+            v => match v.parse::<i64>() {
+                Ok(i) => Some(Constant::Int(i)),
+                Err(_) => v.parse::<f64>().ok().map(Constant::Float),
+            },
+        }
+    }
+
+    pub fn to_literal(self) -> Expr {
+        let value = match self {
+            Constant::Int(i) => i.to_string(),
+            // Debug formatting always keeps a `.` or exponent so the
+            // literal is read back as a float.
+            Constant::Float(f) => format!("{:?}", f),
+        };
+        Expr::Literal { value }
+    }
+
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Constant::Int(i) => i as f64,
+            Constant::Float(f) => f,
+        }
+    }
+
+    /// Matches the translator, where NaN is not truthy.
+    pub fn is_truthy(self) -> bool {
+        match self {
+            Constant::Int(i) => i != 0,
+            Constant::Float(f) => f != 0.0 && !f.is_nan(),
+        }
+    }
+}
+
+fn bool_constant(value: bool) -> Constant {
+    Constant::Float(if value { 1.0 } else { 0.0 })
+}
+
+/// Returns the constant value of an expression that is a literal,
+/// looking through parentheses.
+pub fn constant(expr: &Expr) -> Option<Constant> {
+    match expr {
+        Expr::Literal { value } => Constant::from_literal(value),
+        Expr::Grouping { expr } => constant(expr),
+        _ => None,
+    }
+}
+
+pub fn fold_statements(statements: &[Box<Stmt>]) -> Vec<Box<Stmt>> {
+    statements
+        .iter()
+        .map(|stmt| Box::new(fold_stmt(stmt)))
+        .collect()
+}
+
+pub fn fold_stmt(stmt: &Stmt) -> Stmt {
+    match stmt {
+        Stmt::Block(statements) => Stmt::Block(fold_statements(statements)),
+        Stmt::Expr(expr) => Stmt::Expr(Box::new(fold_expr(expr))),
+        Stmt::Print(expr) => Stmt::Print(Box::new(fold_expr(expr))),
+        Stmt::Return { keyword, value, span } => Stmt::Return {
+            keyword: keyword.clone(),
+            value: value.as_ref().map(|value| Box::new(fold_expr(value))),
+            span: *span,
+        },
+        Stmt::Function { name, params, return_type, body, span, entry } => Stmt::Function {
+            name: name.clone(),
+            params: params.clone(),
+            return_type: *return_type,
+            body: fold_statements(body),
+            span: *span,
+            entry: *entry,
+        },
+        Stmt::If { cond, then_branch, else_branch } => Stmt::If {
+            cond: Box::new(fold_expr(cond)),
+            then_branch: Box::new(fold_stmt(then_branch)),
+            else_branch: else_branch.as_ref().map(|stmt| Box::new(fold_stmt(stmt))),
+        },
+        Stmt::While { condition, body, increment } => Stmt::While {
+            condition: Box::new(fold_expr(condition)),
+            body: Box::new(fold_stmt(body)),
+            increment: increment.as_ref().map(|expr| Box::new(fold_expr(expr))),
+        },
+        Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Comment { .. } => stmt.clone(),
+        Stmt::Var { name, initializer, span } => Stmt::Var {
+            name: name.clone(),
+            initializer: Box::new(fold_expr(initializer)),
+            span: *span,
+        },
+    }
+}
+
+/// Folds constant subexpressions, leaving anything that depends on a
+/// variable or call untouched.
+pub fn fold_expr(expr: &Expr) -> Expr {
+    match expr {
+        Expr::BinaryExpr { op, left, right } => {
+            let left = fold_expr(left);
+            let right = fold_expr(right);
+            if let (Some(lhs), Some(rhs)) = (constant(&left), constant(&right)) {
+                if let Some(folded) = fold_binary(op, lhs, rhs) {
+                    return folded.to_literal();
+                }
+            }
+            Expr::BinaryExpr { op: op.clone(), left: Box::new(left), right: Box::new(right) }
+        },
+        Expr::UnaryExpr { op, right } => {
+            let right = fold_expr(right);
+            let folded = match (op, constant(&right)) {
+                (Token::Minus, Some(Constant::Int(i))) => Some(Constant::Int(i.wrapping_neg())),
+                (Token::Minus, Some(Constant::Float(f))) => Some(Constant::Float(-f)),
+                (Token::Bang, Some(c)) => Some(bool_constant(!c.is_truthy())),
+                _ => None,
+            };
+            match folded {
+                Some(folded) => folded.to_literal(),
+                None => Expr::UnaryExpr { op: op.clone(), right: Box::new(right) },
+            }
+        },
+        Expr::Logical { op, left, right } => {
+            let left = fold_expr(left);
+            let right = fold_expr(right);
+            // The right operand is only dropped when the left decides the
+            // result, as it would never have been evaluated.
+            let folded = match (op, constant(&left), constant(&right)) {
+                (Token::And, Some(lhs), _) if !lhs.is_truthy() => Some(bool_constant(false)),
+                (Token::Or, Some(lhs), _) if lhs.is_truthy() => Some(bool_constant(true)),
+                (Token::And | Token::Or, Some(_), Some(rhs)) => Some(bool_constant(rhs.is_truthy())),
+                _ => None,
+            };
+            match folded {
+                Some(folded) => folded.to_literal(),
+                None => Expr::Logical { op: op.clone(), left: Box::new(left), right: Box::new(right) },
+            }
+        },
+        Expr::Ternary { cond, then, els } => {
+            let cond = fold_expr(cond);
+            let then = fold_expr(then);
+            let els = fold_expr(els);
+            // The branches are converted to floats when they are joined.
+            let pick = |branch: Expr| match constant(&branch) {
+                Some(c) => Constant::Float(c.as_f64()).to_literal(),
+                None => branch,
+            };
+            match constant(&cond) {
+                Some(c) if c.is_truthy() => pick(then),
+                Some(_) => pick(els),
+                None => Expr::Ternary { cond: Box::new(cond), then: Box::new(then), els: Box::new(els) },
+            }
+        },
+        Expr::Grouping { expr } => {
+            let expr = fold_expr(expr);
+            match constant(&expr) {
+                Some(folded) => folded.to_literal(),
+                None => Expr::Grouping { expr: Box::new(expr) },
+            }
+        },
+        Expr::Assign { name, value, span } => Expr::Assign {
+            name: name.clone(),
+            value: Box::new(fold_expr(value)),
+            span: *span,
+        },
+        Expr::Call { callee, paren, args } => Expr::Call {
+            callee: callee.clone(),
+            paren: paren.clone(),
+            args: args.iter().map(|arg| Box::new(fold_expr(arg))).collect(),
+        },
+        Expr::Index { array, index, span } => Expr::Index {
+            array: Box::new(fold_expr(array)),
+            index: Box::new(fold_expr(index)),
+            span: *span,
+        },
+        Expr::SetIndex { array, index, value, span } => Expr::SetIndex {
+            array: Box::new(fold_expr(array)),
+            index: Box::new(fold_expr(index)),
+            value: Box::new(fold_expr(value)),
+            span: *span,
+        },
+        Expr::Get { object, name } => Expr::Get { object: Box::new(fold_expr(object)), name: name.clone() },
+        Expr::Literal { .. } | Expr::StringLiteral { .. } | Expr::Variable { .. } => expr.clone(),
+    }
+}
+
+/// Applies a binary operator the way the translator does. Returns None
+/// for anything the translator would reject or that has no well defined
+/// result, such as an integer remainder by zero. Dividing integers gives
+/// a float, as in the translator.
+pub fn fold_binary(op: &Token, lhs: Constant, rhs: Constant) -> Option<Constant> {
+    if let (Constant::Int(l), Constant::Int(r)) = (lhs, rhs) {
+        return match op {
+            Token::Plus => Some(Constant::Int(l.wrapping_add(r))),
+            Token::Minus => Some(Constant::Int(l.wrapping_sub(r))),
+            Token::Times => Some(Constant::Int(l.wrapping_mul(r))),
+            Token::Div => Some(Constant::Float(l as f64 / r as f64)),
+            Token::Percent => l.checked_rem(r).map(Constant::Int),
+            Token::Amp => Some(Constant::Int(l & r)),
+            Token::Pipe => Some(Constant::Int(l | r)),
+            Token::Caret => Some(Constant::Int(l ^ r)),
+            Token::ShiftLeft => u32::try_from(r).ok().and_then(|r| l.checked_shl(r)).map(Constant::Int),
+            Token::ShiftRight => u32::try_from(r).ok().and_then(|r| l.checked_shr(r)).map(Constant::Int),
+            Token::StarStar => Some(Constant::Float((l as f64).powf(r as f64))),
+            Token::Less => Some(bool_constant(l < r)),
+            Token::Greater => Some(bool_constant(l > r)),
+            Token::Geq => Some(bool_constant(l >= r)),
+            Token::Leq => Some(bool_constant(l <= r)),
+            Token::Eqq => Some(bool_constant(l == r)),
+            Token::BangEq => Some(bool_constant(l != r)),
+            _ => None,
+        };
+    }
+
+    let (l, r) = (lhs.as_f64(), rhs.as_f64());
+    match op {
+        Token::Plus => Some(Constant::Float(l + r)),
+        Token::Minus => Some(Constant::Float(l - r)),
+        Token::Times => Some(Constant::Float(l * r)),
+        Token::Div => Some(Constant::Float(l / r)),
+        Token::Percent => Some(Constant::Float(l % r)),
+        Token::StarStar => Some(Constant::Float(l.powf(r))),
+        // Comparisons with NaN are false, except `!=` which is true, as
+        // with the translator's predicates.
+        Token::Less => Some(bool_constant(l < r)),
+        Token::Greater => Some(bool_constant(l > r)),
+        Token::Geq => Some(bool_constant(l >= r)),
+        Token::Leq => Some(bool_constant(l <= r)),
+        Token::Eqq => Some(bool_constant(l == r)),
+        Token::BangEq => Some(bool_constant(l != r)),
+        _ => None,
+    }
+}
+
+/// Returns the names of the variables of a function body that only ever
+/// hold integers, along with its `int` parameters. Other variables are
+/// stored as floats even when declared with an integer, so assigning a
+/// float to them later keeps its fraction. Names are not told apart by
+/// scope, a name assigned a float anywhere in the body is a float.
+pub fn int_variables(params: &[Param], body: &[Box<Stmt>]) -> HashSet<String> {
+    let mut bindings = vec!();
+    let mut assignments = vec!();
+    for stmt in body.iter() {
+        collect_stmt(stmt, &mut bindings, &mut assignments);
+    }
+    let mut ints = params
+        .iter()
+        .filter(|param| param.ty() == Type::Int)
+        .map(|param| name_of(&param.name))
+        .chain(bindings.iter().map(|(name, _)| name.clone()))
+        .collect::<HashSet<String>>();
+    for param in params.iter().filter(|param| param.ty() != Type::Int) {
+        ints.remove(name_of(&param.name).as_str());
+    }
+    // Dropping a name can make other values floats, so repeat until
+    // nothing changes.
+    loop {
+        let floats = bindings
+            .iter()
+            .chain(assignments.iter())
+            .filter(|(name, value)| ints.contains(name.as_str()) && !is_int(value, &ints))
+            .map(|(name, _)| name.clone())
+            .collect::<HashSet<String>>();
+        if floats.is_empty() {
+            return ints;
+        }
+        ints.retain(|name| !floats.contains(name.as_str()));
+    }
+}
+
+fn name_of(token: &Token) -> String {
+    match token {
+        Token::Ident(id) => id.clone(),
+        tok => tok.to_string(),
+    }
+}
+
+// Whether an expression always gives an integer, given the variables
+// that hold integers.
+fn is_int(expr: &Expr, ints: &HashSet<String>) -> bool {
+    match expr {
+        Expr::Literal { value } => matches!(Constant::from_literal(value), Some(Constant::Int(_))),
+        Expr::Grouping { expr } => is_int(expr, ints),
+        Expr::Variable { name, .. } => ints.contains(name_of(name).as_str()),
+        Expr::Assign { name, value, .. } => ints.contains(name_of(name).as_str()) && is_int(value, ints),
+        Expr::UnaryExpr { op: Token::Minus, right } => is_int(right, ints),
+        Expr::BinaryExpr { op, left, right } => matches!(
+            op,
+            Token::Plus
+            | Token::Minus
+            | Token::Times
+            | Token::Percent
+            | Token::Amp
+            | Token::Pipe
+            | Token::Caret
+            | Token::ShiftLeft
+            | Token::ShiftRight
+        ) && is_int(left, ints) && is_int(right, ints),
+        _ => false,
+    }
+}
+
+type Binding<'a> = (String, &'a Expr);
+
+// Gathers the declarations and assignments of a body, without looking
+// into nested functions.
+fn collect_stmt<'a>(stmt: &'a Stmt, bindings: &mut Vec<Binding<'a>>, assignments: &mut Vec<Binding<'a>>) {
+    match stmt {
+        Stmt::Block(statements) => {
+            for stmt in statements.iter() {
+                collect_stmt(stmt, bindings, assignments);
+            }
+        },
+        Stmt::Expr(expr) | Stmt::Print(expr) => collect_expr(expr, assignments),
+        Stmt::Return { value, .. } => {
+            if let Some(value) = value {
+                collect_expr(value, assignments);
+            }
+        },
+        Stmt::If { cond, then_branch, else_branch } => {
+            collect_expr(cond, assignments);
+            collect_stmt(then_branch, bindings, assignments);
+            if let Some(else_branch) = else_branch {
+                collect_stmt(else_branch, bindings, assignments);
+            }
+        },
+        Stmt::While { condition, body, increment } => {
+            collect_expr(condition, assignments);
+            collect_stmt(body, bindings, assignments);
+            if let Some(increment) = increment {
+                collect_expr(increment, assignments);
+            }
+        },
+        Stmt::Var { name, initializer, .. } => {
+            collect_expr(initializer, assignments);
+            bindings.push((name_of(name), initializer));
+        },
+        Stmt::Function { .. } | Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Comment { .. } => {},
+    }
+}
+
+fn collect_expr<'a>(expr: &'a Expr, assignments: &mut Vec<Binding<'a>>) {
+    match expr {
+        Expr::Assign { name, value, .. } => {
+            collect_expr(value, assignments);
+            assignments.push((name_of(name), value));
+        },
+        Expr::Grouping { expr } | Expr::UnaryExpr { right: expr, .. } | Expr::Get { object: expr, .. } => {
+            collect_expr(expr, assignments);
+        },
+        Expr::BinaryExpr { left, right, .. } | Expr::Logical { left, right, .. } => {
+            collect_expr(left, assignments);
+            collect_expr(right, assignments);
+        },
+        Expr::Ternary { cond, then, els } => {
+            collect_expr(cond, assignments);
+            collect_expr(then, assignments);
+            collect_expr(els, assignments);
+        },
+        Expr::Call { args, .. } => {
+            for arg in args.iter() {
+                collect_expr(arg, assignments);
+            }
+        },
+        Expr::Index { array, index, .. } => {
+            collect_expr(array, assignments);
+            collect_expr(index, assignments);
+        },
+        Expr::SetIndex { array, index, value, .. } => {
+            collect_expr(array, assignments);
+            collect_expr(index, assignments);
+            collect_expr(value, assignments);
+        },
+        Expr::Literal { .. } | Expr::StringLiteral { .. } | Expr::Variable { .. } => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_str,pretty};
+
+    // Folds a single expression and prints the result.
+    fn fold(source: &str) -> String {
+        let statements = parse_str(&format!("{};", source)).unwrap();
+        match statements[0].as_ref() {
+            Stmt::Expr(expr) => pretty::expr(&fold_expr(expr)),
+            stmt => panic!("expected an expression, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn folds_arithmetic() {
+        let statements = parse_str("2 + 3 * 4;").unwrap();
+        let Stmt::Expr(expr) = fold_stmt(&statements[0]) else {
+            panic!("expected an expression");
+        };
+        assert!(matches!(*expr, Expr::Literal { ref value } if value == "14"), "{:?}", expr);
+
+        assert_eq!(fold("(1 + 2) * 3"), "9");
+        assert_eq!(fold("7 / 2"), "3.5");
+        assert_eq!(fold("8 / 2"), "4.0");
+        assert_eq!(fold("7.0 / 2"), "3.5");
+        assert_eq!(fold("-(2 - 5)"), "3");
+        assert_eq!(fold("1 << 4 | 1"), "17");
+    }
+
+    #[test]
+    fn folds_comparisons() {
+        assert_eq!(fold("1 < 2"), "1.0");
+        assert_eq!(fold("2 <= 1"), "0.0");
+        assert_eq!(fold("3 == 3.0"), "1.0");
+        assert_eq!(fold("!(1 != 1)"), "1.0");
+    }
+
+    #[test]
+    fn folds_short_circuits() {
+        // The left operand decides, the right is never evaluated.
+        assert_eq!(fold("false and f()"), "0.0");
+        assert_eq!(fold("true or f()"), "1.0");
+        assert_eq!(fold("true and false"), "0.0");
+        // The right operand has to run.
+        assert_eq!(fold("true and f()"), "true and f()");
+    }
+
+    #[test]
+    fn leaves_variables_alone() {
+        assert_eq!(fold("x + 0"), "x + 0");
+        assert_eq!(fold("x * (2 + 3)"), "x * 5");
+    }
+
+    #[test]
+    fn no_fold_on_remainder_by_zero() {
+        assert_eq!(fold("1 % 0"), "1 % 0");
+        assert_eq!(fold_binary(&Token::Percent, Constant::Int(1), Constant::Int(0)), None);
+        // Division is always a float division, with a well defined result.
+        assert_eq!(fold_binary(&Token::Div, Constant::Int(1), Constant::Int(0)), Some(Constant::Float(f64::INFINITY)));
+        assert_eq!(fold_binary(&Token::Div, Constant::Float(1.0), Constant::Int(0)), Some(Constant::Float(f64::INFINITY)));
+    }
+}
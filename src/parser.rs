@@ -1,73 +1,86 @@
-use crate::token::Token;
-
-#[derive(Debug,Clone)]
-pub enum Expr {
-    BinaryExpr {
-        op: Token,
-        left: Box<Expr>,
-        right: Box<Expr>,
-    },
-    UnaryExpr {
-        op: Token,
-        right: Box<Expr>,
+use thiserror::Error;
+
+pub use crate::ast::{Expr,Param,Stmt,Type};
+use crate::pretty;
+use crate::token::{Span,Spanned,Token};
+
+#[derive(Debug,Error)]
+pub enum ParseError {
+    #[error("expected '{expected}' at {span}, found '{found}'")]
+    UnexpectedToken {
+        expected: Token,
+        found: Token,
+        span: Span,
     },
-    Logical {
-        op: Token,
-        left: Box<Expr>,
-        right: Box<Expr>,
+    #[error("expected identifier at {span}, found '{found}'")]
+    ExpectedIdentifier {
+        found: Token,
+        span: Span,
     },
-    Grouping {
-        expr: Box<Expr>,
+    #[error("cannot use keyword '{keyword}' as a name at {span}")]
+    KeywordAsName {
+        keyword: Token,
+        span: Span,
     },
-    Literal {
-        value: String,
+    #[error("expected expression at {span}, found '{found}'")]
+    ExpectedExpression {
+        found: Token,
+        span: Span,
     },
-    Assign {
-        name: Token,
-        value: Box<Expr>,
+    #[error("invalid assignment target at {span}")]
+    InvalidAssignment {
+        span: Span,
     },
-    Variable {
-        name: Token,
+    #[error("unexpected '==' in variable declaration at {span}, did you mean '='?")]
+    EqualityInDeclaration {
+        span: Span,
     },
-    Call {
-        callee: Box<Expr>,
-        paren: Token,
-        args: Vec<Box<Expr>>,
+    #[error("comparisons can't be chained at {span}, did you mean `{suggestion}`?")]
+    ChainedComparison {
+        suggestion: String,
+        span: Span,
     },
-}
-
-#[derive(Debug,Clone)]
-pub enum Stmt {
-    Block(Vec<Box<Stmt>>),
-    Expr(Box<Expr>),
-    Return {
+    #[error("'{keyword}' outside of a loop at {span}")]
+    OutsideLoop {
         keyword: Token,
-        value: Option<Box<Expr>>,
+        span: Span,
     },
-    Function {
+    #[error("unknown type '{name}' at {span}, expected 'int' or 'float'")]
+    UnknownType {
         name: Token,
-        params: Vec<Token>,
-        body: Vec<Box<Stmt>>,
-    },
-    If {
-        cond: Box<Expr>,
-        then_branch: Box<Stmt>,
-        else_branch: Option<Box<Stmt>>,
+        span: Span,
     },
-    While {
-        condition: Box<Expr>,
-        body: Box<Stmt>,
-    },
-    Var {
-        name: Token,
-        initializer: Box<Expr>,
+    #[error("input nested too deeply at {span}")]
+    TooDeep {
+        span: Span,
     },
 }
 
+pub type ParseResult<T> = Result<T, ParseError>;
+
+/// How deeply statements and expressions can nest before parsing gives up,
+/// rather than overflowing the stack. Every level of parentheses goes
+/// through each precedence rule, so this stays low enough for the 2 MiB
+/// stack of a spawned thread in a debug build.
+pub const MAX_DEPTH: usize = 64;
+
 #[derive(Debug)]
-pub struct Parser {
-    pub tokens: Vec<Token>,
-    current: usize,
+/// Parses tokens pulled lazily from any iterator, such as a `TokenLexer`,
+/// holding on to just the token before and after the current position.
+pub struct Parser<I: Iterator<Item = Spanned<Token>>> {
+    tokens: I,
+    previous: Option<Spanned<Token>>,
+    next: Option<Spanned<Token>>,
+    // Number of loops around the statement being parsed.
+    loop_depth: usize,
+    // Number of nested statements and expressions being parsed.
+    depth: usize,
+    // Whether comments are turned into `Stmt::Comment`, and the ones
+    // collected from consumed tokens but not placed yet.
+    keep_comments: bool,
+    comments: Vec<String>,
+    // Errors recovered from so far, in the order they were found.
+    errors: Vec<ParseError>,
 }
 
 
@@ -77,211 +90,369 @@ macro_rules! bin_expr {
     };
 }
 
-impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0 }
+impl<I: Iterator<Item = Spanned<Token>>> Parser<I> {
+    pub fn new(mut tokens: I) -> Self {
+        let next = tokens.next();
+        Self { tokens, previous: None, next, loop_depth: 0, depth: 0, keep_comments: false, comments: vec!(), errors: vec!() }
+    }
+    /// Parser that keeps comments as `Stmt::Comment`, for the formatter.
+    /// Comments on their own lines go before the statement they precede or
+    /// are inside of, and a comment after a statement on its last line
+    /// trails it.
+    pub fn with_comments(tokens: I) -> Self {
+        let mut parser = Self::new(tokens);
+        parser.keep_comments = true;
+        parser
+    }
+    /// Parses the whole token stream. Errors don't stop parsing, the parser
+    /// skips to the next statement and carries on so that every error in
+    /// the input is reported at once. A bad statement inside a block only
+    /// loses that statement, the rest of the block is still parsed.
+    pub fn parse(&mut self) -> Result<Vec<Box<Stmt>>, Vec<ParseError>> {
+        let (statements, errors) = self.parse_recovering();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        return Ok(statements);
     }
-    pub fn parse(&mut self) -> Vec<Box<Stmt>> {
+    /// Same as `parse`, also returning the statements that did parse when
+    /// there are errors.
+    pub fn parse_recovering(&mut self) -> (Vec<Box<Stmt>>, Vec<ParseError>) {
         let mut statements = vec!();
         while !self.is_at_end() {
-            statements.push(self.declaration());
+            match self.commented_declaration() {
+                Ok(stmts) => statements.extend(stmts),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(false);
+                },
+            }
+        }
+        self.collect_comments();
+        statements.extend(self.pending_comments());
+        return (statements, std::mem::take(&mut self.errors));
+    }
+    /// Parses a declaration along with the comments before, inside and
+    /// trailing it, when comments are kept.
+    pub fn commented_declaration(&mut self) -> ParseResult<Vec<Box<Stmt>>> {
+        if !self.keep_comments {
+            return Ok(vec!(self.declaration()?));
+        }
+        self.collect_comments();
+        let mut statements = self.pending_comments();
+        let stmt = self.declaration()?;
+        statements.extend(self.pending_comments());
+        statements.push(stmt);
+        if let Some(text) = self.previous.as_mut().and_then(|tok| tok.trailing_trivia.take()) {
+            statements.push(Box::new(Stmt::Comment { text, trailing: true }));
         }
-        return statements;
+        return Ok(statements);
     }
-    pub fn declaration(&mut self) -> Box<Stmt> {
+    pub fn declaration(&mut self) -> ParseResult<Box<Stmt>> {
         if self.check_match(vec!(Token::Fun)) { 
-            return self.function_declaration();
+            return self.function_declaration(false);
+        }
+        if self.check_match(vec!(Token::Wagmi)) {
+            // `wagmi` can go before `fun` or replace it.
+            self.check_match(vec!(Token::Fun));
+            return self.function_declaration(true);
         }
         if self.check_match(vec!(Token::Var)) {
             return self.variable_declaration();
         }
         self.statement()
     }
-    pub fn function_declaration(&mut self) -> Box<Stmt> {
-        let name = self.consume_identifier();
-        self.consume(Token::LParen);
+    pub fn function_declaration(&mut self, entry: bool) -> ParseResult<Box<Stmt>> {
+        let name = self.consume_identifier()?;
+        let span = self.previous_span();
+        self.consume(Token::LParen)?;
         let mut params = vec![];
-        if !self.check_match(vec!(Token::RParen)) {
-            params.push(self.consume_identifier());
+        if !self.check(Token::RParen) {
+            params.push(self.param()?);
             while self.check_match(vec!(Token::Comma)) {
-                params.push(self.consume_identifier());
+                params.push(self.param()?);
             }
         }
-        self.consume(Token::RParen);
-        self.consume(Token::LBrace);
+        self.consume(Token::RParen)?;
+        let mut return_type = None;
+        if self.check_match(vec!(Token::Arrow)) {
+            return_type = Some(self.type_annotation()?);
+        }
+        self.consume(Token::LBrace)?;
+        // Loops don't reach into a function defined inside them.
+        let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
         let body = self.block();
-        Box::new(Stmt::Function { name, params, body })
+        self.loop_depth = loop_depth;
+        Ok(Box::new(Stmt::Function { name, params, return_type, body: body?, span, entry }))
+    }
+    pub fn param(&mut self) -> ParseResult<Param> {
+        let name = self.consume_identifier()?;
+        let mut ty = None;
+        if self.check_match(vec!(Token::Colon)) {
+            ty = Some(self.type_annotation()?);
+        }
+        Ok(Param { name, ty })
     }
-    pub fn consume_identifier(&mut self) -> Token {
+    pub fn type_annotation(&mut self) -> ParseResult<Type> {
+        let name = self.consume_identifier()?;
+        match &name {
+            Token::Ident(ty) if ty == "int" => Ok(Type::Int),
+            Token::Ident(ty) if ty == "float" => Ok(Type::Float),
+            _ => Err(ParseError::UnknownType { name, span: self.previous_span() }),
+        }
+    }
+    pub fn consume_identifier(&mut self) -> ParseResult<Token> {
         match self.peek() {
             Token::Ident(_) => {
                 self.advance();
-                self.previous()
+                Ok(self.previous())
             },
-            _ => panic!("cannot match")
+            keyword if keyword.is_keyword() => Err(ParseError::KeywordAsName { keyword, span: self.peek_span() }),
+            found => Err(ParseError::ExpectedIdentifier { found, span: self.peek_span() }),
         }
     }
-    pub fn variable_declaration(&mut self) -> Box<Stmt> {
-        let name = match self.peek() {
-            Token::Ident(_) => {
-                self.advance();
-                self.previous()
-            },
-            _ => panic!("cannot match")
-        };
+    pub fn variable_declaration(&mut self) -> ParseResult<Box<Stmt>> {
+        let span = self.previous_span();
+        let name = self.consume_identifier()?;
         let mut initializer = Expr::Literal { value: "false".to_string() };
+        if self.check(Token::Eqq) {
+            return Err(ParseError::EqualityInDeclaration { span: self.peek_span() });
+        }
         if self.check_match(vec!(Token::Eq)) {
-            initializer = self.expression();
+            initializer = self.expression()?;
         }
-        self.consume(Token::Semicolon);
-        Box::new(Stmt::Var{ name, initializer: Box::new(initializer) })
+        self.consume(Token::Semicolon)?;
+        Ok(Box::new(Stmt::Var{ name, initializer: Box::new(initializer), span }))
     }
-    pub fn statement(&mut self) -> Box<Stmt> {
+    pub fn statement(&mut self) -> ParseResult<Box<Stmt>> {
         if self.check_match(vec!(Token::For)) {
             return self.for_statement();
         }
         if self.check_match(vec!(Token::If)) {
-            return Box::new(self.if_statement());
+            return Ok(Box::new(self.if_statement()?));
         }
         if self.check_match(vec!(Token::Return)) {
-            return Box::new(self.return_statement());
+            return Ok(Box::new(self.return_statement()?));
+        }
+        if self.check_match(vec!(Token::Print)) {
+            return Ok(Box::new(self.print_statement()?));
         }
         if self.check_match(vec!(Token::While)) {
-            return Box::new(self.while_statement());
+            return Ok(Box::new(self.while_statement()?));
+        }
+        if self.check_match(vec!(Token::Break, Token::Continue)) {
+            return Ok(Box::new(self.loop_jump()?));
         }
         if self.check_match(vec!(Token::LBrace)) {
-            return Box::new(Stmt::Block(self.block()));
+            return Ok(Box::new(Stmt::Block(self.block()?)));
         }
-        let expr = self.expression_statement();
-        Box::new(expr)
+        let expr = self.expression_statement()?;
+        Ok(Box::new(expr))
     }
-    pub fn for_statement(&mut self) -> Box<Stmt> {
-        self.consume(Token::LParen);
+    pub fn for_statement(&mut self) -> ParseResult<Box<Stmt>> {
+        self.consume(Token::LParen)?;
         let initializer: Option<Box<Stmt>>;
         if self.check_match(vec!(Token::Semicolon)) {
             initializer = None;
         } else if self.check_match(vec!(Token::Var)) {
-            initializer = Some(self.variable_declaration());
+            initializer = Some(self.variable_declaration()?);
         } else {
-            initializer = Some(Box::new(self.expression_statement()));
+            initializer = Some(Box::new(self.expression_statement()?));
         }
 
         let mut cond: Option<Expr> = None;
-        if !self.check_match(vec!(Token::Semicolon)) {
-            cond = Some(self.expression());
+        if !self.check(Token::Semicolon) {
+            cond = Some(self.expression()?);
         }
-        self.consume(Token::Semicolon);
+        self.consume(Token::Semicolon)?;
 
         let mut increment: Option<Expr> = None;
-        if !self.check_match(vec!(Token::RParen)) {
-            increment = Some(self.expression());
+        if !self.check(Token::RParen) {
+            increment = Some(self.expression()?);
         }
-        self.consume(Token::RParen);
+        self.consume(Token::RParen)?;
 
-        let mut body = self.statement();
-        if increment.is_some() {
-            let expr = Stmt::Expr(Box::new(increment.unwrap()));
-            body = Box::new(Stmt::Block(vec![body, Box::new(expr)]));
+        let mut body = self.loop_body()?;
+        // The desugared loop always has a block body, whatever was written.
+        if !matches!(*body, Stmt::Block(_)) {
+            body = Box::new(Stmt::Block(vec!(body)));
         }
-
-        if cond.is_some() {
+        if cond.is_none() {
             cond = Some(Expr::Literal { value: "true".to_string() });
         }
 
-        body = Box::new(Stmt::While { condition: Box::new(cond.unwrap()), body });
-        if initializer.is_some() {
-            body = Box::new(Stmt::Block(vec![initializer.unwrap(), body]));
+        body = Box::new(Stmt::While {
+            condition: Box::new(cond.unwrap()),
+            body,
+            increment: increment.map(Box::new),
+        });
+        if let Some(initializer) = initializer {
+            body = Box::new(Stmt::Block(vec![initializer, body]));
         }
-        body
+        Ok(body)
     }
-    pub fn if_statement(&mut self) -> Stmt  {
-        self.consume(Token::LParen);
-        let cond = self.expression();
-        self.consume(Token::RParen);
-        let then_branch = self.statement();
+    pub fn if_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(Token::LParen)?;
+        let cond = self.expression()?;
+        self.consume(Token::RParen)?;
+        let then_branch = self.nested(Self::statement)?;
         let mut else_branch = None;
         if self.check_match(vec!(Token::Else)) {
-            else_branch = Some(self.statement());
+            else_branch = Some(self.nested(Self::statement)?);
         }
-        Stmt::If { cond: Box::new(cond), then_branch, else_branch }
+        Ok(Stmt::If { cond: Box::new(cond), then_branch, else_branch })
     }
-    pub fn return_statement(&mut self) -> Stmt  {
+    pub fn return_statement(&mut self) -> ParseResult<Stmt> {
         let keyword = self.previous();
+        let span = self.previous_span();
         let mut value = None;
-        if !self.check_match(vec!(Token::Semicolon)) {
-            value = Some(Box::new(self.expression()));
+        if !self.check(Token::Semicolon) {
+            value = Some(Box::new(self.expression()?));
         }
-        self.consume(Token::Semicolon);
-        Stmt::Return { keyword, value }
+        self.consume(Token::Semicolon)?;
+        Ok(Stmt::Return { keyword, value, span })
+    }
+    pub fn print_statement(&mut self) -> ParseResult<Stmt> {
+        let value = self.expression()?;
+        self.consume(Token::Semicolon)?;
+        Ok(Stmt::Print(Box::new(value)))
+    }
+    pub fn while_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(Token::LParen)?;
+        let cond = self.expression()?;
+        self.consume(Token::RParen)?;
+        let body = self.loop_body()?;
+        return Ok(Stmt::While { condition: Box::new(cond), body, increment: None })
+    }
+    fn loop_body(&mut self) -> ParseResult<Box<Stmt>> {
+        self.loop_depth += 1;
+        let body = self.nested(Self::statement);
+        self.loop_depth -= 1;
+        body
     }
-    pub fn while_statement(&mut self) -> Stmt  {
-        self.consume(Token::LParen);
-        let cond = self.expression();
-        self.consume(Token::RParen);
-        let body = self.statement();
-        return Stmt::While { condition: Box::new(cond), body }
+    pub fn loop_jump(&mut self) -> ParseResult<Stmt> {
+        let keyword = self.previous();
+        let span = self.previous_span();
+        if self.loop_depth == 0 {
+            return Err(ParseError::OutsideLoop { keyword, span });
+        }
+        self.consume(Token::Semicolon)?;
+        match keyword {
+            Token::Break => Ok(Stmt::Break { span }),
+            _ => Ok(Stmt::Continue { span }),
+        }
     }
-    pub fn block(&mut self) -> Vec<Box<Stmt>> {
+    pub fn block(&mut self) -> ParseResult<Vec<Box<Stmt>>> {
         let mut statements = vec!();
-        while !self.check_match(vec!(Token::RBrace)) && !self.is_at_end() {
-            statements.push(self.declaration());
+        while !self.check(Token::RBrace) && !self.is_at_end() {
+            match self.nested(Self::commented_declaration) {
+                Ok(stmts) => statements.extend(stmts),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(true);
+                },
+            }
         }
-        self.consume(Token::RBrace);
-        return statements;
+        self.collect_comments();
+        statements.extend(self.pending_comments());
+        self.consume(Token::RBrace)?;
+        return Ok(statements);
     }
-    pub fn expression_statement(&mut self) -> Stmt {
-        let value = self.expression();
-        self.consume(Token::Semicolon);
-        Stmt::Expr(Box::new(value))
+    pub fn expression_statement(&mut self) -> ParseResult<Stmt> {
+        let value = self.expression()?;
+        self.consume(Token::Semicolon)?;
+        Ok(Stmt::Expr(Box::new(value)))
     }
-    pub fn expression(&mut self) -> Expr {
-        return self.assignment();
+    pub fn expression(&mut self) -> ParseResult<Expr> {
+        return self.nested(Self::assignment);
     }
-    pub fn assignment(&mut self) -> Expr {
-        let expr = self.or();
-        if self.check_match(vec!(Token::Eq)) {
-            let value = self.assignment();
+    pub fn assignment(&mut self) -> ParseResult<Expr> {
+        let expr = self.ternary()?;
+        if self.check_match(vec!(
+            Token::Eq,
+            Token::PlusEq,
+            Token::MinusEq,
+            Token::TimesEq,
+            Token::DivEq,
+            Token::PercentEq,
+            Token::AmpEq,
+            Token::PipeEq,
+            Token::CaretEq,
+            Token::ShiftLeftEq,
+            Token::ShiftRightEq,
+        )) {
+            let op = self.previous();
+            let span = self.previous_span();
+            let value = self.nested(Self::assignment)?;
+            // `x += e` desugars to `x = x + e`.
+            let op = match op {
+                Token::PlusEq => Some(Token::Plus),
+                Token::MinusEq => Some(Token::Minus),
+                Token::TimesEq => Some(Token::Times),
+                Token::DivEq => Some(Token::Div),
+                Token::PercentEq => Some(Token::Percent),
+                Token::AmpEq => Some(Token::Amp),
+                Token::PipeEq => Some(Token::Pipe),
+                Token::CaretEq => Some(Token::Caret),
+                Token::ShiftLeftEq => Some(Token::ShiftLeft),
+                Token::ShiftRightEq => Some(Token::ShiftRight),
+                _ => None,
+            };
+            let value = match op {
+                Some(op) => bin_expr!(expr.clone(), op, value),
+                None => value,
+            };
             return match expr {
-                Expr::Variable { name } => {
-                    Expr::Assign { name, value: Box::new(value) } 
-                },
-                _ => panic!("invalid assignment"),
-            }
+                Expr::Variable { name, span } => Ok(Expr::Assign { name, value: Box::new(value), span }),
+                Expr::Index { array, index, span } => Ok(Expr::SetIndex { array, index, value: Box::new(value), span }),
+                _ => Err(ParseError::InvalidAssignment { span }),
+            };
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn or(&mut self) -> Expr {
-        let mut expr = self.and();
+    pub fn ternary(&mut self) -> ParseResult<Expr> {
+        let expr = self.or()?;
+        if self.check_match(vec!(Token::Question)) {
+            let then = self.expression()?;
+            self.consume(Token::Colon)?;
+            // Right associative, `a ? b : c ? d : e` nests in the else.
+            let els = self.nested(Self::ternary)?;
+            return Ok(Expr::Ternary { cond: Box::new(expr), then: Box::new(then), els: Box::new(els) });
+        }
+        return Ok(expr);
+    }
+    pub fn or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.and()?;
         while self.check_match(vec!(Token::Or)) {
             let op = self.previous();
-            let right = self.and();
+            let right = self.and()?;
             expr = Expr::Logical { left: Box::new(expr), op, right: Box::new(right) }
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn and(&mut self) -> Expr {
-        let mut expr = self.equality();
+    pub fn and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.equality()?;
         while self.check_match(vec!(Token::And)) {
             let op = self.previous();
-            let right = self.equality();
+            let right = self.equality()?;
             expr = Expr::Logical { left: Box::new(expr), op, right: Box::new(right) }
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn equality(&mut self) -> Expr {
-        let mut expr = self.comparison();
+    pub fn equality(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.comparison()?;
         while self.check_match(vec!(
             Token::Eqq, 
             Token::BangEq,
         )) {
             let op = self.previous();
-            let right = self.comparison();
+            let right = self.comparison()?;
             expr = bin_expr!(expr, op, right);
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn comparison(&mut self) -> Expr {
-        let mut expr = self.term();
+    pub fn comparison(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.bitwise()?;
         while self.check_match(vec!(
             Token::Greater, 
             Token::Geq, 
@@ -289,96 +460,210 @@ impl Parser {
             Token::Leq,
         )) {
             let op = self.previous();
-            let right = self.term();
+            let span = self.previous_span();
+            let right = self.bitwise()?;
+            // `a < b < c` would compare the boolean `a < b` against `c`,
+            // which is almost never what was meant.
+            if let Expr::BinaryExpr { op: Token::Greater | Token::Geq | Token::Less | Token::Leq, right: middle, .. } = &expr {
+                let suggestion = format!(
+                    "{} and {} {} {}",
+                    pretty::expr(&expr),
+                    pretty::expr(middle),
+                    op,
+                    pretty::expr(&right),
+                );
+                return Err(ParseError::ChainedComparison { suggestion, span });
+            }
+            expr = bin_expr!(expr, op, right);
+        }
+        return Ok(expr);
+    }
+    pub fn bitwise(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.term()?;
+        while self.check_match(vec!(
+            Token::Amp,
+            Token::Pipe,
+            Token::Caret,
+            Token::ShiftLeft,
+            Token::ShiftRight,
+        )) {
+            let op = self.previous();
+            let right = self.term()?;
             expr = bin_expr!(expr, op, right);
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn term(&mut self) -> Expr {
-        let mut expr = self.factor();
+    pub fn term(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.factor()?;
         while self.check_match(
             vec!(Token::Minus, Token::Plus)
         ) {
             let op = self.previous();
-            let right = self.factor();
+            let right = self.factor()?;
             expr = bin_expr!(expr, op, right);
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn factor(&mut self) -> Expr {
-        let mut expr = self.unary();
+    pub fn factor(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.unary()?;
         while self.check_match(
-            vec!(Token::Div, Token::Times)
+            vec!(Token::Div, Token::Times, Token::Percent)
         ) {
             let op = self.previous();
-            let right = self.unary();
+            let right = self.unary()?;
             expr = bin_expr!(expr, op, right);
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn unary(&mut self) -> Expr {
+    pub fn unary(&mut self) -> ParseResult<Expr> {
         if self.check_match(
             vec!(Token::Bang, Token::Minus)
         ) {
             let op = self.previous();
-            let right = self.unary();
-            return Expr::UnaryExpr { op, right: Box::new(right) };
+            let right = self.nested(Self::unary)?;
+            return Ok(Expr::UnaryExpr { op, right: Box::new(right) });
         }
-        return self.call();
+        return self.power();
     }
-    pub fn call(&mut self) -> Expr {
-        let mut expr = self.primary();
+    // Binds tighter than unary operators on its left, so `-2 ** 2` is
+    // `-(2 ** 2)`, and is right associative, so `2 ** 3 ** 2` is
+    // `2 ** (3 ** 2)`.
+    pub fn power(&mut self) -> ParseResult<Expr> {
+        let expr = self.call()?;
+        if self.check_match(vec!(Token::StarStar)) {
+            let op = self.previous();
+            let right = self.nested(Self::unary)?;
+            return Ok(bin_expr!(expr, op, right));
+        }
+        return Ok(expr);
+    }
+    pub fn call(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.primary()?;
         loop {
             if self.check_match(
                 vec!(Token::LParen)
             ) {
-                expr = self.finish_call(expr);
+                expr = self.finish_call(expr)?;
+            } else if self.check_match(vec!(Token::LBracket)) {
+                let span = self.previous_span();
+                let index = self.expression()?;
+                self.consume(Token::RBracket)?;
+                expr = Expr::Index { array: Box::new(expr), index: Box::new(index), span };
+            } else if self.check_match(vec!(Token::Dot)) {
+                let name = self.consume_identifier()?;
+                expr = Expr::Get { object: Box::new(expr), name };
             } else {
                 break;
             }
         }
-        return expr;
+        return Ok(expr);
     }
-    pub fn finish_call(&mut self, expr: Expr) -> Expr {
+    pub fn finish_call(&mut self, expr: Expr) -> ParseResult<Expr> {
         let mut args = vec!();
         if !self.check(Token::RParen) {
-            let mut sub_expr = self.expression();
+            let mut sub_expr = self.expression()?;
             args.push(Box::new(sub_expr));
             while self.check_match(
                 vec!(Token::Comma)
             ) {
-                sub_expr = self.expression();
+                sub_expr = self.expression()?;
                 args.push(Box::new(sub_expr));
             }
         }
-        let paren = self.consume(Token::RParen);
-        return Expr::Call { callee: Box::new(expr), paren, args };
+        let paren = self.consume(Token::RParen)?;
+        return Ok(Expr::Call { callee: Box::new(expr), paren, args });
     }
-    pub fn primary(&mut self) -> Expr {
+    pub fn primary(&mut self) -> ParseResult<Expr> {
         if self.check_match(vec!(Token::False)) {
-            return Expr::Literal { value: "false".to_string() };
+            return Ok(Expr::Literal { value: "false".to_string() });
         }
         if self.check_match(vec!(Token::True)) {
-            return Expr::Literal { value: "true".to_string() };
+            return Ok(Expr::Literal { value: "true".to_string() });
         }
         match self.peek() {
-            Token::Number(n) => {
+            // Literals keep the spelling from the source.
+            Token::Number(n) | Token::HexNumber(n) => {
                 self.advance();
-                return Expr::Literal { value: n };
+                return Ok(Expr::Literal { value: n });
+            },
+            Token::Str(s) => {
+                self.advance();
+                return Ok(Expr::StringLiteral { value: s });
             },
             Token::Ident(_) => {
                 self.advance();
-                return Expr::Variable { name: self.previous() };
+                return Ok(Expr::Variable { name: self.previous(), span: self.previous_span() });
             },
             _ => {}
         }
         if self.check_match(vec!(Token::LParen)) {
-            let expr = self.expression();
-            self.consume(Token::RParen);
-            return Expr::Grouping { expr: Box::new(expr) };
+            let expr = self.expression()?;
+            self.consume(Token::RParen)?;
+            return Ok(Expr::Grouping { expr: Box::new(expr) });
+        }
+        Err(ParseError::ExpectedExpression { found: self.peek(), span: self.peek_span() })
+    }
+    // Runs a rule that may recurse, failing once the input nests deeper
+    // than MAX_DEPTH.
+    fn nested<T>(&mut self, rule: fn(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(ParseError::TooDeep { span: self.peek_span() });
+        }
+        self.depth += 1;
+        let result = rule(self);
+        self.depth -= 1;
+        result
+    }
+    // Discards tokens until the start of the next statement: past a `;`,
+    // or before a keyword starting a statement. Inside a block it also
+    // stops before a `}`, leaving the block to be closed. No statement in
+    // a block starts at a `}`, so the failed one consumed tokens.
+    fn synchronize(&mut self, in_block: bool) {
+        if in_block && self.check(Token::RBrace) {
+            return;
+        }
+        self.advance();
+        while !self.is_at_end() {
+            if self.previous() == Token::Semicolon {
+                return;
+            }
+            match self.peek() {
+                Token::RBrace if in_block => return,
+                // A block, such as the body of a function with a bad name,
+                // belongs to the failed statement.
+                Token::LBrace => {
+                    self.skip_braces();
+                    return;
+                },
+                Token::Fun
+                | Token::Wagmi
+                | Token::Var
+                | Token::For
+                | Token::If
+                | Token::While
+                | Token::Print
+                | Token::Break
+                | Token::Continue
+                | Token::Return => return,
+                _ => {},
+            }
+            self.advance();
+        }
+    }
+    // Consumes a `{` and everything up to its matching `}`.
+    fn skip_braces(&mut self) {
+        let mut depth = 0;
+        while !self.is_at_end() {
+            match self.peek() {
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth -= 1,
+                _ => {},
+            }
+            self.advance();
+            if depth == 0 {
+                return;
+            }
         }
-        // TODO: Handle this edge case...
-        return Expr::Literal { value: "false".to_string() };
     }
     fn check_match(&mut self, toks: Vec<Token>) -> bool {
         for tok in toks.iter() {
@@ -395,29 +680,383 @@ impl Parser {
         }
         return self.peek() == tok;
     }
-    fn consume(&mut self, tok: Token) -> Token {
-        if self.check(tok) {
+    fn consume(&mut self, tok: Token) -> ParseResult<Token> {
+        if self.check(tok.clone()) {
             self.advance();
-        } 
-        self.previous()
+            return Ok(self.previous());
+        }
+        Err(ParseError::UnexpectedToken {
+            expected: tok,
+            found: self.peek(),
+            span: self.peek_span(),
+        })
     }
+    // Stops one past the last token, so `previous` is always the last
+    // token consumed.
     fn advance(&mut self) {
-        self.current += 1;
-        self.previous();
+        if self.next.is_some() {
+            self.collect_comments();
+            self.previous = self.next.take();
+            self.next = self.tokens.next();
+        }
+    }
+    // Moves the comments trailing the previous token and leading the next
+    // one into the collected comments, if they are kept.
+    fn collect_comments(&mut self) {
+        if !self.keep_comments {
+            return;
+        }
+        if let Some(tok) = self.previous.as_mut() {
+            self.comments.extend(tok.trailing_trivia.take());
+        }
+        if let Some(tok) = self.next.as_mut() {
+            self.comments.append(&mut tok.leading_trivia);
+        }
+    }
+    fn pending_comments(&mut self) -> Vec<Box<Stmt>> {
+        self.comments
+            .drain(..)
+            .map(|text| Box::new(Stmt::Comment { text, trailing: false }))
+            .collect()
     }
     fn is_at_end(&self) -> bool {
         return self.peek() == Token::EOF
     }
+    // Nothing has been consumed at the start of the stream.
     fn previous(&self) -> Token {
-        if let Some(tok) = self.tokens.get(self.current-1) {
-            return tok.clone();
+        if let Some(tok) = &self.previous {
+            return tok.node.clone();
         }
         return Token::EOF;
     }
     fn peek(&self) -> Token {
-        if let Some(tok) = self.tokens.get(self.current) {
-            return tok.clone();
+        if let Some(tok) = &self.next {
+            return tok.node.clone();
         }
         return Token::EOF;
     }
+    fn previous_span(&self) -> Span {
+        if let Some(tok) = &self.previous {
+            return tok.span;
+        }
+        return Span::default();
+    }
+    fn peek_span(&self) -> Span {
+        if let Some(tok) = &self.next {
+            return tok.span;
+        }
+        // Past the last token, point just after it.
+        match &self.previous {
+            Some(tok) => Span { line: tok.span.line, col: tok.span.col + tok.span.len, len: 0 },
+            None => Span::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::TokenLexer;
+
+    fn parse(source: &str) -> Result<Vec<Box<Stmt>>, Vec<ParseError>> {
+        Parser::new(TokenLexer::new(source).map(|token| token.unwrap())).parse()
+    }
+
+    // Parses a single expression statement.
+    fn expr(source: &str) -> Expr {
+        let statements = parse(&format!("{};", source)).unwrap();
+        match statements[0].as_ref() {
+            Stmt::Expr(expr) => *expr.clone(),
+            stmt => panic!("expected an expression, found {:?}", stmt),
+        }
+    }
+
+    // Writes the expression in prefix form with every node parenthesized,
+    // showing how it was grouped.
+    fn tree(expr: &Expr) -> String {
+        match expr {
+            Expr::BinaryExpr { op, left, right } | Expr::Logical { op, left, right } => {
+                format!("({} {} {})", op, tree(left), tree(right))
+            },
+            Expr::UnaryExpr { op, right } => format!("({} {})", op, tree(right)),
+            Expr::Ternary { cond, then, els } => format!("(? {} {} {})", tree(cond), tree(then), tree(els)),
+            Expr::Grouping { expr } => format!("(group {})", tree(expr)),
+            Expr::Literal { value } => value.clone(),
+            Expr::StringLiteral { value } => format!("{:?}", value),
+            Expr::Variable { name, .. } => name.to_string(),
+            Expr::Assign { name, value, .. } => format!("(= {} {})", name, tree(value)),
+            Expr::Call { callee, args, .. } => {
+                let args = args.iter().map(|arg| format!(" {}", tree(arg))).collect::<String>();
+                format!("(call {}{})", tree(callee), args)
+            },
+            Expr::Index { array, index, .. } => format!("([] {} {})", tree(array), tree(index)),
+            Expr::SetIndex { array, index, value, .. } => {
+                format!("([]= {} {} {})", tree(array), tree(index), tree(value))
+            },
+            Expr::Get { object, name } => format!("(. {} {})", tree(object), name),
+        }
+    }
+
+    fn is_variable(expr: &Expr, expected: &str) -> bool {
+        matches!(expr, Expr::Variable { name: Token::Ident(name), .. } if name == expected)
+    }
+
+    #[test]
+    fn bang_eq_is_inequality() {
+        match expr("a != b") {
+            Expr::BinaryExpr { op: Token::BangEq, left, right } => {
+                assert!(is_variable(&left, "a"));
+                assert!(is_variable(&right, "b"));
+            },
+            expr => panic!("expected `!=`, found {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn for_keeps_its_condition() {
+        let statements = parse("for (var i = 0; i < 10; i = i + 1) print i;").unwrap();
+        let Stmt::Block(desugared) = statements[0].as_ref() else {
+            panic!("expected a block, found {:?}", statements[0]);
+        };
+        match desugared[1].as_ref() {
+            Stmt::While { condition, increment: Some(_), .. } => {
+                assert_eq!(pretty::expr(condition), "i < 10");
+            },
+            stmt => panic!("expected a while loop, found {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn string_literal() {
+        match expr(r#"print_str("hi\n")"#) {
+            Expr::Call { args, .. } => {
+                assert!(matches!(args[0].as_ref(), Expr::StringLiteral { value } if value == "hi\n"));
+            },
+            expr => panic!("expected a call, found {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn missing_semicolon() {
+        let errors = parse("var x = 5").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "expected ';' at line 1, col 10, found 'end of file'");
+
+        let errors = parse("fun f() {\n    var x = 5\n    print x;\n}").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "expected ';' at line 3, col 5, found 'print'");
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        let cases = [
+            ("1 + 2 * 3", "(+ 1 (* 2 3))"),
+            ("1 - 2 - 3", "(- (- 1 2) 3)"),
+            ("a = b = c", "(= a (= b c))"),
+            ("!a == b", "(== (! a) b)"),
+            ("-2 * 3", "(* (- 2) 3)"),
+            // Each level against the one below it.
+            ("a = b or c", "(= a (or b c))"),
+            ("a = b ? c : d", "(= a (? b c d))"),
+            ("a ? b : c or d", "(? a b (or c d))"),
+            ("a > b ? a : b", "(? (> a b) a b)"),
+            ("a or b and c", "(or a (and b c))"),
+            ("a and b or c", "(or (and a b) c)"),
+            ("a and b == c", "(and a (== b c))"),
+            ("a == b < c", "(== a (< b c))"),
+            ("a != b >= c", "(!= a (>= b c))"),
+            ("a < b & c", "(< a (& b c))"),
+            ("a | b + c", "(| a (+ b c))"),
+            ("a << b - c", "(<< a (- b c))"),
+            ("a + b % c", "(+ a (% b c))"),
+            ("a / -b", "(/ a (- b))"),
+            ("-a ** b", "(- (** a b))"),
+            ("a ** f(b)", "(** a (call f b))"),
+            ("-f(a)[b]", "(- ([] (call f a) b))"),
+            // Left associative within a level.
+            ("a / b * c", "(* (/ a b) c)"),
+            ("a == b != c", "(!= (== a b) c)"),
+            ("a & b | c ^ d", "(^ (| (& a b) c) d)"),
+            ("a or b or c", "(or (or a b) c)"),
+            // Right associative.
+            ("a ? b : c ? d : e", "(? a b (? c d e))"),
+            ("a += b = c", "(= a (+ a (= b c)))"),
+            ("!!a", "(! (! a))"),
+            // Parentheses override precedence.
+            ("(1 + 2) * 3", "(* (group (+ 1 2)) 3)"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(tree(&expr(source)), expected, "parsing {}", source);
+        }
+    }
+
+    #[test]
+    fn missing_function_name() {
+        let errors = parse("fun (a) { return a; }").unwrap_err();
+        assert_eq!(errors[0].to_string(), "expected identifier at line 1, col 5, found '('");
+
+        let errors = parse("var = 1;").unwrap_err();
+        assert_eq!(errors[0].to_string(), "expected identifier at line 1, col 5, found '='");
+    }
+
+    #[test]
+    fn recovers_after_a_bad_statement() {
+        let tokens = TokenLexer::new("var a = 1;\nvar b = (2 + ;\nprint a;").map(|token| token.unwrap());
+        let (statements, errors) = Parser::new(tokens).parse_recovering();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].to_string(), "expected expression at line 2, col 14, found ';'");
+        assert_eq!(pretty::print(&statements), "var a = 1;\nprint a;\n");
+    }
+
+    #[test]
+    fn recovers_inside_a_block() {
+        let tokens = TokenLexer::new("fun f() {\n    var a = 1;\n    a = * 2;\n    return a;\n}").map(|token| token.unwrap());
+        let (statements, errors) = Parser::new(tokens).parse_recovering();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(pretty::print(&statements), "fun f() {\n    var a = 1;\n    return a;\n}\n");
+    }
+
+    #[test]
+    fn power_groups_to_the_right() {
+        assert_eq!(tree(&expr("2 ** 3 ** 2")), "(** 2 (** 3 2))");
+        assert_eq!(tree(&expr("2 ** -3 ** 2")), "(** 2 (- (** 3 2)))");
+        assert_eq!(tree(&expr("2 * 3 ** 2")), "(* 2 (** 3 2))");
+    }
+
+    #[test]
+    fn keywords_as_names() {
+        let cases = [
+            ("var if = 1;", "cannot use keyword 'if' as a name at line 1, col 5"),
+            ("fun while() { return 1; }", "cannot use keyword 'while' as a name at line 1, col 5"),
+            ("fun f(a, return) { return a; }", "cannot use keyword 'return' as a name at line 1, col 10"),
+        ];
+        for (source, expected) in cases {
+            let errors = parse(source).unwrap_err();
+            assert_eq!(errors.len(), 1, "{:?}", errors);
+            assert_eq!(errors[0].to_string(), expected);
+        }
+        // Names that only start with a keyword are fine.
+        parse("var iff = 1; fun whiley(format) { return format; }").unwrap();
+    }
+
+    #[test]
+    fn reports_every_broken_statement() {
+        let errors = parse("var a = ;\nvar b = 2;\n1 = b;\nprint (b;\nvar c = 3;").unwrap_err();
+        let messages = errors.iter().map(|err| err.to_string()).collect::<Vec<String>>();
+        assert_eq!(messages, vec!(
+            "expected expression at line 1, col 9, found ';'",
+            "invalid assignment target at line 3, col 3",
+            "expected ')' at line 4, col 9, found ';'",
+        ));
+    }
+
+    #[test]
+    fn double_equals_in_declaration() {
+        let errors = parse("var x == 5;").unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(matches!(errors[0], ParseError::EqualityInDeclaration { span } if span.col == 7), "{:?}", errors);
+        assert!(errors[0].to_string().contains("did you mean '='?"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn print_statement() {
+        let statements = parse("print 1 + 2;").unwrap();
+        match statements[0].as_ref() {
+            Stmt::Print(expr) => assert_eq!(tree(expr), "(+ 1 2)"),
+            stmt => panic!("expected a print, found {:?}", stmt),
+        }
+        let errors = parse("print;").unwrap_err();
+        assert_eq!(errors[0].to_string(), "expected expression at line 1, col 6, found ';'");
+    }
+
+    #[test]
+    fn stream_boundaries() {
+        let mut parser = Parser::new(TokenLexer::new("a").map(|token| token.unwrap()));
+        // Nothing consumed yet.
+        assert_eq!(parser.previous(), Token::EOF);
+        assert_eq!(parser.previous_span(), Span::default());
+        assert_eq!(parser.peek(), Token::Ident("a".to_string()));
+
+        for _ in 0..3 {
+            parser.advance();
+            assert_eq!(parser.previous(), Token::Ident("a".to_string()));
+            assert_eq!(parser.peek(), Token::EOF);
+            assert!(parser.is_at_end());
+        }
+        assert_eq!(parser.peek_span(), Span { line: 1, col: 2, len: 0 });
+
+        let mut empty = Parser::new(TokenLexer::new("").map(|token| token.unwrap()));
+        empty.advance();
+        assert_eq!(empty.previous(), Token::EOF);
+        assert_eq!(empty.peek_span(), Span::default());
+    }
+
+    #[test]
+    fn compound_assignment_desugars() {
+        let cases = [
+            ("x += 2", "(= x (+ x 2))"),
+            ("x -= 2", "(= x (- x 2))"),
+            ("x *= 2", "(= x (* x 2))"),
+            ("x /= 2", "(= x (/ x 2))"),
+            ("x %= 2", "(= x (% x 2))"),
+            ("x &= 2", "(= x (& x 2))"),
+            ("x |= 2", "(= x (| x 2))"),
+            ("x ^= 2", "(= x (^ x 2))"),
+            ("x <<= 2", "(= x (<< x 2))"),
+            ("x >>= 2", "(= x (>> x 2))"),
+            ("a[i] += 1", "([]= a i (+ ([] a i) 1))"),
+            // The whole right-hand side is the operand.
+            ("x *= 1 + 2", "(= x (* x (+ 1 2)))"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(tree(&expr(source)), expected, "parsing {}", source);
+        }
+        // `+` followed by something else is still addition.
+        assert_eq!(tree(&expr("x +-y")), "(+ x (- y))");
+    }
+
+    #[test]
+    fn chained_comparison() {
+        let errors = parse("print a < b < c;").unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(matches!(errors[0], ParseError::ChainedComparison { .. }), "{:?}", errors);
+        assert_eq!(errors[0].to_string(), "comparisons can't be chained at line 1, col 13, did you mean `a < b and b < c`?");
+        // Grouping or joining the comparisons is fine.
+        assert_eq!(tree(&expr("(a < b) < c")), "(< (group (< a b)) c)");
+        assert_eq!(tree(&expr("a < b and b >= c")), "(and (< a b) (>= b c))");
+    }
+
+    #[test]
+    fn annotated_params() {
+        let statements = parse("fun f(n: int, x: float, y) { return x; }").unwrap();
+        let Stmt::Function { params, .. } = statements[0].as_ref() else {
+            panic!("expected a function, found {:?}", statements[0]);
+        };
+        let types = params.iter().map(|param| (param.name.to_string(), param.ty)).collect::<Vec<_>>();
+        assert_eq!(types, vec!(
+            ("n".to_string(), Some(Type::Int)),
+            ("x".to_string(), Some(Type::Float)),
+            ("y".to_string(), None),
+        ));
+        assert_eq!(params[2].ty(), Type::Float);
+
+        let errors = parse("fun f(n: string) { return n; }").unwrap_err();
+        assert_eq!(errors[0].to_string(), "unknown type 'string' at line 1, col 10, expected 'int' or 'float'");
+    }
+
+    #[test]
+    fn declared_return_type() {
+        let statements = parse("fun f() -> int { return 1; } fun g(x) { return x; }").unwrap();
+        let return_types = statements
+            .iter()
+            .map(|stmt| match stmt.as_ref() {
+                Stmt::Function { return_type, .. } => *return_type,
+                stmt => panic!("expected a function, found {:?}", stmt),
+            })
+            .collect::<Vec<Option<Type>>>();
+        assert_eq!(return_types, vec!(Some(Type::Int), None));
+
+        let errors = parse("fun f() -> { return 1; }").unwrap_err();
+        assert_eq!(errors[0].to_string(), "expected identifier at line 1, col 12, found '{'");
+    }
 }
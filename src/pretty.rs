@@ -0,0 +1,307 @@
+use crate::parser::{Expr,Stmt};
+use crate::token::Token;
+
+const INDENT: &str = "    ";
+
+/// Prints the statements back as source. Parentheses are only added
+/// where precedence requires them, besides the ones written in the
+/// source. For loops lose their initializer to an enclosing block and
+/// come out as `for (; cond; increment)`. Comments are printed when the
+/// statements were parsed with them.
+pub fn print(statements: &[Box<Stmt>]) -> String {
+    let mut printer = Printer { out: String::new(), indent: 0 };
+    let is_comment = |stmt: &&Box<Stmt>| matches!(***stmt, Stmt::Comment { .. });
+    for (i, stmt) in statements.iter().enumerate() {
+        // Separate functions from their neighbours with a blank line.
+        // Comments stay with the statement they precede or trail.
+        let owner = statements[i..].iter().find(|stmt| !is_comment(stmt));
+        let is_function = matches!(owner.map(|stmt| stmt.as_ref()), Some(Stmt::Function { .. }));
+        let before = statements[..i].iter().rev().find(|stmt| !is_comment(stmt));
+        let after_function = matches!(before.map(|stmt| stmt.as_ref()), Some(Stmt::Function { .. }));
+        let attached = matches!(**stmt, Stmt::Comment { trailing: true, .. })
+            || (i > 0 && matches!(*statements[i-1], Stmt::Comment { trailing: false, .. }));
+        if i > 0 && !attached && (is_function || after_function) {
+            printer.out.push('\n');
+        }
+        printer.stmt(stmt);
+    }
+    printer.out
+}
+
+/// Prints a single expression as source.
+pub fn expr(expr: &Expr) -> String {
+    let mut out = String::new();
+    write_expr(&mut out, expr, 0);
+    out
+}
+
+struct Printer {
+    out: String,
+    indent: usize,
+}
+
+impl Printer {
+    fn line_start(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        // Goes at the end of the line of the statement before.
+        if let Stmt::Comment { text, trailing: true } = stmt {
+            self.out.pop();
+            self.out.push_str(&format!(" {}\n", text));
+            return;
+        }
+        self.line_start();
+        self.stmt_inline(stmt);
+        self.out.push('\n');
+    }
+
+    // Writes a statement without its leading indentation or trailing
+    // newline.
+    fn stmt_inline(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::Expr(expr) => {
+                write_expr(&mut self.out, expr, 0);
+                self.out.push(';');
+            },
+            Stmt::Print(expr) => {
+                self.out.push_str("print ");
+                write_expr(&mut self.out, expr, 0);
+                self.out.push(';');
+            },
+            Stmt::Return { value, .. } => {
+                self.out.push_str("return");
+                if let Some(value) = value {
+                    self.out.push(' ');
+                    write_expr(&mut self.out, value, 0);
+                }
+                self.out.push(';');
+            },
+            Stmt::Function { name, params, return_type, body, entry, .. } => {
+                let params = params
+                    .iter()
+                    .map(|param| match param.ty {
+                        Some(ty) => format!("{}: {}", param.name, ty),
+                        None => param.name.to_string(),
+                    })
+                    .collect::<Vec<String>>();
+                if *entry {
+                    self.out.push_str("wagmi ");
+                }
+                self.out.push_str(&format!("fun {}({}) ", name, params.join(", ")));
+                if let Some(ty) = return_type {
+                    self.out.push_str(&format!("-> {} ", ty));
+                }
+                self.block(body);
+            },
+            Stmt::If { cond, then_branch, else_branch } => {
+                self.out.push_str("if (");
+                write_expr(&mut self.out, cond, 0);
+                self.out.push(')');
+                self.branch(then_branch);
+                if let Some(else_branch) = else_branch {
+                    if let Stmt::Block(_) = **then_branch {
+                        self.out.push(' ');
+                    } else {
+                        self.out.push('\n');
+                        self.line_start();
+                    }
+                    self.out.push_str("else");
+                    if let Stmt::If { .. } = **else_branch {
+                        self.out.push(' ');
+                        self.stmt_inline(else_branch);
+                    } else {
+                        self.branch(else_branch);
+                    }
+                }
+            },
+            Stmt::While { condition, body, increment: None } => {
+                self.out.push_str("while (");
+                write_expr(&mut self.out, condition, 0);
+                self.out.push(')');
+                self.branch(body);
+            },
+            Stmt::While { condition, body, increment: Some(increment) } => {
+                self.out.push_str("for (; ");
+                write_expr(&mut self.out, condition, 0);
+                self.out.push_str("; ");
+                write_expr(&mut self.out, increment, 0);
+                self.out.push(')');
+                self.branch(body);
+            },
+            Stmt::Break { .. } => self.out.push_str("break;"),
+            Stmt::Continue { .. } => self.out.push_str("continue;"),
+            Stmt::Comment { text, .. } => self.out.push_str(text),
+            Stmt::Var { name, initializer, .. } => {
+                self.out.push_str(&format!("var {} = ", name));
+                write_expr(&mut self.out, initializer, 0);
+                self.out.push(';');
+            },
+        }
+    }
+
+    // Blocks stay on the line of their statement, anything else goes
+    // indented on the next line.
+    fn branch(&mut self, stmt: &Stmt) {
+        if let Stmt::Block(statements) = stmt {
+            self.out.push(' ');
+            self.block(statements);
+            return;
+        }
+        self.out.push('\n');
+        self.indent += 1;
+        self.line_start();
+        self.stmt_inline(stmt);
+        self.indent -= 1;
+    }
+
+    fn block(&mut self, statements: &[Box<Stmt>]) {
+        if statements.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push_str("{\n");
+        self.indent += 1;
+        for stmt in statements.iter() {
+            self.stmt(stmt);
+        }
+        self.indent -= 1;
+        self.line_start();
+        self.out.push('}');
+    }
+}
+
+// Binding strength of each operator, following the parser's rules.
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Assign { .. } | Expr::SetIndex { .. } => 0,
+        Expr::Ternary { .. } => 1,
+        Expr::Logical { op: Token::Or, .. } => 2,
+        Expr::Logical { .. } => 3,
+        Expr::BinaryExpr { op: Token::StarStar, .. } => 10,
+        Expr::BinaryExpr { op, .. } => match op {
+            Token::Eqq | Token::BangEq => 4,
+            Token::Greater | Token::Geq | Token::Less | Token::Leq => 5,
+            Token::Amp | Token::Pipe | Token::Caret | Token::ShiftLeft | Token::ShiftRight => 6,
+            Token::Plus | Token::Minus => 7,
+            _ => 8,
+        },
+        Expr::UnaryExpr { .. } => 9,
+        Expr::Call { .. } | Expr::Index { .. } | Expr::Get { .. } => 11,
+        _ => 12,
+    }
+}
+
+// Writes the expression, wrapping it in parentheses when it binds looser
+// than `min` requires.
+fn write_expr(out: &mut String, expr: &Expr, min: u8) {
+    let prec = precedence(expr);
+    let wrap = prec < min;
+    if wrap {
+        out.push('(');
+    }
+    match expr {
+        Expr::BinaryExpr { op: Token::StarStar, left, right } => {
+            // Right associative, with a unary operand on the right.
+            write_expr(out, left, prec + 1);
+            out.push_str(" ** ");
+            write_expr(out, right, 9);
+        },
+        Expr::BinaryExpr { op, left, right } | Expr::Logical { op, left, right } => {
+            // Operators are left associative, so an equal right operand
+            // needs parentheses to keep its grouping.
+            write_expr(out, left, prec);
+            out.push_str(&format!(" {} ", op));
+            write_expr(out, right, prec + 1);
+        },
+        Expr::UnaryExpr { op, right } => {
+            out.push_str(&op.to_string());
+            write_expr(out, right, prec);
+        },
+        Expr::Ternary { cond, then, els } => {
+            // Right associative, so only the condition needs to bind
+            // tighter.
+            write_expr(out, cond, prec + 1);
+            out.push_str(" ? ");
+            write_expr(out, then, 0);
+            out.push_str(" : ");
+            write_expr(out, els, prec);
+        },
+        Expr::Grouping { expr } => {
+            out.push('(');
+            write_expr(out, expr, 0);
+            out.push(')');
+        },
+        Expr::Literal { value } => out.push_str(value),
+        Expr::StringLiteral { value } => out.push_str(&escape(value)),
+        Expr::Assign { name, value, .. } => {
+            out.push_str(&format!("{} = ", name));
+            write_expr(out, value, prec);
+        },
+        Expr::Variable { name, .. } => out.push_str(&name.to_string()),
+        Expr::Call { callee, args, .. } => {
+            write_expr(out, callee, prec);
+            out.push('(');
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expr(out, arg, 0);
+            }
+            out.push(')');
+        },
+        Expr::Index { array, index, .. } => {
+            write_expr(out, array, prec);
+            out.push('[');
+            write_expr(out, index, 0);
+            out.push(']');
+        },
+        Expr::Get { object, name } => {
+            write_expr(out, object, prec);
+            out.push_str(&format!(".{}", name));
+        },
+        Expr::SetIndex { array, index, value, .. } => {
+            write_expr(out, array, 11);
+            out.push('[');
+            write_expr(out, index, 0);
+            out.push_str("] = ");
+            write_expr(out, value, prec);
+        },
+    }
+    if wrap {
+        out.push(')');
+    }
+}
+
+// Quotes a string using only the escapes the lexer understands.
+fn escape(value: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in value.chars() {
+        match ch {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex,parse_with_comments};
+
+    #[test]
+    fn comments_stay_in_place() {
+        let source = include_str!("../tests/programs/comments.mai");
+        let statements = parse_with_comments(lex(source).unwrap()).unwrap();
+        assert_eq!(print(&statements), source);
+    }
+}
@@ -0,0 +1,150 @@
+use std::io::{self,Write};
+
+use eyre::eyre;
+use inkwell::OptimizationLevel;
+use inkwell::context::Context;
+use inkwell::targets::{InitializationConfig,Target};
+
+use crate::llvm_translator::PRINT_FN;
+use crate::parser::Stmt;
+use crate::token::{Span,Token};
+
+/// Name of the function each line's top-level statements are wrapped in.
+const REPL_FN: &str = "__repl_line";
+
+extern "C" fn print_f64(value: f64) {
+    println!("{}", value);
+}
+
+/// Interactive session state. Functions defined on earlier lines are kept
+/// and translated again into the fresh module built for every line.
+pub struct Repl {
+    functions: Vec<Box<Stmt>>,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self { functions: vec!() }
+    }
+
+    /// Evaluates one line of input, returning the value of its top-level
+    /// statements besides function definitions, if they produce one.
+    pub fn eval(&mut self, line: &str) -> eyre::Result<Option<f64>> {
+        let statements = crate::parse(crate::lex(line)?)?;
+        let (functions, rest): (Vec<Box<Stmt>>, Vec<Box<Stmt>>) = statements
+            .into_iter()
+            .partition(|stmt| matches!(stmt.as_ref(), Stmt::Function { .. }));
+
+        // A function defined again replaces the earlier definition.
+        let defined = functions
+            .iter()
+            .filter_map(|fun| match fun.as_ref() {
+                Stmt::Function { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect::<Vec<Token>>();
+        let mut program = self.functions
+            .iter()
+            .filter(|fun| !matches!(fun.as_ref(), Stmt::Function { name, .. } if defined.contains(name)))
+            .cloned()
+            .collect::<Vec<Box<Stmt>>>();
+        program.extend(functions.iter().cloned());
+        if !rest.is_empty() {
+            program.push(Box::new(Stmt::Function {
+                name: Token::Ident(REPL_FN.to_string()),
+                params: vec!(),
+                return_type: None,
+                body: rest.clone(),
+                span: Span::default(),
+                entry: false,
+            }));
+        }
+
+        let context = Context::create();
+        let module = context.create_module("repl");
+        crate::translate(&context, &module, &program, &crate::TranslateOptions::default())?;
+
+        // Only remember functions once they have translated successfully.
+        self.functions.retain(|fun| !matches!(fun.as_ref(), Stmt::Function { name, .. } if defined.contains(name)));
+        self.functions.extend(functions);
+        if rest.is_empty() {
+            return Ok(None);
+        }
+
+        let engine = module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|e| eyre!(e.to_string()))?;
+        if let Some(fun) = module.get_function(PRINT_FN) {
+            engine.add_global_mapping(&fun, print_f64 as *const () as usize);
+        }
+
+        // Lines ending in a statement without a value, like `print`, don't
+        // echo anything.
+        let is_void = module
+            .get_function(REPL_FN)
+            .is_none_or(|fun| fun.get_type().get_return_type().is_none());
+        if is_void {
+            unsafe {
+                engine.get_function::<unsafe extern "C" fn()>(REPL_FN)?.call();
+            }
+            return Ok(None);
+        }
+        let value = unsafe {
+            let fun = engine.get_function::<unsafe extern "C" fn() -> f64>(REPL_FN)?;
+            fun.call()
+        };
+        Ok(Some(value))
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs the read-eval-print loop on stdin until EOF.
+pub fn run() -> eyre::Result<()> {
+    Target::initialize_native(&InitializationConfig::default()).map_err(|e| eyre!(e))?;
+
+    let mut repl = Repl::new();
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        match repl.eval(line.as_str()) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => {},
+            Err(err) => eprintln!("error: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn functions_persist_between_lines() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval("1 + 2 * 3;").unwrap(), Some(7.0));
+        assert_eq!(repl.eval("fun double(x) { return x * 2; }").unwrap(), None);
+        assert_eq!(repl.eval("double(21);").unwrap(), Some(42.0));
+        // A line that fails leaves the earlier definitions in place.
+        assert!(repl.eval("fun broken() { return missing; }").is_err());
+        assert_eq!(repl.eval("double(4) + 1;").unwrap(), Some(9.0));
+        // Defining a function again replaces it.
+        assert_eq!(repl.eval("fun double(x) { return x * 3; }").unwrap(), None);
+        assert_eq!(repl.eval("double(2);").unwrap(), Some(6.0));
+        assert_eq!(repl.eval("print double(1);").unwrap(), None);
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::{HashMap,HashSet};
+
+use thiserror::Error;
+
+use crate::llvm_translator::BUILTINS;
+use crate::parser::{Expr,Stmt};
+use crate::token::{Span,Token};
+
+#[derive(Debug,Error)]
+pub enum ResolveError {
+    #[error("undefined variable `{name}` at {span}")]
+    UndefinedVariable {
+        name: String,
+        span: Span,
+    },
+    #[error("undefined function `{name}` at {span}")]
+    UndefinedFunction {
+        name: String,
+        span: Span,
+    },
+    #[error("cannot assign to global `{name}` at {span}, globals are constant")]
+    AssignToGlobal {
+        name: String,
+        span: Span,
+    },
+    #[error("function `{name}` defined at {first} is defined again at {second}")]
+    DuplicateFunction {
+        name: String,
+        first: Span,
+        second: Span,
+    },
+    #[error("function `{second}` at {span} is marked with wagmi, but `{first}` already is")]
+    DuplicateEntry {
+        first: String,
+        second: String,
+        span: Span,
+    },
+    #[error("function `{function}` at {span} has more than one parameter named `{name}`")]
+    DuplicateParameter {
+        name: String,
+        function: String,
+        span: Span,
+    },
+}
+
+/// Checks that every variable and function used by the program is
+/// defined and that nothing is defined twice, reporting every problem
+/// found.
+pub fn resolve(statements: &[Box<Stmt>]) -> Result<(), Vec<ResolveError>> {
+    let mut resolver = Resolver::new(statements);
+    for stmt in statements.iter() {
+        resolver.stmt(stmt);
+    }
+    if !resolver.errors.is_empty() {
+        return Err(resolver.errors);
+    }
+    Ok(())
+}
+
+struct Resolver {
+    scopes: Vec<HashSet<String>>,
+    /// Functions defined inside the current function, one set per scope.
+    local_functions: Vec<HashSet<String>>,
+    functions: HashSet<String>,
+    /// Top-level variables, which every function can use.
+    globals: HashSet<String>,
+    errors: Vec<ResolveError>,
+}
+
+fn name_of(token: &Token) -> String {
+    match token {
+        Token::Ident(id) => id.clone(),
+        tok => tok.to_string(),
+    }
+}
+
+impl Resolver {
+    fn new(statements: &[Box<Stmt>]) -> Self {
+        // Top-level functions can be called before their definition.
+        let mut functions = BUILTINS
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<HashSet<String>>();
+        let mut errors = vec!();
+        let mut defined: HashMap<String, Span> = HashMap::new();
+        let mut entry: Option<String> = None;
+        let mut globals = HashSet::new();
+        for stmt in statements.iter() {
+            if let Stmt::Var { name, .. } = stmt.as_ref() {
+                globals.insert(name_of(name));
+            }
+            if let Stmt::Function { name, span, entry: is_entry, .. } = stmt.as_ref() {
+                let name = name_of(name);
+                if *is_entry {
+                    match &entry {
+                        Some(first) => errors.push(ResolveError::DuplicateEntry {
+                            first: first.clone(),
+                            second: name.clone(),
+                            span: *span,
+                        }),
+                        None => entry = Some(name.clone()),
+                    }
+                }
+                if let Some(first) = defined.get(name.as_str()) {
+                    errors.push(ResolveError::DuplicateFunction { name: name.clone(), first: *first, second: *span });
+                    continue;
+                }
+                defined.insert(name.clone(), *span);
+                functions.insert(name);
+            }
+        }
+        Self { scopes: vec!(HashSet::new()), local_functions: vec!(), functions, globals, errors }
+    }
+
+    fn declare(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name_of(name));
+        }
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.scopes.iter().rev().any(|scope| scope.contains(name))
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.is_local(name) || self.globals.contains(name)
+    }
+
+    fn is_function(&self, name: &str) -> bool {
+        self.functions.contains(name) || self.local_functions.iter().any(|scope| scope.contains(name))
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(HashSet::new());
+        self.local_functions.push(HashSet::new());
+    }
+
+    fn pop_scope(&mut self) {
+        self.scopes.pop();
+        self.local_functions.pop();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => {
+                self.push_scope();
+                for stmt in statements.iter() {
+                    self.stmt(stmt);
+                }
+                self.pop_scope();
+            },
+            Stmt::Expr(expr) | Stmt::Print(expr) => self.expr(expr),
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            },
+            Stmt::Function { name, params, body, span, .. } => {
+                // Top-level functions only see their own parameters and
+                // locals, nested ones also see those of the functions
+                // around them, and can be called after their definition.
+                let nested = !self.local_functions.is_empty();
+                if nested {
+                    if let Some(scope) = self.local_functions.last_mut() {
+                        scope.insert(name_of(name));
+                    }
+                }
+                let outer = match nested {
+                    true => None,
+                    false => Some((
+                        std::mem::take(&mut self.scopes),
+                        std::mem::take(&mut self.local_functions),
+                    )),
+                };
+                self.push_scope();
+                for param in params.iter() {
+                    let seen = self.scopes.last().is_some_and(|scope| scope.contains(name_of(&param.name).as_str()));
+                    if seen {
+                        self.errors.push(ResolveError::DuplicateParameter {
+                            name: name_of(&param.name),
+                            function: name_of(name),
+                            span: *span,
+                        });
+                    }
+                    self.declare(&param.name);
+                }
+                for stmt in body.iter() {
+                    self.stmt(stmt);
+                }
+                self.pop_scope();
+                if let Some((scopes, local_functions)) = outer {
+                    self.scopes = scopes;
+                    self.local_functions = local_functions;
+                }
+            },
+            Stmt::If { cond, then_branch, else_branch } => {
+                self.expr(cond);
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch);
+                }
+            },
+            Stmt::While { condition, body, increment } => {
+                self.expr(condition);
+                self.stmt(body);
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                }
+            },
+            Stmt::Break { .. } | Stmt::Continue { .. } | Stmt::Comment { .. } => {},
+            Stmt::Var { name, initializer, .. } => {
+                // The initializer can't refer to the variable it defines.
+                self.expr(initializer);
+                self.declare(name);
+            },
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::BinaryExpr { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            },
+            Expr::UnaryExpr { right, .. } => self.expr(right),
+            Expr::Ternary { cond, then, els } => {
+                self.expr(cond);
+                self.expr(then);
+                self.expr(els);
+            },
+            Expr::Grouping { expr } => self.expr(expr),
+            Expr::Literal { .. } | Expr::StringLiteral { .. } => {},
+            Expr::Assign { name, value, span } => {
+                self.expr(value);
+                let name = name_of(name);
+                if self.is_local(name.as_str()) {
+                    return;
+                }
+                match self.globals.contains(name.as_str()) {
+                    true => self.errors.push(ResolveError::AssignToGlobal { name, span: *span }),
+                    false => self.errors.push(ResolveError::UndefinedVariable { name, span: *span }),
+                }
+            },
+            Expr::Variable { name, span } => {
+                let name = name_of(name);
+                if !self.is_defined(name.as_str()) {
+                    self.errors.push(ResolveError::UndefinedVariable { name, span: *span });
+                }
+            },
+            Expr::Call { callee, args, .. } => {
+                match callee.as_ref() {
+                    Expr::Variable { name, span } => {
+                        let name = name_of(name);
+                        if !self.is_function(name.as_str()) {
+                            self.errors.push(ResolveError::UndefinedFunction { name, span: *span });
+                        }
+                    },
+                    callee => self.expr(callee),
+                }
+                for arg in args.iter() {
+                    self.expr(arg);
+                }
+            },
+            Expr::Index { array, index, .. } => {
+                self.expr(array);
+                self.expr(index);
+            },
+            Expr::SetIndex { array, index, value, .. } => {
+                self.expr(array);
+                self.expr(index);
+                self.expr(value);
+            },
+            Expr::Get { object, .. } => self.expr(object),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_to_ir,parse_str};
+
+    fn errors(source: &str) -> Vec<String> {
+        match resolve(&parse_str(source).unwrap()) {
+            Ok(()) => vec!(),
+            Err(errors) => errors.iter().map(|err| err.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn undeclared_variable() {
+        assert_eq!(
+            errors("fun f(a) {\n    var b = a;\n    return b + c;\n}"),
+            vec!("undefined variable `c` at line 3, col 16"),
+        );
+        // A variable is only visible after its declaration.
+        assert_eq!(errors("fun f() { x = 1; var x = 2; return x; }"), vec!("undefined variable `x` at line 1, col 11"));
+    }
+
+    #[test]
+    fn undefined_function() {
+        assert_eq!(
+            errors("fun f() {\n    return g(1) + abs(-1);\n}"),
+            vec!("undefined function `g` at line 2, col 12"),
+        );
+    }
+
+    #[test]
+    fn reported_before_codegen() {
+        let err = compile_to_ir("fun f() { return missing(y); }").unwrap_err();
+        let message = format!("{:?}", err);
+        assert!(message.contains("failed to resolve input"), "{}", message);
+        assert!(message.contains("undefined function `missing` at line 1, col 18"), "{}", message);
+        assert!(message.contains("undefined variable `y` at line 1, col 26"), "{}", message);
+    }
+
+    #[test]
+    fn globals_are_constant() {
+        assert_eq!(
+            errors("var PI = 3.14159;\nfun f() {\n    PI = 3;\n    return PI;\n}"),
+            vec!("cannot assign to global `PI` at line 3, col 5, globals are constant"),
+        );
+        // A local of the same name can be assigned.
+        assert_eq!(errors("var PI = 3.14159; fun f() { var PI = 3; PI += 1; return PI; }"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn duplicate_definitions() {
+        assert_eq!(
+            errors("fun foo() { return 1; }\nfun foo() { return 2; }"),
+            vec!("function `foo` defined at line 1, col 5 is defined again at line 2, col 5"),
+        );
+        assert_eq!(
+            errors("fun f(a, a) { return a; }"),
+            vec!("function `f` at line 1, col 5 has more than one parameter named `a`"),
+        );
+    }
+}
@@ -1,9 +1,22 @@
+use std::fmt;
+
+use serde::Serialize;
+
 /// Represents a primitive syntax token.
-#[derive(Debug,Clone,PartialEq)]
+#[derive(Debug,Clone,PartialEq,Serialize)]
 pub enum Token {
     // Equalty and comparison operations.
     Eq,
-    Neq,
+    PlusEq,
+    MinusEq,
+    TimesEq,
+    DivEq,
+    PercentEq,
+    AmpEq,
+    PipeEq,
+    CaretEq,
+    ShiftLeftEq,
+    ShiftRightEq,
     Eqq,
     BangEq,
     Greater,
@@ -14,7 +27,20 @@ pub enum Token {
     Plus,
     Minus,
     Times,
+    StarStar,
     Div,
+    Percent,
+    // Bitwise ops.
+    Amp,
+    Pipe,
+    Caret,
+    ShiftLeft,
+    ShiftRight,
+    // Conditional expression.
+    Question,
+    Colon,
+    Dot,
+    Arrow,
     // Unary ops.
     Bang,
     // Grouping ops.
@@ -24,21 +50,155 @@ pub enum Token {
     Semicolon,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
     // Literals and identifiers.
     If,
     While,
     For,
+    Break,
+    Continue,
     Fun,
     Return,
+    Print,
     Or,
     And,
     Else,
     True,
     False,
     Number(String),
+    HexNumber(String),
+    Str(String),
     Ident(String),
     Var,
     Wagmi,
     EOF,
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s: &str = match self {
+            Token::Eq => "=",
+            Token::PlusEq => "+=",
+            Token::MinusEq => "-=",
+            Token::TimesEq => "*=",
+            Token::DivEq => "/=",
+            Token::PercentEq => "%=",
+            Token::AmpEq => "&=",
+            Token::PipeEq => "|=",
+            Token::CaretEq => "^=",
+            Token::ShiftLeftEq => "<<=",
+            Token::ShiftRightEq => ">>=",
+            Token::Eqq => "==",
+            Token::BangEq => "!=",
+            Token::Greater => ">",
+            Token::Less => "<",
+            Token::Geq => ">=",
+            Token::Leq => "<=",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Times => "*",
+            Token::StarStar => "**",
+            Token::Div => "/",
+            Token::Percent => "%",
+            Token::Amp => "&",
+            Token::Pipe => "|",
+            Token::Caret => "^",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+            Token::Question => "?",
+            Token::Colon => ":",
+            Token::Dot => ".",
+            Token::Arrow => "->",
+            Token::Bang => "!",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::Comma => ",",
+            Token::Semicolon => ";",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::If => "if",
+            Token::While => "while",
+            Token::For => "for",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::Fun => "fun",
+            Token::Return => "return",
+            Token::Print => "print",
+            Token::Or => "or",
+            Token::And => "and",
+            Token::Else => "else",
+            Token::True => "true",
+            Token::False => "false",
+            Token::Number(n) => n,
+            Token::HexNumber(n) => n,
+            Token::Str(s) => return write!(f, "{:?}", s),
+            Token::Ident(id) => id,
+            Token::Var => "var",
+            Token::Wagmi => "wagmi",
+            Token::EOF => "end of file",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Token {
+    /// Whether the token is a reserved word, which can't be used as a name.
+    pub fn is_keyword(&self) -> bool {
+        matches!(
+            self,
+            Token::If
+                | Token::While
+                | Token::For
+                | Token::Break
+                | Token::Continue
+                | Token::Fun
+                | Token::Return
+                | Token::Print
+                | Token::Or
+                | Token::And
+                | Token::Else
+                | Token::True
+                | Token::False
+                | Token::Var
+                | Token::Wagmi
+        )
+    }
+}
+
+/// Location of a token in the source, with 1-indexed line and column.
+#[derive(Debug,Clone,Copy,PartialEq,Default,Serialize)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, col {}", self.line, self.col)
+    }
+}
+
+/// Wraps a value with the span it was lexed from, and the comments
+/// around it.
+#[derive(Debug,Clone,PartialEq,Serialize)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+    /// Comments between the previous token and this one, except one on
+    /// the line of the previous token, which trails that token instead.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub leading_trivia: Vec<String>,
+    /// Comment after the token on the same line.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trailing_trivia: Option<String>,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Self { node, span, leading_trivia: vec!(), trailing_trivia: None }
+    }
+}
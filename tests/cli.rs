@@ -0,0 +1,190 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command,Output};
+
+use mai::run_wasm;
+
+fn mai(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mai"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("failed to run mai")
+}
+
+#[test]
+fn native_target_runs_a_constant_program() {
+    let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join("constant");
+    let result = mai(&[
+        "--input", "tests/programs/constant.mai",
+        "--target", "native",
+        "--output", output.to_str().unwrap(),
+    ]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+
+    let run = Command::new(&output).output().expect("failed to run the executable");
+    assert!(run.status.success());
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "42\n");
+}
+
+#[test]
+fn exit_code_per_stage() {
+    let lex_error = Path::new(env!("CARGO_TARGET_TMPDIR")).join("lex_error.mai");
+    fs::write(&lex_error, "var x = 1 @ 2;\n").unwrap();
+    let cases: [(&[&str], i32); 7] = [
+        (&["--input", lex_error.to_str().unwrap(), "--emit", "llvm-ir"], 2),
+        (&["--input", "tests/programs/parse_error.mai", "--emit", "llvm-ir"], 3),
+        (&["--input", "tests/programs/codegen_error.mai", "--emit", "llvm-ir"], 4),
+        (&["--input", "tests/programs/constant.mai", "--target", "not-a-target"], 5),
+        (&["--input", "tests/programs/grouping.mai", "--call", "grouped", "--linker", "/nonexistent/wasm-ld"], 6),
+        (&["--input", "tests/programs/assert.mai", "--interpret", "--call", "fails"], 7),
+        // Outside of any stage.
+        (&["--input", "tests/programs/missing.mai"], 1),
+    ];
+    for (args, code) in cases {
+        let result = mai(args);
+        assert_eq!(result.status.code(), Some(code), "{:?}\n{}", args, String::from_utf8_lossy(&result.stderr));
+    }
+}
+
+#[test]
+fn result_formats() {
+    let cases = [
+        ("plain", "7"),
+        ("scientific", "7.000000e0"),
+        ("json", r#"{"func":"safe_sub","result":7.0}"#),
+    ];
+    for (format, expected) in cases {
+        let result = mai(&[
+            "--input", "main.mai",
+            "--interpret", "--call", "safe_sub", "--args", "20,13",
+            "--result-format", format,
+        ]);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        let stdout = String::from_utf8_lossy(&result.stdout);
+        assert_eq!(stdout.lines().last(), Some(expected), "{}", format);
+    }
+}
+
+#[test]
+fn call_with_explicit_args() {
+    let call = |args: &str, exit_code: bool| {
+        let mut all = vec!("--input", "main.mai", "--interpret", "--call", "safe_sub");
+        if exit_code {
+            all.push("--exit-code");
+        }
+        all.extend(["--args", args]);
+        mai(&all)
+    };
+    let result = call("20,13", false);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(String::from_utf8_lossy(&result.stdout).lines().last(), Some("7"));
+
+    // The sign of the result doesn't overlap with the error codes.
+    assert_eq!(call("20,13", true).status.code(), Some(10));
+    assert_eq!(call("20,21", true).status.code(), Some(0));
+    assert_eq!(mai(&["--input", "tests/programs/power.mai", "--interpret", "--call", "negated", "--exit-code", "--args", "2"]).status.code(), Some(11));
+
+    let result = call("20", false);
+    assert_eq!(result.status.code(), Some(7));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("`safe_sub` expects 2 arguments, found 1"), "{}", stderr);
+}
+
+#[test]
+fn emit_stops_after_its_stage() {
+    let stdout = |args: &[&str]| {
+        let result = mai(args);
+        assert!(result.status.success(), "{:?}\n{}", args, String::from_utf8_lossy(&result.stderr));
+        String::from_utf8_lossy(&result.stdout).to_string()
+    };
+    // Each stage succeeds on input that only fails in a later one.
+    let tokens = stdout(&["--input", "tests/programs/parse_error.mai", "--emit", "tokens"]);
+    assert!(tokens.starts_with("[Fun, Ident(\"broken\")"), "{}", tokens);
+    let ast = stdout(&["--input", "tests/programs/codegen_error.mai", "--emit", "ast"]);
+    assert_eq!(ast, "fun undefined(a) {\n    return a + b;\n}\n");
+    let ir = stdout(&["--input", "tests/programs/grouping.mai", "--emit", "llvm-ir"]);
+    assert!(ir.starts_with("; ModuleID = 'tmp'") && ir.contains("define double @grouped()"), "{}", ir);
+
+    let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join("grouping.wasm");
+    let wasm = stdout(&["--input", "tests/programs/grouping.mai", "--emit", "wasm", "--output", output.to_str().unwrap()]);
+    assert!(!wasm.contains("Running"), "{}", wasm);
+    assert!(fs::read(&output).unwrap().starts_with(b"\0asm"));
+}
+
+#[test]
+fn empty_file_compiles_nothing() {
+    let empty = Path::new(env!("CARGO_TARGET_TMPDIR")).join("empty.mai");
+    fs::write(&empty, "// Nothing but a comment.\n").unwrap();
+    for args in [&["--emit", "llvm-ir"][..], &["--interpret"], &[]] {
+        let result = mai(&[&["--input", empty.to_str().unwrap()], args].concat());
+        assert!(result.status.success(), "{:?}\n{}", args, String::from_utf8_lossy(&result.stderr));
+        assert!(String::from_utf8_lossy(&result.stdout).ends_with("no statements to compile\n"), "{:?}", result);
+    }
+}
+
+#[test]
+fn check_reports_front_end_errors() {
+    // The linker is never needed, so a missing one is no error.
+    let check = |file: &str| mai(&["--input", file, "--check", "--linker", "/nonexistent/wasm-ld"]);
+
+    let result = check("tests/programs/parse_error.mai");
+    assert_eq!(result.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("expected expression at line 3, col 15, found ';'"), "{:?}", result);
+
+    let result = check("tests/programs/codegen_error.mai");
+    assert_eq!(result.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("undefined variable `b` at line 3, col 16"), "{:?}", result);
+
+    let result = check("tests/programs/grouping.mai");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(String::from_utf8_lossy(&result.stdout).ends_with("no errors found\n"), "{:?}", result);
+}
+
+#[test]
+fn json_tokens_and_ast() {
+    let json = |emit: &str| {
+        let result = mai(&["--input", "tests/programs/grouping.mai", "--emit", emit, "--format", "json"]);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        serde_json::from_slice::<serde_json::Value>(&result.stdout).unwrap()
+    };
+
+    let tokens = json("tokens");
+    assert_eq!(tokens[1]["node"]["Ident"], "grouped");
+    assert_eq!(tokens[1]["span"], serde_json::json!({ "line": 2, "col": 5, "len": 7 }));
+
+    let ast = json("ast");
+    let function = &ast[0]["Function"];
+    assert_eq!(function["name"]["Ident"], "grouped");
+    assert_eq!(function["span"]["line"], 2);
+    let ret = &function["body"][0]["Return"];
+    assert_eq!(ret["span"]["line"], 3);
+    assert_eq!(ret["value"]["BinaryExpr"]["op"], "Times");
+}
+
+#[test]
+fn checked_arith_traps_on_overflow() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let source = dir.join("overflow.mai");
+    fs::write(&source, "fun times(x: int, y: int) -> int { return x * y; }\n").unwrap();
+    let wasm = |flags: &[&str], name: &str| {
+        let output = dir.join(name);
+        let args = [&["--input", source.to_str().unwrap(), "--emit", "wasm", "--output", output.to_str().unwrap()], flags].concat();
+        let result = mai(&args);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        fs::read(output).unwrap()
+    };
+    let big = (1u64 << 62) as f64;
+
+    let wrapping = wasm(&[], "wrapping.wasm");
+    assert_eq!(run_wasm(&wrapping, "times", &[big, 2.0]).unwrap(), Some(i64::MIN as f64));
+
+    let checked = wasm(&["--checked-arith"], "checked.wasm");
+    assert_eq!(run_wasm(&checked, "times", &[big, -2.0]).unwrap(), Some(i64::MIN as f64));
+    assert_eq!(run_wasm(&checked, "times", &[0.0, big]).unwrap(), Some(0.0));
+    assert_eq!(run_wasm(&checked, "times", &[-1.0, big]).unwrap(), Some(-big));
+    for args in [[big, 2.0], [-1.0, i64::MIN as f64]] {
+        let err = run_wasm(&checked, "times", &args).unwrap_err();
+        assert!(err.to_string().starts_with("`times` trapped"), "{:?}: {}", args, err);
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::path::Path;
+
+use mai::{parse_str,pretty};
+
+// Pieces of source, valid or not, that random inputs are built from.
+const FRAGMENTS: &[&str] = &[
+    "fun", "wagmi", "var", "if", "else", "while", "for", "return", "print", "break", "continue",
+    "and", "or", "true", "false", "x", "f", "array", "0", "1.5", "0x1F", "2e3", "\"s\\n\"", "\"",
+    "(", ")", "{", "}", "[", "]", ",", ";", ":", "->", ".", "?", "=", "==", "!=", "!", "<", "<=",
+    ">", ">=", "+", "-", "*", "**", "/", "%", "&", "|", "^", "<<", ">>", "+=", "// c\n", " ", "\n",
+];
+
+// Small xorshift generator, so every run tries the same inputs.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// Formatting a program that parses gives a program that parses to the
+// same formatting.
+fn assert_round_trips(source: &str) {
+    let Ok(statements) = parse_str(source) else {
+        return;
+    };
+    let formatted = pretty::print(&statements);
+    let reparsed = parse_str(&formatted)
+        .unwrap_or_else(|err| panic!("formatted {:?} as {:?}, which doesn't parse: {:?}", source, formatted, err));
+    assert_eq!(pretty::print(&reparsed), formatted, "formatting {:?} is not stable", source);
+}
+
+#[test]
+fn random_fragments_never_panic() {
+    let mut rng = Rng(0x5eed);
+    for _ in 0..20_000 {
+        let source = (0..rng.below(40))
+            .map(|_| FRAGMENTS[rng.below(FRAGMENTS.len())])
+            .collect::<Vec<&str>>()
+            .join(" ");
+        assert_round_trips(&source);
+    }
+}
+
+#[test]
+fn random_bytes_never_panic() {
+    let mut rng = Rng(0xb17e5);
+    for _ in 0..20_000 {
+        let bytes = (0..rng.below(64)).map(|_| rng.next() as u8).collect::<Vec<u8>>();
+        let _ = parse_str(&String::from_utf8_lossy(&bytes));
+    }
+}
+
+#[test]
+fn deep_nesting_is_an_error() {
+    for open in ["(", "{", "-", "!", "a = ", "x ? y : ", "2 ** ", "if (1) ", "while (1) ", "fun f() {"] {
+        let err = parse_str(&open.repeat(10_000)).unwrap_err();
+        assert!(err.to_string().contains("nested too deeply"), "{:?}: {}", open, err);
+    }
+}
+
+#[test]
+fn programs_round_trip() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    for entry in fs::read_dir(dir).unwrap() {
+        assert_round_trips(&fs::read_to_string(entry.unwrap().path()).unwrap());
+    }
+}
@@ -0,0 +1,43 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use mai::{compile_to_ir,Compiler};
+
+// Compares the IR of `tests/golden/<name>.mai` with the checked-in
+// `<name>.ll`. Run with `MAI_BLESS=1` to rewrite the expected IR.
+fn check_ir(name: &str) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let source = fs::read_to_string(dir.join(name).with_extension("mai")).unwrap();
+    let expected_path = dir.join(name).with_extension("ll");
+    let ir = compile_to_ir(&source).unwrap();
+    if env::var_os("MAI_BLESS").is_some() {
+        fs::write(&expected_path, &ir).unwrap();
+    }
+    let expected = fs::read_to_string(&expected_path).unwrap();
+    assert_eq!(ir, expected, "IR of {} changed", name);
+
+    // Compiling again, also in a context that was used before, gives the
+    // same IR.
+    let compiler = Compiler::new();
+    for _ in 0..3 {
+        assert_eq!(compiler.compile(&source).unwrap(), expected);
+    }
+}
+
+#[test]
+fn two_functions() {
+    check_ir("two_functions");
+}
+
+#[test]
+fn one_compiler_for_many_programs() {
+    let compiler = Compiler::new();
+    for i in 0..100 {
+        let source = format!("fun f{0}(x) {{ var y = x * {0}; return y + {1}; }}", i, i % 7);
+        let ir = compiler.compile(&source).unwrap();
+        assert!(ir.contains(&format!("@f{}(", i)), "{}", ir);
+        // Reusing the context leaves nothing behind from earlier programs.
+        assert_eq!(ir, compile_to_ir(&source).unwrap(), "program {}", i);
+    }
+}
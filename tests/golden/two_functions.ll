@@ -0,0 +1,16 @@
+; ModuleID = 'tmp'
+source_filename = "tmp"
+
+define double @area(double %w, double %h) {
+entry:
+  %tmpmul.i = fmul double %w, %w
+  %tmpmul = fmul double %tmpmul.i, %h
+  %tmpdiv = fmul double %tmpmul, 5.000000e-01
+  ret double %tmpdiv
+}
+
+define double @square(double %x) {
+entry:
+  %tmpmul = fmul double %x, %x
+  ret double %tmpmul
+}
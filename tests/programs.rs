@@ -0,0 +1,216 @@
+use std::fs;
+use std::path::Path;
+
+use mai::parser::Stmt;
+use mai::token::Token;
+use mai::{check,compile_to_wasm,interpret,parse_str,run_wasm,with_implicit_main};
+
+// Samples for the command line tests that fail before they can run, on
+// purpose, or never return.
+const NOT_RUN: &[&str] = &[
+    "keyword_names.mai",
+    "members.mai",
+    "recovery.mai",
+    "runaway.mai",
+];
+
+// Samples that are expected to be rejected, with part of the error.
+const ERRORS: &[(&str, &str)] = &[
+    ("codegen_error.mai", "undefined variable `b` at line 3, col 16"),
+    ("parse_error.mai", "expected expression at line 3, col 15, found ';'"),
+];
+
+// Functions that trap on purpose, left out of the sweep. Their traps are
+// checked by tests/wasm.rs and tests/cli.rs.
+const TRAPS: &[(&str, &str)] = &[
+    ("arrays.mai", "outside"),
+    ("assert.mai", "fails"),
+];
+
+// Functions taking a count, which are only given whole, non-negative
+// numbers.
+const COUNTS: &[(&str, &str)] = &[
+    ("arrays.mai", "squares"),
+];
+const COUNT_INPUTS: &[f64] = &[0.0, 1.0, 7.0];
+
+// The global of globals.mai, which is only close to pi.
+#[allow(clippy::approx_constant)]
+const PI: f64 = 3.14159;
+
+// What calls return, at least one per sample that is run. None is a void
+// function.
+const EXPECTED: &[(&str, &str, &[f64], Option<f64>)] = &[
+    ("arithmetic.mai", "arithmetic", &[2.0, 3.0], Some(12.0)),
+    ("arrays.mai", "squares", &[5.0], Some(30.0)),
+    ("assert.mai", "passes", &[], Some(1.0)),
+    ("blocks.mai", "flat", &[1.0], Some(4.0)),
+    ("blocks.mai", "wrapped", &[1.0], Some(4.0)),
+    ("comments.mai", "sum", &[5.0], Some(10.0)),
+    ("conditionals.mai", "conditionals", &[1.0, 2.0], Some(-35.0)),
+    ("conditionals.mai", "conditionals", &[2.0, 2.0], Some(-26.0)),
+    ("conditionals.mai", "conditionals", &[3.0, 2.0], Some(44.0)),
+    ("constant.mai", "answer", &[], Some(42.0)),
+    ("constant_if.mai", "taken", &[], Some(1.0)),
+    ("constant_if.mai", "fallback", &[], Some(2.0)),
+    ("fall_off.mai", "count", &[3.0], Some(3.0)),
+    ("fall_off.mai", "count", &[-2.0], Some(0.0)),
+    ("fall_off.mai", "echo", &[0.0], Some(0.0)),
+    ("globals.mai", "circumference", &[1.0], Some(2.0 * PI)),
+    ("globals.mai", "area", &[2.0], Some(PI * 2.0 * 2.0)),
+    ("grouping.mai", "grouped", &[], Some(9.0)),
+    ("grouping.mai", "nested", &[1.0, 2.0, 3.0], Some(15.0)),
+    ("inline.mai", "main", &[], Some(6.0)),
+    ("jumps.mai", "jumps", &[10.0, 6.0], Some(6.0)),
+    ("jumps.mai", "jumps", &[10.0, 100.0], Some(20.0)),
+    ("literals.mai", "main", &[], None),
+    ("loop.mai", "fib", &[10.0], Some(55.0)),
+    ("loop.mai", "loop", &[3.0, 2.0], Some(8.0)),
+    ("nan.mai", "nan", &[0.0], None),
+    ("nested.mai", "scaled", &[3.0, 4.0], Some(12.0)),
+    ("power.mai", "right", &[], Some(512.0)),
+    ("power.mai", "builtin", &[], Some(1024.0)),
+    ("power.mai", "negated", &[2.0], Some(-4.0)),
+    ("precedence.mai", "precedence", &[1.0, 2.0, 3.0], Some(3.0)),
+    ("return_types.mai", "double", &[4.0], Some(8.0)),
+    ("return_types.mai", "half", &[5.0], Some(2.5)),
+    ("return_types.mai", "truncated", &[3.0], Some(4.0)),
+    ("scoping.mai", "scoping", &[2.0], Some(22.0)),
+    ("scoping.mai", "scoping", &[-1.0], Some(-2.0)),
+];
+
+// Every parameter is tried with each of these.
+const INPUTS: &[f64] = &[-3.0, 0.0, 1.0, 2.5, 7.0];
+
+// Exported functions of the program with their number of parameters.
+fn exports(statements: &[Box<Stmt>]) -> Vec<(String, usize)> {
+    with_implicit_main(statements)
+        .iter()
+        .filter_map(|stmt| match stmt.as_ref() {
+            Stmt::Function { name: Token::Ident(name), params, .. } if !name.starts_with('_') => {
+                Some((name.clone(), params.len()))
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+// Every combination of inputs for `arity` parameters.
+fn arguments(arity: usize, inputs: &'static [f64]) -> Vec<Vec<f64>> {
+    let mut all = vec!(vec!());
+    for _ in 0..arity {
+        all = all
+            .iter()
+            .flat_map(|args| inputs.iter().map(move |input| {
+                let mut args = args.clone();
+                args.push(*input);
+                args
+            }))
+            .collect();
+    }
+    all
+}
+
+fn same(interpreted: &Option<f64>, compiled: &Option<f64>) -> bool {
+    match (interpreted, compiled) {
+        (Some(a), Some(b)) => a == b || (a.is_nan() && b.is_nan()),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// The name and source of every sample, in order.
+fn programs() -> Vec<(String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut paths = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mai"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| (path.file_name().unwrap().to_str().unwrap().to_string(), fs::read_to_string(path).unwrap()))
+        .collect()
+}
+
+fn is_run(file: &str) -> bool {
+    !NOT_RUN.contains(&file) && !ERRORS.iter().any(|(name, _)| *name == file)
+}
+
+#[test]
+fn interpreter_and_wasm_agree() {
+    let mut mismatches = vec!();
+    for (file, source) in programs().iter() {
+        let file = file.as_str();
+        if !is_run(file) {
+            continue;
+        }
+        let statements = parse_str(source).unwrap_or_else(|err| panic!("{} doesn't parse: {:?}", file, err));
+        let wasm = compile_to_wasm(source).unwrap_or_else(|err| panic!("{} doesn't compile: {:?}", file, err));
+
+        for (func, arity) in exports(&statements) {
+            let call = (file, func.as_str());
+            if TRAPS.contains(&call) {
+                continue;
+            }
+            let inputs = match COUNTS.contains(&call) {
+                true => COUNT_INPUTS,
+                false => INPUTS,
+            };
+            for args in arguments(arity, inputs) {
+                let interpreted = interpret(&statements, &func, &args);
+                let compiled = run_wasm(&wasm, &func, &args);
+                let agree = match (&interpreted, &compiled) {
+                    (Ok(a), Ok(b)) => same(a, b),
+                    _ => false,
+                };
+                if !agree {
+                    mismatches.push(format!(
+                        "{}: {}({:?}) interpreted {:?}, compiled {:?}",
+                        file, func, args, interpreted, compiled,
+                    ));
+                }
+            }
+        }
+    }
+    assert!(mismatches.is_empty(), "\n{}", mismatches.join("\n"));
+}
+
+#[test]
+fn programs_return_the_expected_values() {
+    let mut wrong = vec!();
+    for (file, source) in programs().iter() {
+        let file = file.as_str();
+        if !is_run(file) {
+            continue;
+        }
+        let expected = EXPECTED.iter().filter(|(name, ..)| *name == file).collect::<Vec<_>>();
+        assert!(!expected.is_empty(), "{} has no expected values", file);
+
+        let statements = parse_str(source).unwrap();
+        let wasm = compile_to_wasm(source).unwrap();
+        for (_, func, args, value) in expected {
+            let interpreted = interpret(&statements, func, args).unwrap();
+            let compiled = run_wasm(&wasm, func, args).unwrap();
+            if interpreted != *value || compiled != *value {
+                wrong.push(format!(
+                    "{}: {}({:?}) should be {:?}, interpreted {:?}, compiled {:?}",
+                    file, func, args, value, interpreted, compiled,
+                ));
+            }
+        }
+    }
+    assert!(wrong.is_empty(), "\n{}", wrong.join("\n"));
+}
+
+#[test]
+fn broken_programs_are_rejected() {
+    for (file, error) in ERRORS.iter() {
+        let (_, source) = programs().into_iter().find(|(name, _)| name == file).unwrap();
+        let checked = format!("{:?}", check(&source).unwrap_err());
+        assert!(checked.contains(error), "{}: {}", file, checked);
+        let compiled = format!("{:?}", compile_to_wasm(&source).unwrap_err());
+        assert!(compiled.contains(error), "{}: {}", file, compiled);
+    }
+}
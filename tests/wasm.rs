@@ -0,0 +1,437 @@
+use std::thread;
+
+use mai::llvm_translator::PRINT_FN;
+use mai::{compile_to_wasm,interpret,parse_str,run_all,run_wasm,run_wasm_with_options,RunOptions};
+
+fn compile(source: &str) -> Vec<u8> {
+    compile_to_wasm(source).unwrap()
+}
+
+#[test]
+fn compiles_run_concurrently() {
+    let threads = (0..4)
+        .map(|i| thread::spawn(move || {
+            let wasm = compile(&format!("fun value() {{ return {}; }}", i));
+            run_wasm(&wasm, "value", &[]).unwrap()
+        }))
+        .collect::<Vec<_>>();
+    for (i, thread) in threads.into_iter().enumerate() {
+        assert_eq!(thread.join().unwrap(), Some(i as f64));
+    }
+}
+
+#[test]
+fn array_elements_read_back() {
+    let wasm = compile("
+        fun roundtrip(x) {
+            var a = array(3);
+            a[0] = x;
+            a[2] = x * 2;
+            return a[0] + a[1] + a[2];
+        }
+    ");
+    assert_eq!(run_wasm(&wasm, "roundtrip", &[4.0]).unwrap(), Some(12.0));
+
+    let wasm = compile(include_str!("programs/arrays.mai"));
+    assert_eq!(run_wasm(&wasm, "squares", &[5.0]).unwrap(), Some(30.0));
+}
+
+#[test]
+fn array_index_out_of_bounds_traps() {
+    let wasm = compile(include_str!("programs/arrays.mai"));
+    let err = run_wasm(&wasm, "outside", &[]).unwrap_err();
+    assert!(err.to_string().contains("`outside` trapped"), "{}", err);
+}
+
+#[test]
+fn not_equal_compares() {
+    let wasm = compile("fun ne(a, b) { return a != b; }");
+    assert_eq!(run_wasm(&wasm, "ne", &[1.0, 2.0]).unwrap(), Some(1.0));
+    assert_eq!(run_wasm(&wasm, "ne", &[2.0, 2.0]).unwrap(), Some(0.0));
+}
+
+#[test]
+fn run_all_calls_one_compilation() {
+    let results = run_all(
+        include_str!("programs/grouping.mai"),
+        &[("nested", vec!(1.0, 2.0, 3.0)), ("nested", vec!(2.0, 1.0, 0.0)), ("grouped", vec!())],
+    );
+    assert_eq!(results.unwrap(), vec!(15.0, -3.0, 9.0));
+}
+
+#[test]
+fn run_all_rejects_void_functions() {
+    let err = run_all("fun show(x) { print x; }", &[("show", vec!(1.0))]).unwrap_err();
+    assert_eq!(err.to_string(), "`show` returns no value");
+}
+
+#[test]
+fn powers() {
+    let source = include_str!("programs/power.mai");
+    let wasm = compile(source);
+    let statements = parse_str(source).unwrap();
+    for (func, args, expected) in [("builtin", vec!(), 1024.0), ("right", vec!(), 512.0), ("negated", vec!(2.0), -4.0)] {
+        assert_eq!(run_wasm(&wasm, func, &args).unwrap(), Some(expected), "compiled {}", func);
+        assert_eq!(interpret(&statements, func, &args).unwrap(), Some(expected), "interpreted {}", func);
+    }
+
+    let wasm = compile("fun power(base, exp) { return pow(base, exp); }");
+    assert_eq!(run_wasm(&wasm, "power", &[2.0, 0.5]).unwrap(), Some(2f64.sqrt()));
+    assert_eq!(run_wasm(&wasm, "power", &[2.0, -1.0]).unwrap(), Some(0.5));
+}
+
+#[test]
+fn libm_calls_are_provided() {
+    let wasm = compile("
+        fun low(x, y) { return min(x, y); }
+        fun high(x, y) { return max(x, y); }
+        fun clamped(x, y) { return safe_sub(x, y); }
+        fun rem(x, y) { return x % y; }
+    ");
+    assert_eq!(run_wasm(&wasm, "low", &[2.0, -1.5]).unwrap(), Some(-1.5));
+    assert_eq!(run_wasm(&wasm, "high", &[2.0, -1.5]).unwrap(), Some(2.0));
+    assert_eq!(run_wasm(&wasm, "clamped", &[2.0, 3.0]).unwrap(), Some(0.0));
+    assert_eq!(run_wasm(&wasm, "rem", &[7.5, 2.0]).unwrap(), Some(1.5));
+}
+
+#[test]
+fn runaway_loop_runs_out_of_fuel() {
+    let wasm = compile(include_str!("programs/runaway.mai"));
+    let options = RunOptions { fuel: Some(1_000_000), ..RunOptions::default() };
+    let err = run_wasm_with_options(&wasm, "spin", &[], &options).unwrap_err();
+    assert_eq!(err.to_string(), "`spin` ran out of fuel after 1000000 instructions");
+}
+
+// Grows its memory of one page by the given number of pages, returning
+// the old size or -1 when it can't.
+const GROWS: &str = r#"
+    (module
+        (memory (export "memory") 1)
+        (func (export "grow") (param f64) (result f64)
+            (f64.convert_i32_s (memory.grow (i32.trunc_f64_s (local.get 0))))))
+"#;
+
+#[test]
+fn memory_cannot_grow_past_the_limit() {
+    let options = RunOptions { max_memory_pages: Some(4), ..RunOptions::default() };
+    assert_eq!(run_wasm_with_options(GROWS.as_bytes(), "grow", &[3.0], &options).unwrap(), Some(1.0));
+    assert_eq!(run_wasm_with_options(GROWS.as_bytes(), "grow", &[4.0], &options).unwrap(), Some(-1.0));
+    assert_eq!(run_wasm(GROWS.as_bytes(), "grow", &[4.0]).unwrap(), Some(1.0));
+
+    let options = RunOptions { max_memory_pages: Some(0), ..RunOptions::default() };
+    let err = run_wasm_with_options(GROWS.as_bytes(), "grow", &[0.0], &options).unwrap_err();
+    assert!(err.to_string().contains("the module needs 1 memory pages, more than the limit of 0"), "{}", err);
+}
+
+#[test]
+fn integer_and_mixed_arithmetic() {
+    let source = "
+        fun ints() { var x = 7; x = x % 4 * 6 & 7; return x << 2 | 1; }
+        fun quarter() { return 10 / 4; }
+        fun reassigned(a) { var x = 1; x = a; return x; }
+        fun halves(n) { var total = 0; for (var i = 1; i <= n; i += 1) { total += i / 2; } return total; }
+    ";
+    let wasm = compile(source);
+    let statements = parse_str(source).unwrap();
+    let cases: [(&str, Vec<f64>, f64); 4] = [
+        ("ints", vec!(), 9.0),
+        ("quarter", vec!(), 2.5),
+        ("reassigned", vec!(2.7), 2.7),
+        ("halves", vec!(3.0), 3.0),
+    ];
+    for (func, args, expected) in cases.iter() {
+        assert_eq!(run_wasm(&wasm, func, args).unwrap(), Some(*expected), "{}", func);
+        assert_eq!(interpret(&statements, func, args).unwrap(), Some(*expected), "{}", func);
+    }
+}
+
+#[test]
+fn multiplies_and_divides() {
+    let wasm = compile("fun mul(a, b) { return a * b; } fun div(a, b) { return a / b; }");
+    assert_eq!(run_wasm(&wasm, "mul", &[6.0, 7.0]).unwrap(), Some(42.0));
+    assert_eq!(run_wasm(&wasm, "div", &[10.0, 4.0]).unwrap(), Some(2.5));
+}
+
+#[test]
+fn shadowing_ends_with_the_block() {
+    let source = "
+        fun shadowed(a) {
+            var x = a;
+            {
+                var x = a * 10;
+                x += 1;
+            }
+            if (a > 0) { var x = -1; }
+            return x;
+        }
+    ";
+    let wasm = compile(source);
+    let statements = parse_str(source).unwrap();
+    assert_eq!(run_wasm(&wasm, "shadowed", &[3.0]).unwrap(), Some(3.0));
+    assert_eq!(interpret(&statements, "shadowed", &[3.0]).unwrap(), Some(3.0));
+
+    let wasm = compile(include_str!("programs/scoping.mai"));
+    assert_eq!(run_wasm(&wasm, "scoping", &[2.0]).unwrap(), Some(22.0));
+}
+
+#[test]
+fn call_checks_the_number_of_arguments() {
+    let wasm = compile(include_str!("../main.mai"));
+    assert_eq!(run_wasm(&wasm, "safe_sub", &[20.0, 13.0]).unwrap(), Some(7.0));
+    let err = run_wasm(&wasm, "safe_sub", &[20.0]).unwrap_err();
+    assert_eq!(err.to_string(), "`safe_sub` expects 2 arguments, found 1");
+}
+
+#[test]
+fn safe_sub_saturates_at_zero() {
+    let source = "fun clamped(a, b) { return safe_sub(a, b); }";
+    let wasm = compile(source);
+    let statements = parse_str(source).unwrap();
+    for (args, expected) in [([20.0, 21.0], 0.0), ([20.0, 13.0], 7.0), ([20.0, 20.0], 0.0)] {
+        assert_eq!(run_wasm(&wasm, "clamped", &args).unwrap(), Some(expected), "{:?}", args);
+        assert_eq!(interpret(&statements, "clamped", &args).unwrap(), Some(expected), "{:?}", args);
+    }
+}
+
+#[test]
+fn math_intrinsics() {
+    let source = "
+        fun absolute(x) { return abs(x); }
+        fun root(x) { return sqrt(x); }
+        fun down(x) { return floor(x); }
+        fun up(x) { return ceil(x); }
+        fun power(x, y) { return pow(x, y); }
+    ";
+    let wasm = compile(source);
+    let statements = parse_str(source).unwrap();
+    let cases: [(&str, Vec<f64>, f64); 8] = [
+        ("absolute", vec!(-3.0), 3.0),
+        ("absolute", vec!(2.5), 2.5),
+        ("root", vec!(16.0), 4.0),
+        ("down", vec!(-1.5), -2.0),
+        ("down", vec!(2.7), 2.0),
+        ("up", vec!(-1.5), -1.0),
+        ("up", vec!(2.1), 3.0),
+        ("power", vec!(3.0, 4.0), 81.0),
+    ];
+    for (func, args, expected) in cases.iter() {
+        assert_eq!(run_wasm(&wasm, func, args).unwrap(), Some(*expected), "compiled {}({:?})", func, args);
+        assert_eq!(interpret(&statements, func, args).unwrap(), Some(*expected), "interpreted {}({:?})", func, args);
+    }
+    assert!(run_wasm(&wasm, "root", &[-1.0]).unwrap().unwrap().is_nan());
+
+    let err = compile_to_wasm("fun f() { return sqrt(1, 2); }").unwrap_err();
+    assert!(format!("{:?}", err).contains("`sqrt` expects 1 arguments, found 2"), "{:?}", err);
+}
+
+#[test]
+fn while_loop_accumulates() {
+    let wasm = compile("
+        fun sum(n) {
+            var total = 0;
+            var i = 1;
+            while (i <= n) {
+                total = total + i;
+                i = i + 1;
+            }
+            return total;
+        }
+    ");
+    assert_eq!(run_wasm(&wasm, "sum", &[10.0]).unwrap(), Some(55.0));
+    assert_eq!(run_wasm(&wasm, "sum", &[0.0]).unwrap(), Some(0.0));
+}
+
+#[test]
+fn every_top_level_function_is_exported() {
+    let wasm = compile("
+        fun add(a, b) { return a + b; }
+        fun main() { return add(1, 2) * 10; }
+    ");
+    assert_eq!(run_wasm(&wasm, "add", &[4.0, 5.0]).unwrap(), Some(9.0));
+    assert_eq!(run_wasm(&wasm, "main", &[]).unwrap(), Some(30.0));
+}
+
+#[test]
+fn whole_block_is_translated() {
+    let wasm = compile("
+        fun steps(x) {
+            var y = x;
+            y = y * 2;
+            if (y > 10) {
+                return y;
+                y = 0;
+            }
+            y = y + 1;
+            return y;
+        }
+    ");
+    assert_eq!(run_wasm(&wasm, "steps", &[3.0]).unwrap(), Some(7.0));
+    assert_eq!(run_wasm(&wasm, "steps", &[6.0]).unwrap(), Some(12.0));
+}
+
+#[test]
+fn local_variable_is_read_back() {
+    let wasm = compile("fun next(x) { var y = x + 1; return y * y; }");
+    assert_eq!(run_wasm(&wasm, "next", &[2.0]).unwrap(), Some(9.0));
+}
+
+#[test]
+fn parameter_is_reassigned() {
+    let wasm = compile("fun bump(x) { x = x + 1; return x = x * 2; }");
+    assert_eq!(run_wasm(&wasm, "bump", &[4.0]).unwrap(), Some(10.0));
+    let err = compile_to_wasm("fun f() { y = 1; return y; }").unwrap_err();
+    assert!(format!("{:?}", err).contains("undefined variable `y`"), "{:?}", err);
+}
+
+#[test]
+fn logical_operators_short_circuit() {
+    // Reading `a[5]` traps, so the calls only succeed when it is skipped.
+    let wasm = compile("
+        fun both(x) { var a = array(1); return x and a[5]; }
+        fun either(x) { var a = array(1); return x or a[5]; }
+    ");
+    assert_eq!(run_wasm(&wasm, "both", &[0.0]).unwrap(), Some(0.0));
+    assert_eq!(run_wasm(&wasm, "either", &[2.0]).unwrap(), Some(1.0));
+    assert!(run_wasm(&wasm, "both", &[1.0]).is_err());
+    assert!(run_wasm(&wasm, "either", &[0.0]).is_err());
+}
+
+#[test]
+fn negation_and_not() {
+    let wasm = compile("fun neg(x) { return -x; } fun not(x) { return !x; }");
+    assert_eq!(run_wasm(&wasm, "neg", &[2.5]).unwrap(), Some(-2.5));
+    assert_eq!(run_wasm(&wasm, "neg", &[-4.0]).unwrap(), Some(4.0));
+    assert_eq!(run_wasm(&wasm, "not", &[0.0]).unwrap(), Some(1.0));
+    assert_eq!(run_wasm(&wasm, "not", &[5.0]).unwrap(), Some(0.0));
+}
+
+#[test]
+fn each_comparison() {
+    let wasm = compile("
+        fun lt(a, b) { return a < b; }
+        fun le(a, b) { return a <= b; }
+        fun gt(a, b) { return a > b; }
+        fun ge(a, b) { return a >= b; }
+        fun eq(a, b) { return a == b; }
+        fun ne(a, b) { return a != b; }
+    ");
+    // Results for 1 against 2, 2 against 2 and 3 against 2.
+    let cases = [
+        ("lt", [1.0, 0.0, 0.0]),
+        ("le", [1.0, 1.0, 0.0]),
+        ("gt", [0.0, 0.0, 1.0]),
+        ("ge", [0.0, 1.0, 1.0]),
+        ("eq", [0.0, 1.0, 0.0]),
+        ("ne", [1.0, 0.0, 1.0]),
+    ];
+    for (func, expected) in cases {
+        for (a, expected) in [1.0, 2.0, 3.0].iter().zip(expected) {
+            assert_eq!(run_wasm(&wasm, func, &[*a, 2.0]).unwrap(), Some(expected), "{}({}, 2)", func, a);
+        }
+    }
+}
+
+#[test]
+fn greater_at_the_boundary() {
+    let wasm = compile("fun gt(a, b) { return a > b; } fun constants() { return (5 > 5) + (6 > 5) * 10; }");
+    assert_eq!(run_wasm(&wasm, "gt", &[5.0, 5.0]).unwrap(), Some(0.0));
+    assert_eq!(run_wasm(&wasm, "gt", &[6.0, 5.0]).unwrap(), Some(1.0));
+    assert_eq!(run_wasm(&wasm, "constants", &[]).unwrap(), Some(10.0));
+}
+
+#[test]
+fn main_calls_square() {
+    let wasm = compile("fun square(x) { return x * x; } fun main() { return square(4); }");
+    assert_eq!(run_wasm(&wasm, "main", &[]).unwrap(), Some(16.0));
+    let err = compile_to_wasm("fun square(x) { return x * x; } fun main() { return square(4, 5); }").unwrap_err();
+    assert!(format!("{:?}", err).contains("`square` expects 1 arguments, found 2"), "{:?}", err);
+}
+
+#[test]
+fn recursive_fib() {
+    // `fib` calls itself, and `first` calls `fib` before its definition.
+    let wasm = compile("
+        fun first(n) { return fib(n); }
+        fun fib(n) { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); }
+    ");
+    assert_eq!(run_wasm(&wasm, "fib", &[10.0]).unwrap(), Some(55.0));
+    assert_eq!(run_wasm(&wasm, "first", &[12.0]).unwrap(), Some(144.0));
+}
+
+#[test]
+fn print_calls_the_host() {
+    let wasm = compile("print(1 + 2); print 0.5;");
+    let mut store = wasmer::Store::default();
+    let module = wasmer::Module::new(&store, &wasm).unwrap();
+    let printed = wasmer::FunctionEnv::new(&mut store, vec!());
+    let imports = wasmer::imports! {
+        "env" => {
+            PRINT_FN => wasmer::Function::new_typed_with_env(&mut store, &printed, |mut env: wasmer::FunctionEnvMut<Vec<f64>>, value: f64| {
+                env.data_mut().push(value);
+            }),
+        },
+    };
+    let instance = wasmer::Instance::new(&mut store, &module, &imports).unwrap();
+    instance.exports.get_function("main").unwrap().call(&mut store, &[]).unwrap();
+    assert_eq!(printed.as_ref(&store), &vec!(3.0, 0.5));
+}
+
+#[test]
+fn bitwise_operators_bind_tighter_than_comparison() {
+    let wasm = compile("
+        fun masked() { return 6 & 3 == 2; }
+        fun shift() { return 1 << 4 == 16; }
+        fun mixed() { return 5 ^ 1 | 8 >> 2; }
+    ");
+    assert_eq!(run_wasm(&wasm, "masked", &[]).unwrap(), Some(1.0));
+    assert_eq!(run_wasm(&wasm, "shift", &[]).unwrap(), Some(1.0));
+    // One precedence level, applied left to right.
+    assert_eq!(run_wasm(&wasm, "mixed", &[]).unwrap(), Some(3.0));
+
+    let err = compile_to_wasm("fun f() { return 1.5 & 1; }").unwrap_err();
+    assert!(err.to_string().contains("requires integer operands"), "{}", err);
+}
+
+#[test]
+fn bare_statements_become_main() {
+    let wasm = compile("
+        fun double(x) { return x * 2; }
+        var n = double(4);
+        n += 1;
+        return n;
+    ");
+    assert_eq!(run_wasm(&wasm, "main", &[]).unwrap(), Some(9.0));
+    assert_eq!(run_wasm(&wasm, "double", &[1.5]).unwrap(), Some(3.0));
+}
+
+#[test]
+fn ternary_picks_a_branch() {
+    let wasm = compile("fun max2(a, b) { return a > b ? a : b; }");
+    assert_eq!(run_wasm(&wasm, "max2", &[3.0, 7.5]).unwrap(), Some(7.5));
+    assert_eq!(run_wasm(&wasm, "max2", &[-1.0, -4.0]).unwrap(), Some(-1.0));
+    assert_eq!(run_wasm(&wasm, "max2", &[2.0, 2.0]).unwrap(), Some(2.0));
+}
+
+#[test]
+fn empty_body_returns_zero() {
+    let wasm = compile("fun noop() {}");
+    assert_eq!(run_wasm(&wasm, "noop", &[]).unwrap(), Some(0.0));
+}
+
+#[test]
+fn comparisons_with_nan_are_false() {
+    let source = "
+        fun less(zero) { var n = zero / zero; return n < 1; }
+        fun greater(zero) { var n = zero / zero; return n > 1; }
+        fun at_most(zero) { var n = zero / zero; return n <= 1; }
+        fun at_least(zero) { var n = zero / zero; return n >= 1; }
+        fun equal(zero) { var n = zero / zero; return n == n; }
+        fun not_equal(zero) { var n = zero / zero; return n != n; }
+    ";
+    let wasm = compile(source);
+    let statements = parse_str(source).unwrap();
+    for (func, expected) in [("less", 0.0), ("greater", 0.0), ("at_most", 0.0), ("at_least", 0.0), ("equal", 0.0), ("not_equal", 1.0)] {
+        assert_eq!(run_wasm(&wasm, func, &[0.0]).unwrap(), Some(expected), "{}", func);
+        assert_eq!(interpret(&statements, func, &[0.0]).unwrap(), Some(expected), "{}", func);
+    }
+}